@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::path::Path;
+use bip39::Mnemonic;
+use libp2p::identity;
+use x25519_dalek::StaticSecret;
+use crate::keystore::{KeyStore, PassphraseParams};
+use crate::vault::Vault;
+
+// Backups of who we are: the Ed25519 key behind our PeerId and the long-term
+// X25519 key peers know us by. Restoring one brings back the same PeerId, so
+// contacts keep reaching us. The signed prekey and ratchet sessions aren't
+// part of it, they are replaced on restore.
+//
+// As a mnemonic it's 48 BIP39 words, the 24 words of each key one after the
+// other. As a file it's JSON with both keys sealed under a key derived from
+// the password, the same way `KeyStore` seals the key files.
+
+const BACKUP_VERSION: u32 = 1;
+// Authenticated with the sealed keys
+const BACKUP_LABEL: &str = "identity-backup";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BackupFile {
+    version: u32,
+    kdf: PassphraseParams,
+    // Hex of the sealed Ed25519 and X25519 secrets
+    keys: String,
+}
+
+pub struct IdentityBackup {
+    identity: [u8; 32],
+    ecdh: [u8; 32],
+}
+
+impl IdentityBackup {
+    pub fn new(identity: &identity::Keypair, ecdh: &StaticSecret) -> Result<Self, Box<dyn Error>> {
+        let keypair = identity.clone().try_into_ed25519()?;
+        let secret = <[u8; 32]>::try_from(keypair.secret().as_ref())?;
+        Ok(IdentityBackup { identity: secret, ecdh: ecdh.to_bytes() })
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if bytes.len() != 64 {
+            return Err("Invalid identity backup".into());
+        }
+        let (identity, ecdh) = bytes.split_at(32);
+        Ok(IdentityBackup { identity: identity.try_into()?, ecdh: ecdh.try_into()? })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        [self.identity, self.ecdh].concat()
+    }
+
+    pub fn peer_id(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self.keypair()?.public().to_peer_id().to_string())
+    }
+
+    fn keypair(&self) -> Result<identity::Keypair, Box<dyn Error>> {
+        Ok(identity::Keypair::ed25519_from_bytes(self.identity)?)
+    }
+
+    pub fn to_mnemonic(&self) -> Result<String, Box<dyn Error>> {
+        let identity = Mnemonic::from_entropy(&self.identity)?;
+        let ecdh = Mnemonic::from_entropy(&self.ecdh)?;
+        Ok(format!("{} {}", identity, ecdh))
+    }
+
+    pub fn from_mnemonic(phrase: &str) -> Result<Self, Box<dyn Error>> {
+        let words: Vec<&str> = phrase.split_whitespace().collect();
+        if words.len() != 48 {
+            return Err(format!("Expected 48 words, got {}", words.len()).into());
+        }
+        let identity = Mnemonic::parse(words[..24].join(" "))?;
+        let ecdh = Mnemonic::parse(words[24..].join(" "))?;
+        Self::from_bytes(&[identity.to_entropy(), ecdh.to_entropy()].concat())
+    }
+
+    pub fn to_file(&self, password: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let kdf = PassphraseParams::generate();
+        let vault = Vault::with_key(Path::new(""), kdf.derive_key(password)?);
+        let keys = hex::encode(vault.seal(BACKUP_LABEL, &self.to_bytes())?);
+        Ok(serde_json::to_vec_pretty(&BackupFile { version: BACKUP_VERSION, kdf, keys })?)
+    }
+
+    pub fn from_file(data: &[u8], password: &str) -> Result<Self, Box<dyn Error>> {
+        let file: BackupFile = serde_json::from_slice(data).map_err(|_| "Not an identity backup")?;
+        if file.version != BACKUP_VERSION {
+            return Err(format!("Unsupported backup version {}", file.version).into());
+        }
+        let vault = Vault::with_key(Path::new(""), file.kdf.derive_key(password)?);
+        let bytes = vault.open_sealed(BACKUP_LABEL, &hex::decode(&file.keys)?)
+            .map_err(|_| "Wrong password")?;
+        Self::from_bytes(&bytes)
+    }
+
+    // Replaces the identity and X25519 keys in `keys`, along with a fresh prekey.
+    pub fn restore(&self, keys: &KeyStore) -> Result<(), Box<dyn Error>> {
+        keys.write("identity.key", &self.keypair()?.to_protobuf_encoding()?)?;
+        keys.write("ecdh.key", &self.ecdh)?;
+        keys.write("prekey.key", &StaticSecret::random_from_rng(rand::rngs::OsRng).to_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::error::Error;
+use libp2p::{identity, PeerId};
+use crate::store::now_millis;
+use crate::vault::Vault;
+
+// Several installs (devices) of one user. Every install keeps its own
+// PeerId; the account is the PeerId of the primary device, whose identity
+// key signs a `DeviceCert` for each device linked to it. The primary sends
+// the full list of certificates to its devices and contacts as
+// `P2PMessage::Devices`. Contacts then send to every device of the account
+// and show all of their messages in the account's conversation.
+//
+// Linking: the new device signs its consent to join the account and shows it
+// as a link code (`link_code`). The user enters the code on the primary,
+// which signs the certificate and sends the new device the list. The new
+// device then pulls the history from the primary with
+// `P2PMessage::HistoryRequest`. Both signatures are in the certificate, so
+// no one can claim a device that didn't agree to it.
+
+const DEVICES_FILE: &str = "devices.bin";
+const LINK_CODE_PREFIX: &str = "phantom-link:1";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCert {
+    // PeerId of the primary device
+    pub account: String,
+    pub device: String,
+    pub name: String,
+    pub issued_at: i64,
+    // The device's signature over `consent_payload`, hex
+    pub device_signature: String,
+    // The account's signature over the fields above, hex
+    pub signature: String,
+}
+
+fn consent_payload(account: &str, device: &str) -> Vec<u8> {
+    format!("phantom-link:{}:{}", account, device).into_bytes()
+}
+
+fn signing_payload(account: &str, device: &str, name: &str, issued_at: i64, device_signature: &str) -> Vec<u8> {
+    format!("phantom-device:{}:{}:{}:{}:{}", account, device, issued_at, device_signature, name).into_bytes()
+}
+
+// What a new device shows to be linked to `account`.
+pub fn link_code(device: &identity::Keypair, account: &str) -> Result<String, String> {
+    account.parse::<PeerId>().map_err(|e| format!("Invalid PeerId: {}", e))?;
+    let device_id = device.public().to_peer_id().to_string();
+    let signature = device.sign(&consent_payload(account, &device_id)).map_err(|e| e.to_string())?;
+    Ok(format!("{}:{}:{}", LINK_CODE_PREFIX, device_id, hex::encode(signature)))
+}
+
+// Ed25519 PeerIds carry the public key itself.
+fn public_key(peer_id: &str) -> Result<identity::PublicKey, String> {
+    let peer_id: PeerId = peer_id.parse().map_err(|e| format!("Invalid PeerId: {}", e))?;
+    let multihash = peer_id.as_ref();
+    if multihash.code() != 0 {
+        return Err("PeerId doesn't carry its public key".to_string());
+    }
+    identity::PublicKey::try_decode_protobuf(multihash.digest()).map_err(|e| e.to_string())
+}
+
+fn verify_signature(peer_id: &str, payload: &[u8], signature: &str) -> Result<bool, String> {
+    let signature = hex::decode(signature).map_err(|e| e.to_string())?;
+    Ok(public_key(peer_id)?.verify(payload, &signature))
+}
+
+impl DeviceCert {
+    // Signs a certificate for the device that showed `code`.
+    pub fn issue(account: &identity::Keypair, code: &str, name: &str) -> Result<Self, String> {
+        let rest = code.trim().strip_prefix(LINK_CODE_PREFIX).and_then(|rest| rest.strip_prefix(':'));
+        let Some((device, device_signature)) = rest.and_then(|rest| rest.split_once(':')) else {
+            return Err("Not a device link code".to_string());
+        };
+        let account_id = account.public().to_peer_id().to_string();
+        if !verify_signature(device, &consent_payload(&account_id, device), device_signature)? {
+            return Err("The link code is for another account".to_string());
+        }
+
+        let issued_at = now_millis();
+        let signature = account.sign(&signing_payload(&account_id, device, name, issued_at, device_signature))
+            .map_err(|e| e.to_string())?;
+        Ok(DeviceCert {
+            account: account_id,
+            device: device.to_string(),
+            name: name.to_string(),
+            issued_at,
+            device_signature: device_signature.to_string(),
+            signature: hex::encode(signature),
+        })
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        let signed = signing_payload(&self.account, &self.device, &self.name, self.issued_at, &self.device_signature);
+        if !verify_signature(&self.account, &signed, &self.signature)?
+            || !verify_signature(&self.device, &consent_payload(&self.account, &self.device), &self.device_signature)?
+        {
+            return Err(format!("Invalid certificate for device {}", self.device));
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DevicesInfo {
+    // PeerId of our account's primary
+    pub account: String,
+    pub primary: bool,
+    pub devices: Vec<DeviceCert>,
+    pub pending_link: Option<String>,
+}
+
+// What a `P2PMessage::Devices` changed.
+#[derive(Debug, PartialEq)]
+pub enum DevicesUpdate {
+    // We were linked to the account we asked for
+    Linked,
+    // Our account's device list changed; `removed` if we're no longer on it
+    Own { removed: bool },
+    Contact,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct Devices {
+    // The primary we are linked to, `None` on the primary itself and on
+    // installs that were never linked
+    pub account: Option<String>,
+    // Certificates of our account's devices, except the primary's
+    pub own: Vec<DeviceCert>,
+    // The primary we asked to be linked to
+    pub pending_link: Option<String>,
+    // Certificates of our contacts' devices, by account
+    contacts: HashMap<String, Vec<DeviceCert>>,
+}
+
+impl Devices {
+    pub fn load(vault: &Vault) -> Result<Self, Box<dyn Error>> {
+        match vault.read(DEVICES_FILE)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(Devices::default()),
+        }
+    }
+
+    pub fn save(&self, vault: &Vault) -> Result<(), Box<dyn Error>> {
+        vault.write(DEVICES_FILE, &serde_json::to_vec(self)?)
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.account.is_none()
+    }
+
+    pub fn info(&self, local_peer_id: &str) -> DevicesInfo {
+        DevicesInfo {
+            account: self.account.clone().unwrap_or_else(|| local_peer_id.to_string()),
+            primary: self.is_primary(),
+            devices: self.own.clone(),
+            pending_link: self.pending_link.clone(),
+        }
+    }
+
+    // The conversation messages from `peer_id` belong in: its account if it's
+    // a linked device of a contact.
+    pub fn account_of(&self, peer_id: &str) -> String {
+        self.contacts.iter()
+            .find(|(_, certs)| certs.iter().any(|c| c.device == peer_id))
+            .map(|(account, _)| account.clone())
+            .unwrap_or_else(|| peer_id.to_string())
+    }
+
+    // Every PeerId a message to `account` goes to.
+    pub fn recipients(&self, account: &str) -> Vec<String> {
+        let mut recipients = vec![account.to_string()];
+        for cert in self.contacts.get(account).into_iter().flatten() {
+            if !recipients.contains(&cert.device) {
+                recipients.push(cert.device.clone());
+            }
+        }
+        recipients
+    }
+
+    // Whether `peer_id` is another device of our own account, primary included.
+    pub fn is_own_device(&self, peer_id: &str) -> bool {
+        self.account.as_deref() == Some(peer_id) || self.own.iter().any(|c| c.device == peer_id)
+    }
+
+    // Our account's other devices.
+    pub fn own_devices(&self, local_peer_id: &str) -> Vec<String> {
+        self.account.iter().cloned()
+            .chain(self.own.iter().map(|c| c.device.clone()))
+            .filter(|d| d != local_peer_id)
+            .collect()
+    }
+
+    // Applies the certificate list `sender` sent us.
+    pub fn receive(&mut self, local_peer_id: &str, sender: &str, certs: Vec<DeviceCert>) -> Result<DevicesUpdate, String> {
+        for cert in &certs {
+            if cert.account != sender {
+                return Err(format!("{} sent a certificate for account {}", sender, cert.account));
+            }
+            cert.verify()?;
+        }
+        let ours = certs.iter().any(|c| c.device == local_peer_id);
+
+        if self.pending_link.as_deref() == Some(sender) && ours {
+            self.account = Some(sender.to_string());
+            self.own = certs;
+            self.pending_link = None;
+            Ok(DevicesUpdate::Linked)
+        } else if self.account.as_deref() == Some(sender) {
+            if ours {
+                self.own = certs;
+            } else {
+                self.account = None;
+                self.own.clear();
+            }
+            Ok(DevicesUpdate::Own { removed: !ours })
+        } else {
+            if certs.is_empty() {
+                self.contacts.remove(sender);
+            } else {
+                self.contacts.insert(sender.to_string(), certs);
+            }
+            Ok(DevicesUpdate::Contact)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id(key: &identity::Keypair) -> String {
+        key.public().to_peer_id().to_string()
+    }
+
+    #[test]
+    fn certificate_needs_both_signatures() {
+        let (account, device) = (identity::Keypair::generate_ed25519(), identity::Keypair::generate_ed25519());
+        let code = link_code(&device, &peer_id(&account)).unwrap();
+        let cert = DeviceCert::issue(&account, &code, "Ноутбук").unwrap();
+        assert_eq!(cert.device, peer_id(&device));
+        assert!(cert.verify().is_ok());
+
+        let mut renamed = cert.clone();
+        renamed.name = "Телефон".to_string();
+        assert!(renamed.verify().is_err());
+
+        let mut stolen = cert;
+        stolen.device = peer_id(&identity::Keypair::generate_ed25519());
+        assert!(stolen.verify().is_err());
+    }
+
+    #[test]
+    fn link_code_only_works_for_its_account() {
+        let (account, other, device) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let code = link_code(&device, &peer_id(&account)).unwrap();
+        assert!(DeviceCert::issue(&other, &code, "Ноутбук").is_err());
+        assert!(DeviceCert::issue(&account, "phantom-link:1:garbage", "Ноутбук").is_err());
+    }
+
+    #[test]
+    fn linking_and_contacts() {
+        let (account, device) = (identity::Keypair::generate_ed25519(), identity::Keypair::generate_ed25519());
+        let (account_id, device_id) = (peer_id(&account), peer_id(&device));
+        let cert = DeviceCert::issue(&account, &link_code(&device, &account_id).unwrap(), "Ноутбук").unwrap();
+
+        // The new device asked to be linked
+        let mut ours = Devices { pending_link: Some(account_id.clone()), ..Devices::default() };
+        assert_eq!(ours.receive(&device_id, &account_id, vec![cert.clone()]), Ok(DevicesUpdate::Linked));
+        assert!(!ours.is_primary());
+        assert_eq!(ours.own_devices(&device_id), vec![account_id.clone()]);
+
+        // Unlinked by the primary
+        assert_eq!(ours.receive(&device_id, &account_id, Vec::new()), Ok(DevicesUpdate::Own { removed: true }));
+        assert!(ours.is_primary());
+
+        // A contact learns where else to send
+        let mut contact = Devices::default();
+        assert_eq!(contact.receive("contact", &account_id, vec![cert.clone()]), Ok(DevicesUpdate::Contact));
+        assert_eq!(contact.account_of(&device_id), account_id);
+        assert_eq!(contact.recipients(&account_id), vec![account_id.clone(), device_id]);
+
+        // Nobody else can hand out the account's certificates
+        assert!(contact.receive("contact", "someone", vec![cert]).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use libp2p::{core::PeerRecord, core::SignedEnvelope, identify, identity, kad, multiaddr::Protocol, Multiaddr, PeerId, StreamProtocol};
+
+// Peer discovery beyond mDNS. We run our own Kademlia DHT (not the IPFS one)
+// seeded from the bootstrap nodes in settings. Desktop nodes are usually in
+// client mode and so never end up in anyone's routing table; instead every
+// node puts a signed record with its current addresses under
+// `/phantom/addrs/<peer id>`, which contacts look up before dialing.
+
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/phantom/kad/1.0.0");
+pub const IDENTIFY_PROTOCOL: &str = "/phantom/id/1.0.0";
+
+const ADDRESS_RECORD_PREFIX: &str = "/phantom/addrs/";
+
+// Kademlia on our own protocol, so we don't end up in the IPFS DHT.
+// Newer libp2p wants the protocol in `Config::new`, which ours doesn't have yet.
+#[allow(deprecated)]
+pub fn build_kad(peer_id: PeerId) -> kad::Behaviour<kad::store::MemoryStore> {
+    let mut config = kad::Config::default();
+    config.set_protocol_names(vec![KAD_PROTOCOL]);
+    kad::Behaviour::with_config(peer_id, kad::store::MemoryStore::new(peer_id), config)
+}
+
+// Tells peers which addresses we listen on, so they can be added to the DHT
+pub fn build_identify(key: &identity::Keypair) -> identify::Behaviour {
+    identify::Behaviour::new(identify::Config::new(IDENTIFY_PROTOCOL.to_string(), key.public()))
+}
+
+pub fn address_record_key(peer_id: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{}{}", ADDRESS_RECORD_PREFIX, peer_id))
+}
+
+// The peer whose addresses are stored under `key`, if it's one of our address records.
+pub fn peer_from_record_key(key: &kad::RecordKey) -> Option<PeerId> {
+    std::str::from_utf8(key.as_ref()).ok()?
+        .strip_prefix(ADDRESS_RECORD_PREFIX)?
+        .parse()
+        .ok()
+}
+
+pub fn address_record(identity: &identity::Keypair, addrs: Vec<Multiaddr>) -> Result<kad::Record, String> {
+    let peer_id = identity.public().to_peer_id();
+    let signed = PeerRecord::new(identity, addrs).map_err(|e| e.to_string())?;
+    let value = signed.into_signed_envelope().into_protobuf_encoding();
+    Ok(kad::Record::new(address_record_key(&peer_id), value))
+}
+
+// Anyone can put anything under a key, so only addresses signed by the peer
+// the key is about are accepted.
+pub fn verify_address_record(record: &kad::Record) -> Result<(PeerId, Vec<Multiaddr>), String> {
+    let expected = peer_from_record_key(&record.key).ok_or("Not an address record")?;
+    let envelope = SignedEnvelope::from_protobuf_encoding(&record.value).map_err(|e| e.to_string())?;
+    let signed = PeerRecord::from_signed_envelope(envelope).map_err(|e| e.to_string())?;
+    if signed.peer_id() != expected {
+        return Err(format!("Address record for {} signed by {}", expected, signed.peer_id()));
+    }
+    Ok((expected, signed.addresses().to_vec()))
+}
+
+// Bootstrap nodes are full multiaddrs ending in `/p2p/<peer id>`.
+pub fn parse_bootstrap_node(addr: &str) -> Result<(PeerId, Multiaddr), String> {
+    let addr: Multiaddr = addr.parse().map_err(|e| format!("Invalid multiaddr {}: {}", addr, e))?;
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Ok((peer_id, addr)),
+        _ => Err(format!("Bootstrap node {} has no /p2p/ suffix", addr)),
+    }
+}
+
+// Addresses worth publishing: what others confirmed they see us at, plus our
+// listen addresses minus loopback and unspecified ones.
+pub fn publishable_addresses<'a>(external: impl Iterator<Item = &'a Multiaddr>, listen: impl Iterator<Item = &'a Multiaddr>) -> Vec<Multiaddr> {
+    let mut addrs: Vec<Multiaddr> = external.cloned().collect();
+    for addr in listen {
+        let local = addr.iter().any(|p| match p {
+            Protocol::Ip4(ip) => ip.is_loopback() || ip.is_unspecified(),
+            Protocol::Ip6(ip) => ip.is_loopback() || ip.is_unspecified(),
+            _ => false,
+        });
+        if !local && !addrs.contains(addr) {
+            addrs.push(addr.clone());
+        }
+    }
+    addrs
+}
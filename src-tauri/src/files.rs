@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce
+};
+use libp2p::{request_response, StreamProtocol};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use crate::vault::Vault;
+
+// Direct file transfer. The sender announces a file with a sealed
+// `FileOffer` over the inbox, which carries a fresh AES-256 key; the
+// receiver then pulls the file chunk by chunk over `/phantom/file/1`. Pulling
+// makes pause and resume trivial: the receiver just stops asking, and after a
+// restart picks up at the first chunk it doesn't have yet. Every chunk is
+// sealed under the transfer key and carries the hash of its plaintext, and
+// the finished file is checked against the hash from the offer.
+
+pub const FILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/phantom/file/1");
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+pub type FileBehaviour = request_response::cbor::Behaviour<ChunkRequest, ChunkResponse>;
+
+pub fn build_file_behaviour() -> FileBehaviour {
+    request_response::cbor::Behaviour::new(
+        [(FILE_PROTOCOL, request_response::ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ChunkRequest {
+    pub id: String,
+    pub index: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub enum ChunkResponse {
+    // The sealed chunk and the SHA-256 of its plaintext
+    Chunk {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+        hash: String,
+    },
+    // The sender paused the transfer, ask again later
+    Paused,
+    // Unknown or cancelled transfer, or one that wasn't offered to the requester
+    Unavailable,
+}
+
+// Sent sealed over the inbox to announce a file.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct FileOffer {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+    pub key: [u8; 32],
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferStatus {
+    // Incoming, waiting for the user to accept
+    Offered,
+    Active,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Transfer {
+    pub id: String,
+    pub peer_id: String,
+    pub direction: Direction,
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+    pub key: [u8; 32],
+    pub status: TransferStatus,
+    // Outgoing: the file we serve. Incoming: the `.part` file while
+    // receiving, the finished file once completed.
+    pub path: PathBuf,
+    // Incoming: chunks written so far. Outgoing: chunks served so far.
+    pub chunks_done: u64,
+}
+
+// What the UI gets to see: everything except the key.
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferInfo {
+    pub id: String,
+    pub peer_id: String,
+    pub direction: Direction,
+    pub name: String,
+    pub size: u64,
+    pub transferred: u64,
+    pub status: TransferStatus,
+    pub path: Option<String>,
+}
+
+impl Transfer {
+    pub fn outgoing(peer_id: &str, path: PathBuf, size: u64, hash: String) -> Self {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        let name = path.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_string());
+
+        Transfer {
+            id: hex::encode(id),
+            peer_id: peer_id.to_string(),
+            direction: Direction::Outgoing,
+            name,
+            size,
+            hash,
+            key,
+            status: TransferStatus::Active,
+            path,
+            chunks_done: 0,
+        }
+    }
+
+    pub fn incoming(peer_id: &str, offer: FileOffer, downloads_dir: &Path) -> Self {
+        Transfer {
+            path: downloads_dir.join(format!("{}.part", offer.id)),
+            id: offer.id,
+            peer_id: peer_id.to_string(),
+            direction: Direction::Incoming,
+            // Only the file name, never a path from the peer
+            name: sanitize_name(&offer.name),
+            size: offer.size,
+            hash: offer.hash,
+            key: offer.key,
+            status: TransferStatus::Offered,
+            chunks_done: 0,
+        }
+    }
+
+    pub fn offer(&self) -> FileOffer {
+        FileOffer {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            size: self.size,
+            hash: self.hash.clone(),
+            key: self.key,
+        }
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        self.size.div_ceil(CHUNK_SIZE)
+    }
+
+    // A failed transfer can still be resumed or cancelled.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, TransferStatus::Completed | TransferStatus::Cancelled)
+    }
+
+    // Stops the transfer for good. A partly received file is removed.
+    pub fn cancel(&mut self) -> Result<(), String> {
+        if self.is_finished() {
+            return Err("Transfer is already finished".to_string());
+        }
+        self.status = TransferStatus::Cancelled;
+        if self.direction == Direction::Incoming {
+            let _ = fs::remove_file(&self.path);
+        }
+        Ok(())
+    }
+
+    pub fn transferred(&self) -> u64 {
+        (self.chunks_done * CHUNK_SIZE).min(self.size)
+    }
+
+    pub fn info(&self) -> TransferInfo {
+        let show_path = self.direction == Direction::Outgoing || self.status == TransferStatus::Completed;
+        TransferInfo {
+            id: self.id.clone(),
+            peer_id: self.peer_id.clone(),
+            direction: self.direction,
+            name: self.name.clone(),
+            size: self.size,
+            transferred: self.transferred(),
+            status: self.status,
+            path: show_path.then(|| self.path.to_string_lossy().to_string()),
+        }
+    }
+}
+
+// Transfer ids come from the peer and end up in a file name, so only accept
+// what `Transfer::outgoing` generates.
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn sanitize_name(name: &str) -> String {
+    let name = Path::new(name).file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if name.is_empty() || name.starts_with('.') {
+        format!("file{}", name)
+    } else {
+        name
+    }
+}
+
+// `dir/name`, or `dir/name (n)` if that's taken.
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+        _ => (name.to_string(), String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, ext)))
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub fn read_chunk(path: &Path, index: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+    let mut data = Vec::new();
+    file.take(CHUNK_SIZE).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub fn write_chunk(path: &Path, index: u64, data: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    file.seek(SeekFrom::Start(index * CHUNK_SIZE))?;
+    file.write_all(data)
+}
+
+// The key is only used for this transfer, so the chunk index is a unique
+// nonce. Binding the transfer id and index as associated data stops chunks
+// from being served out of place.
+fn chunk_nonce_and_aad(id: &str, index: u64) -> ([u8; 12], Vec<u8>) {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    let mut aad = id.as_bytes().to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    (nonce, aad)
+}
+
+pub fn seal_chunk(key: &[u8; 32], id: &str, index: u64, data: &[u8]) -> Result<ChunkResponse, String> {
+    let (nonce, aad) = chunk_nonce_and_aad(id, index);
+    let cipher = Aes256Gcm::new(key.into());
+    let sealed = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: &aad })
+        .map_err(|e| e.to_string())?;
+    Ok(ChunkResponse::Chunk { data: sealed, hash: hex::encode(Sha256::digest(data)) })
+}
+
+pub fn open_chunk(key: &[u8; 32], id: &str, index: u64, sealed: &[u8], hash: &str) -> Result<Vec<u8>, String> {
+    let (nonce, aad) = chunk_nonce_and_aad(id, index);
+    let cipher = Aes256Gcm::new(key.into());
+    let data = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: sealed, aad: &aad })
+        .map_err(|_| format!("Failed to decrypt chunk {}", index))?;
+    if hex::encode(Sha256::digest(&data)) != hash {
+        return Err(format!("Hash mismatch in chunk {}", index));
+    }
+    Ok(data)
+}
+
+const TRANSFERS_FILE: &str = "transfers.bin";
+
+pub fn load_transfers(vault: &Vault) -> Result<HashMap<String, Transfer>, Box<dyn Error>> {
+    match vault.read(TRANSFERS_FILE)? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+pub fn save_transfers(vault: &Vault, transfers: &HashMap<String, Transfer>) -> Result<(), Box<dyn Error>> {
+    vault.write(TRANSFERS_FILE, &serde_json::to_vec(transfers)?)
+}
+
+// Checks a fully received file against the hash from the offer and moves it
+// next to the other downloads under its real name. Returns the final path.
+pub fn finish_download(part_path: &Path, downloads_dir: &Path, name: &str, hash: &str) -> Result<PathBuf, String> {
+    // An empty file has no chunks, so nothing was written yet
+    if !part_path.exists() {
+        File::create(part_path).map_err(|e| e.to_string())?;
+    }
+    let actual = hash_file(part_path).map_err(|e| e.to_string())?;
+    if actual != hash {
+        let _ = fs::remove_file(part_path);
+        return Err("File hash does not match the offer".to_string());
+    }
+    let path = unique_path(downloads_dir, name);
+    fs::rename(part_path, &path).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("phantom-files-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn offer(id: &str) -> FileOffer {
+        FileOffer { id: id.to_string(), name: "photo.jpg".to_string(), size: 10, hash: String::new(), key: [0; 32] }
+    }
+
+    #[test]
+    fn chunks_only_open_in_place() {
+        let key = [1; 32];
+        let ChunkResponse::Chunk { data, hash } = seal_chunk(&key, "id", 3, b"chunk").unwrap() else {
+            panic!("not a chunk");
+        };
+        assert_eq!(open_chunk(&key, "id", 3, &data, &hash).unwrap(), b"chunk");
+        assert!(open_chunk(&key, "id", 4, &data, &hash).is_err());
+        assert!(open_chunk(&key, "other", 3, &data, &hash).is_err());
+        assert!(open_chunk(&[2; 32], "id", 3, &data, &hash).is_err());
+    }
+
+    #[test]
+    fn offered_names_stay_in_the_downloads_dir() {
+        assert_eq!(sanitize_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_name(".bashrc"), "file.bashrc");
+        assert_eq!(sanitize_name(""), "file");
+        assert!(!is_valid_id("../part"));
+        assert!(is_valid_id(&"ab".repeat(16)));
+    }
+
+    #[test]
+    fn unique_path_numbers_taken_names() {
+        let dir = temp_dir("unique");
+        fs::write(dir.join("photo.jpg"), b"").unwrap();
+        assert_eq!(unique_path(&dir, "photo.jpg"), dir.join("photo (1).jpg"));
+        assert_eq!(unique_path(&dir, "other.jpg"), dir.join("other.jpg"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn download_is_checked_against_the_offer() {
+        let dir = temp_dir("finish");
+        let part = dir.join("x.part");
+        write_chunk(&part, 0, b"contents").unwrap();
+        let hash = hex::encode(Sha256::digest(b"contents"));
+
+        assert!(finish_download(&part, &dir, "a.txt", &"00".repeat(32)).is_err());
+        assert!(!part.exists());
+
+        write_chunk(&part, 0, b"contents").unwrap();
+        let path = finish_download(&part, &dir, "a.txt", &hash).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"contents");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn cancelling_a_failed_download_removes_the_part_file() {
+        let dir = temp_dir("cancel");
+        let mut transfer = Transfer::incoming("peer", offer("failed"), &dir);
+        write_chunk(&transfer.path, 0, b"partial").unwrap();
+        transfer.status = TransferStatus::Failed;
+
+        transfer.cancel().unwrap();
+        assert_eq!(transfer.status, TransferStatus::Cancelled);
+        assert!(!transfer.path.exists());
+        assert!(transfer.cancel().is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
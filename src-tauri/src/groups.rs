@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::error::Error;
+use libp2p::gossipsub;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use crate::vault::Vault;
+
+// Group channels. Every group has its own random AES-256 key which a
+// member hands to the others over the 1-on-1 inbox channel (see
+// `GroupUpdate`). The key is replaced on every membership change and on
+// explicit rotation, so `epoch` doubles as the key id carried by each group
+// message. Any member may invite, remove or rotate; when two of them change
+// the group at the same epoch, `supersedes` picks the same key on every
+// member and the other change is dropped. The owner is the group's creator
+// and is only shown in the UI.
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub key: [u8; 32],
+    pub epoch: u64,
+    pub members: Vec<String>,
+    // Groups saved before owners existed are owned by their first member
+    #[serde(default)]
+    pub owner: String,
+}
+
+// What the UI gets to see: everything except the key.
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupInfo {
+    pub id: String,
+    pub name: String,
+    pub epoch: u64,
+    pub members: Vec<String>,
+    pub owner: String,
+}
+
+// Plaintext of `P2PMessage::Group`, encrypted with the pairwise shared key.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "payload")]
+pub enum GroupUpdate {
+    Key(Group),
+    Removed { group_id: String },
+}
+
+// Wire format of a message published on a group topic.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct GroupEnvelope {
+    pub epoch: u64,
+    pub content: String,
+}
+
+impl Group {
+    pub fn new(name: String, owner: String) -> Self {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        Group {
+            id: hex::encode(id),
+            name,
+            key: random_key(),
+            epoch: 0,
+            members: vec![owner.clone()],
+            owner,
+        }
+    }
+
+    pub fn owner(&self) -> &str {
+        match self.members.first() {
+            Some(first) if self.owner.is_empty() => first,
+            _ => &self.owner,
+        }
+    }
+
+    // Whether `self` should replace `current`, our copy of the same group:
+    // a later epoch does, and at the same epoch the key with the lower hash.
+    pub fn supersedes(&self, current: &Group) -> bool {
+        self.epoch > current.epoch
+            || (self.epoch == current.epoch && Sha256::digest(self.key) < Sha256::digest(current.key))
+    }
+
+    pub fn topic_name(&self) -> String {
+        topic_for(&self.id)
+    }
+
+    pub fn topic(&self) -> gossipsub::IdentTopic {
+        gossipsub::IdentTopic::new(self.topic_name())
+    }
+
+    pub fn is_member(&self, peer_id: &str) -> bool {
+        self.members.iter().any(|m| m == peer_id)
+    }
+
+    pub fn rotate_key(&mut self) {
+        self.key = random_key();
+        self.epoch += 1;
+    }
+
+    pub fn info(&self) -> GroupInfo {
+        GroupInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            epoch: self.epoch,
+            members: self.members.clone(),
+            owner: self.owner().to_string(),
+        }
+    }
+}
+
+pub fn topic_for(group_id: &str) -> String {
+    format!("group-{}", group_id)
+}
+
+fn random_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+const GROUPS_FILE: &str = "groups.bin";
+
+pub fn load_groups(vault: &Vault) -> Result<HashMap<String, Group>, Box<dyn Error>> {
+    match vault.read(GROUPS_FILE)? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+pub fn save_groups(vault: &Vault, groups: &HashMap<String, Group>) -> Result<(), Box<dyn Error>> {
+    vault.write(GROUPS_FILE, &serde_json::to_vec(groups)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_epoch_supersedes() {
+        let mut group = Group::new("test".to_string(), "owner".to_string());
+        let old = group.clone();
+        group.rotate_key();
+        assert!(group.supersedes(&old));
+        assert!(!old.supersedes(&group));
+    }
+
+    #[test]
+    fn concurrent_keys_pick_the_same_winner() {
+        let base = Group::new("test".to_string(), "owner".to_string());
+        let (mut a, mut b) = (base.clone(), base);
+        a.rotate_key();
+        b.rotate_key();
+        assert_eq!(a.epoch, b.epoch);
+        assert_ne!(a.supersedes(&b), b.supersedes(&a));
+        assert!(!a.supersedes(&a));
+    }
+
+    #[test]
+    fn first_member_owns_groups_saved_without_owner() {
+        let mut group = Group::new("test".to_string(), "owner".to_string());
+        group.members.push("member".to_string());
+        group.owner.clear();
+        assert_eq!(group.owner(), "owner");
+    }
+}
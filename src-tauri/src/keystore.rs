@@ -0,0 +1,242 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{rngs::OsRng, RngCore};
+use crate::vault::Vault;
+
+// The key files in the data dir: the libp2p identity, the X25519 keys and
+// `storage.key`, which the vault and the message store are encrypted under.
+// Without a passphrase they are plain files. With one, `passphrase.json`
+// holds the Argon2 salt and parameters, and every key file is sealed under
+// the key derived from the passphrase, so nothing can be read before it is
+// entered.
+
+pub const KEY_FILES: &[&str] = &["identity.key", "ecdh.key", "prekey.key", "storage.key"];
+const PASSPHRASE_FILE: &str = "passphrase.json";
+// Present while a passphrase change is being applied, see `set_passphrase`
+const COMMIT_FILE: &str = "keys.commit";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PassphraseParams {
+    salt: String,
+    // Argon2id memory (KiB), iterations and lanes
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl PassphraseParams {
+    pub fn generate() -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        PassphraseParams { salt: hex::encode(salt), m_cost: 64 * 1024, t_cost: 3, p_cost: 1 }
+    }
+
+    pub fn derive_key(&self, passphrase: &str) -> Result<[u8; 32], Box<dyn Error>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32)).map_err(|e| e.to_string())?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &hex::decode(&self.salt)?, &mut key)
+            .map_err(|e| e.to_string())?;
+        Ok(key)
+    }
+}
+
+#[derive(Clone)]
+pub struct KeyStore {
+    dir: PathBuf,
+    // Seals the key files, if there is a passphrase
+    sealed: Option<Vault>,
+}
+
+impl KeyStore {
+    pub fn is_protected(dir: &Path) -> bool {
+        finish_commit(dir);
+        dir.join(PASSPHRASE_FILE).exists()
+    }
+
+    // Fails if a passphrase is set and `passphrase` is missing or wrong.
+    pub fn open(dir: &Path, passphrase: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+        }
+        finish_commit(dir);
+
+        let params_path = dir.join(PASSPHRASE_FILE);
+        let sealed = if params_path.exists() {
+            let params: PassphraseParams = serde_json::from_slice(&fs::read(&params_path)?)?;
+            let passphrase = passphrase.ok_or("A passphrase is required")?;
+            Some(Vault::with_key(dir, params.derive_key(passphrase)?))
+        } else {
+            None
+        };
+
+        let keys = KeyStore { dir: dir.to_path_buf(), sealed };
+        // A wrong passphrase can't open any of them
+        for name in KEY_FILES {
+            if keys.read(name).is_err() {
+                return Err(if keys.sealed.is_some() { "Wrong passphrase".into() } else { format!("Failed to read {}", name).into() });
+            }
+        }
+        Ok(keys)
+    }
+
+    // Plain key files in `dir`, for the relay, which has no passphrase.
+    pub fn plain(dir: &Path) -> Self {
+        KeyStore { dir: dir.to_path_buf(), sealed: None }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed.is_some()
+    }
+
+    // Returns `None` if the file doesn't exist yet.
+    pub fn read(&self, name: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match &self.sealed {
+            Some(vault) => vault.read(name),
+            None => {
+                let path = self.dir.join(name);
+                if !path.exists() {
+                    return Ok(None);
+                }
+                Ok(Some(fs::read(path)?))
+            }
+        }
+    }
+
+    pub fn write(&self, name: &str, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        match &self.sealed {
+            Some(vault) => vault.write(name, bytes),
+            None => Ok(fs::write(self.dir.join(name), bytes)?),
+        }
+    }
+
+    // Seals the key files under `passphrase` instead, or stores them in plain
+    // if it's `None`. The new files are staged next to the old ones and only
+    // swapped in once all of them are written, so a crash leaves either the
+    // old or the new set behind.
+    pub fn set_passphrase(&self, passphrase: Option<&str>) -> Result<KeyStore, Box<dyn Error>> {
+        let params = passphrase.map(|_| PassphraseParams::generate());
+        let sealed = match (&params, passphrase) {
+            (Some(params), Some(passphrase)) => Some(Vault::with_key(&self.dir, params.derive_key(passphrase)?)),
+            _ => None,
+        };
+        let next = KeyStore { dir: self.dir.clone(), sealed };
+
+        for name in KEY_FILES {
+            let Some(bytes) = self.read(name)? else { continue };
+            let data = match &next.sealed {
+                Some(vault) => vault.seal(name, &bytes)?,
+                None => bytes,
+            };
+            fs::write(staged(&self.dir, name), data)?;
+        }
+        match &params {
+            Some(params) => fs::write(staged(&self.dir, PASSPHRASE_FILE), serde_json::to_vec(params)?)?,
+            None => {
+                let _ = fs::remove_file(staged(&self.dir, PASSPHRASE_FILE));
+            }
+        }
+
+        // From here on the new set wins, even after a crash
+        fs::write(self.dir.join(COMMIT_FILE), if params.is_some() { "sealed" } else { "plain" })?;
+        finish_commit(&self.dir);
+        Ok(next)
+    }
+}
+
+fn staged(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.new", name))
+}
+
+// Swaps in a staged set of key files if its commit was started, and throws
+// away one that never got that far.
+fn finish_commit(dir: &Path) {
+    let commit = fs::read_to_string(dir.join(COMMIT_FILE)).ok();
+
+    for name in KEY_FILES.iter().chain(&[PASSPHRASE_FILE]) {
+        let staged = staged(dir, name);
+        if !staged.exists() {
+            continue;
+        }
+        let result = match commit {
+            Some(_) => fs::rename(&staged, dir.join(name)),
+            None => fs::remove_file(&staged),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to apply staged {}: {}", name, e);
+            return;
+        }
+    }
+
+    if commit.as_deref() == Some("plain") {
+        let _ = fs::remove_file(dir.join(PASSPHRASE_FILE));
+    }
+    if commit.is_some() {
+        let _ = fs::remove_file(dir.join(COMMIT_FILE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("phantom-keystore-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn passphrase_seals_and_unseals_the_key_files() {
+        let dir = temp_dir("passphrase");
+        let keys = KeyStore::open(&dir, None).unwrap();
+        keys.write("identity.key", b"secret").unwrap();
+
+        keys.set_passphrase(Some("correct horse")).unwrap();
+        assert!(KeyStore::is_protected(&dir));
+        assert_ne!(fs::read(dir.join("identity.key")).unwrap(), b"secret");
+        assert!(KeyStore::open(&dir, None).is_err());
+        assert!(KeyStore::open(&dir, Some("wrong")).is_err());
+
+        let keys = KeyStore::open(&dir, Some("correct horse")).unwrap();
+        assert_eq!(keys.read("identity.key").unwrap().unwrap(), b"secret");
+
+        keys.set_passphrase(None).unwrap();
+        assert!(!KeyStore::is_protected(&dir));
+        assert_eq!(fs::read(dir.join("identity.key")).unwrap(), b"secret");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn uncommitted_staged_files_are_discarded() {
+        let dir = temp_dir("staged");
+        let keys = KeyStore::open(&dir, None).unwrap();
+        keys.write("identity.key", b"old").unwrap();
+        fs::write(staged(&dir, "identity.key"), b"new").unwrap();
+
+        let keys = KeyStore::open(&dir, None).unwrap();
+        assert_eq!(keys.read("identity.key").unwrap().unwrap(), b"old");
+        assert!(!staged(&dir, "identity.key").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn committed_staged_files_are_swapped_in() {
+        let dir = temp_dir("commit");
+        let keys = KeyStore::open(&dir, None).unwrap();
+        keys.write("identity.key", b"old").unwrap();
+        fs::write(staged(&dir, "identity.key"), b"new").unwrap();
+        fs::write(dir.join(COMMIT_FILE), "plain").unwrap();
+
+        let keys = KeyStore::open(&dir, None).unwrap();
+        assert_eq!(keys.read("identity.key").unwrap().unwrap(), b"new");
+        assert!(!dir.join(COMMIT_FILE).exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::vault::Vault;
+
+// Store-and-forward for peers that are offline. A node with the mailbox role
+// enabled subscribes to the inbox topics of the contacts registered with it
+// and holds whatever is published there while the contact is away. The held
+// frames are the sealed ratchet frames as they were published, so the mailbox
+// can't read them; it only learns who talks to whom.
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MailboxSettings {
+    // Serve as a mailbox for contacts that register with us
+    pub enabled: bool,
+    // The peer we ask to hold our own messages
+    pub mailbox_peer: Option<String>,
+    pub max_messages_per_peer: usize,
+    pub retention_hours: u64,
+}
+
+impl Default for MailboxSettings {
+    fn default() -> Self {
+        MailboxSettings {
+            enabled: false,
+            mailbox_peer: None,
+            max_messages_per_peer: 500,
+            retention_hours: 7 * 24,
+        }
+    }
+}
+
+// Sent over the sealed inbox channel between a contact and its mailbox.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum MailboxMessage {
+    Register,
+    Unregister,
+    Accepted,
+    Declined,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct HeldFrame {
+    pub from: String,
+    pub frame: String,
+    pub received_at: u64,
+}
+
+#[derive(Default)]
+pub struct Mailbox {
+    pub settings: MailboxSettings,
+    // Registered contacts and the frames we hold for them
+    clients: HashMap<String, Vec<HeldFrame>>,
+}
+
+const SETTINGS_FILE: &str = "mailbox-settings.bin";
+const MAILBOX_FILE: &str = "mailbox.bin";
+
+impl Mailbox {
+    pub fn load(vault: &Vault) -> Result<Self, Box<dyn Error>> {
+        let settings = match vault.read(SETTINGS_FILE)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => MailboxSettings::default(),
+        };
+        let clients = match vault.read(MAILBOX_FILE)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => HashMap::new(),
+        };
+        Ok(Mailbox { settings, clients })
+    }
+
+    pub fn save_settings(&self, vault: &Vault) -> Result<(), Box<dyn Error>> {
+        vault.write(SETTINGS_FILE, &serde_json::to_vec(&self.settings)?)
+    }
+
+    pub fn save(&self, vault: &Vault) -> Result<(), Box<dyn Error>> {
+        vault.write(MAILBOX_FILE, &serde_json::to_vec(&self.clients)?)
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &String> {
+        self.clients.keys()
+    }
+
+    pub fn is_client(&self, peer_id: &str) -> bool {
+        self.clients.contains_key(peer_id)
+    }
+
+    // Returns false if we don't serve as a mailbox.
+    pub fn register(&mut self, peer_id: &str) -> bool {
+        if !self.settings.enabled {
+            return false;
+        }
+        self.clients.entry(peer_id.to_string()).or_default();
+        true
+    }
+
+    pub fn unregister(&mut self, peer_id: &str) {
+        self.clients.remove(peer_id);
+    }
+
+    // Drops all clients and held frames, returning who was registered.
+    pub fn clear(&mut self) -> Vec<String> {
+        self.clients.drain().map(|(peer, _)| peer).collect()
+    }
+
+    pub fn hold(&mut self, client: &str, from: &str, frame: String) {
+        let max = self.settings.max_messages_per_peer;
+        let Some(held) = self.clients.get_mut(client) else { return };
+        held.push(HeldFrame {
+            from: from.to_string(),
+            frame,
+            received_at: now(),
+        });
+        if held.len() > max {
+            // Oldest go first
+            held.drain(..held.len() - max);
+        }
+    }
+
+    // Everything still within retention for `client`, oldest first.
+    pub fn take(&mut self, client: &str) -> Vec<HeldFrame> {
+        let cutoff = self.cutoff();
+        self.clients.get_mut(client)
+            .map(std::mem::take)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|held| held.received_at >= cutoff)
+            .collect()
+    }
+
+    // Drops frames older than the retention period. Returns true if anything was dropped.
+    pub fn prune(&mut self) -> bool {
+        let cutoff = self.cutoff();
+        let mut changed = false;
+        for held in self.clients.values_mut() {
+            let before = held.len();
+            held.retain(|h| h.received_at >= cutoff);
+            changed |= held.len() != before;
+        }
+        changed
+    }
+
+    fn cutoff(&self) -> u64 {
+        now().saturating_sub(self.settings.retention_hours.saturating_mul(3600))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
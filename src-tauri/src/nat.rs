@@ -0,0 +1,45 @@
+use libp2p::{autonat, multiaddr::Protocol, Multiaddr, PeerId};
+
+// How reachable we are from outside, as shown in the UI. AutoNAT asks other
+// peers to dial us back to find out; when we're private, a reservation on the
+// configured relay gives us a `/p2p-circuit` address, and DCUtR upgrades
+// relayed connections to direct ones by hole punching.
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkStatus {
+    // "public", "private" or "unknown"
+    pub reachability: String,
+    pub public_address: Option<String>,
+    pub relay_reserved: bool,
+}
+
+impl NetworkStatus {
+    pub fn new() -> Self {
+        NetworkStatus {
+            reachability: "unknown".to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_nat_status(&mut self, status: &autonat::NatStatus) {
+        let (reachability, public_address) = match status {
+            autonat::NatStatus::Public(addr) => ("public", Some(addr.to_string())),
+            autonat::NatStatus::Private => ("private", None),
+            autonat::NatStatus::Unknown => ("unknown", None),
+        };
+        self.reachability = reachability.to_string();
+        self.public_address = public_address;
+    }
+}
+
+// The relay is configured as a full multiaddr ending in `/p2p/<relay id>`.
+// Listening on it with `/p2p-circuit` appended makes the relay client
+// reserve a slot there.
+pub fn relay_listen_address(relay: &str) -> Result<(PeerId, Multiaddr), String> {
+    let addr: Multiaddr = relay.parse().map_err(|e| format!("Invalid relay address {}: {}", relay, e))?;
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Ok((peer_id, addr.with(Protocol::P2pCircuit))),
+        _ => Err(format!("Relay address {} has no /p2p/ suffix", relay)),
+    }
+}
@@ -0,0 +1,2434 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::num::NonZeroU8;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use libp2p::{
+    autonat, dcutr, gossipsub, identify, kad, mdns, noise, relay, request_response, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux,
+    core::transport::ListenerId,
+    swarm::dial_opts::{DialOpts, PeerCondition},
+    swarm::ConnectionId,
+    futures::StreamExt, identity,
+};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use x25519_dalek::StaticSecret;
+use crate::backup::IdentityBackup;
+use crate::devices::{self, DeviceCert, Devices, DevicesInfo, DevicesUpdate};
+use crate::discovery;
+use crate::files::{self, ChunkRequest, ChunkResponse, Direction, Transfer, TransferInfo, TransferStatus};
+use crate::groups::{self, Group, GroupEnvelope, GroupInfo, GroupUpdate};
+use crate::keystore::KeyStore;
+use crate::mailbox::{Mailbox, MailboxMessage, MailboxSettings};
+use crate::nat::{self, NetworkStatus};
+use crate::protocol::{decrypt_message, DeliveryStatus, encrypt_message, open_from_peer, seal_for_peer, signed_bundle, verify_bundle, InboxFrame, Opened, P2PMessage};
+use crate::ratchet::{self, Session};
+use crate::safety::{self, SafetyNumber};
+use crate::settings::{self, GossipsubSettings, Settings, Transport};
+use crate::store::{self, now_millis, Contact, Store, StoredMessage};
+use crate::spam::Validator;
+use crate::sync::{self, BloomFilter, Conversation, Summary, SyncRequest, SyncResponse, SyncedMessage};
+use crate::vault::Vault;
+
+// The P2P node on its own, without any UI. `PhantomNode::new` loads the keys
+// and state from a data dir, `run` drives the swarm. Everything else talks to
+// it through the `NodeHandle` and listens to the `NodeEvent` stream; the
+// Tauri app is just one such consumer.
+
+// What the node reports while running.
+#[derive(Clone, Debug)]
+pub enum NodeEvent {
+    LocalPeerId(String),
+    ListenAddress(String),
+    PeerDiscovered(String),
+    PeerExpired(String),
+    // A chat message: `channel` is "phantom-global", a group id or the sender's PeerId
+    Message { sender: String, content: String, channel: String },
+    HandshakeComplete(String),
+    HandshakeRejected(String),
+    // A session was set up with a different X25519 identity key than the one
+    // we knew for the peer; `verified` if the user had verified the old one
+    KeyChanged { peer_id: String, verified: bool },
+    // A handshake never completed and `count` queued messages were dropped
+    HandshakeTimeout { peer_id: String, count: usize },
+    PeerTyping { peer_id: String, is_typing: bool },
+    GroupUpdated(String),
+    // Our mailbox peer answered a registration
+    MailboxStatus { peer_id: String, accepted: bool },
+    PeerLookup { peer_id: String, found: bool },
+    MessageEdited { peer_id: String, message_id: String, content: String },
+    MessageDeleted { peer_id: String, message_id: String },
+    // `peer_id` toggled `emoji` on the message
+    MessageReaction { peer_id: String, message_id: String, emoji: String },
+    // A receipt for a message we sent to `peer_id`
+    MessageStatus { peer_id: String, message_id: String, status: DeliveryStatus },
+    NetworkStatus(NetworkStatus),
+    // A file transfer was offered, made progress or changed status
+    Transfer(TransferInfo),
+    // Our linked devices or a contact's changed
+    DevicesUpdated,
+    // `count` messages of the history arrived from `peer_id`, another of
+    // our devices or a contact filling a gap
+    HistorySynced { peer_id: String, count: usize },
+}
+
+// What the handles ask the running node to do. Commands with a `reply` are
+// answered once the swarm has acted on them.
+enum NodeCommand {
+    Publish { topic: String, data: Vec<u8>, reply: oneshot::Sender<Result<(), String>> },
+    Subscribe { topic: String },
+    Unsubscribe { topic: String },
+    // Answered when the connection is established or has failed
+    Dial { addr: libp2p::Multiaddr, reply: oneshot::Sender<Result<(), String>> },
+    Disconnect { peer_id: libp2p::PeerId, reply: oneshot::Sender<Result<(), String>> },
+    // Looks up the peer's address record in the DHT and dials it
+    FindPeer { peer_id: libp2p::PeerId },
+    // Settings changed, reapply the bootstrap nodes and relay
+    ReloadSettings,
+    // A transfer was accepted or resumed, start asking for its chunks
+    PollTransfers,
+    PeerScores { reply: oneshot::Sender<Vec<PeerScore>> },
+    // Answered once the swarm is gone
+    Shutdown { reply: oneshot::Sender<()> },
+}
+
+// The node's identity, kept in the key store so it can be sealed under the
+// passphrase. The app's is `identity.key`, the relay names its own.
+pub fn load_or_generate_keypair(keys: &KeyStore, file_name: &str) -> Result<identity::Keypair, Box<dyn Error>> {
+    if let Some(bytes) = keys.read(file_name)? {
+        match identity::Keypair::from_protobuf_encoding(&bytes) {
+            Ok(keypair) => {
+                println!("Loaded existing identity from {:?}", keys.dir().join(file_name));
+                return Ok(keypair);
+            },
+            Err(e) => {
+                eprintln!("Failed to load identity key: {}. Generating new one.", e);
+            }
+        }
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    keys.write(file_name, &keypair.to_protobuf_encoding()?)?;
+    println!("Generated and saved new identity to {:?}", keys.dir().join(file_name));
+
+    Ok(keypair)
+}
+
+// Used for the long-term X25519 identity key (`ecdh.key`) and the signed
+// prekey (`prekey.key`).
+fn load_or_generate_ecdh_key(keys: &KeyStore, file_name: &str) -> Result<StaticSecret, Box<dyn Error>> {
+    if let Some(bytes) = keys.read(file_name)? {
+        if let Ok(arr) = <[u8; 32]>::try_from(bytes.as_slice()) {
+            return Ok(StaticSecret::from(arr));
+        }
+    }
+
+    let secret = StaticSecret::random_from_rng(OsRng);
+    keys.write(file_name, &secret.to_bytes())?;
+    println!("Generated and saved new X25519 key {} in {:?}", file_name, keys.dir());
+    Ok(secret)
+}
+
+// Outgoing inbox messages waiting for a session with the peer. They are sent
+// as soon as the handshake completes, or reported via `HandshakeTimeout`.
+struct PendingHandshake {
+    started: Instant,
+    messages: Vec<P2PMessage>,
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// History is handed to a new device in pages of at most this many messages,
+// and at most this much JSON so a page fits in one gossipsub message
+const HISTORY_PAGE: u32 = 200;
+const HISTORY_PAGE_BYTES: usize = 24 * 1024;
+
+// Progress of a download is saved every this many chunks
+const SAVE_EVERY_CHUNKS: u64 = 16;
+
+// A gossipsub peer as the router rates it, for the UI.
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerScore {
+    pub peer_id: String,
+    // `None` with scoring turned off
+    pub score: Option<f64>,
+    pub topics: Vec<String>,
+}
+
+// Shared by the app and the headless relay, which has to route the same topics.
+pub fn build_gossipsub(key: &identity::Keypair, settings: &GossipsubSettings) -> Result<gossipsub::Behaviour, Box<dyn Error + Send + Sync>> {
+    // Gossipsub configuration. A message is its author, sequence number and
+    // data, so the same text from two peers or sent twice isn't a duplicate.
+    // Strict validation guarantees the first two; the source is length
+    // prefixed so the fields can't run into each other.
+    let message_id_fn = |message: &gossipsub::Message| {
+        let source = message.source.map(|peer| peer.to_bytes()).unwrap_or_default();
+        let hash = Sha256::new()
+            .chain_update((source.len() as u64).to_be_bytes())
+            .chain_update(&source)
+            .chain_update(message.sequence_number.unwrap_or_default().to_be_bytes())
+            .chain_update(&message.data)
+            .finalize();
+        gossipsub::MessageId::from(hash.to_vec())
+    };
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+        .heartbeat_interval(Duration::from_secs(settings.heartbeat_secs))
+        .mesh_n(settings.mesh_n)
+        .mesh_n_low(settings.mesh_n_low)
+        .mesh_n_high(settings.mesh_n_high)
+        .mesh_outbound_min(settings.mesh_outbound_min)
+        .max_transmit_size(settings.max_transmit_size)
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        // Nothing is forwarded before it passed `spam`'s checks
+        .validate_messages()
+        .message_id_fn(message_id_fn)
+        .build()
+        .map_err(std::io::Error::other)?;
+
+    let mut gossipsub = gossipsub::Behaviour::new(
+        gossipsub::MessageAuthenticity::Signed(key.clone()),
+        gossipsub_config,
+    )?;
+    if settings.scoring {
+        gossipsub.with_peer_score(settings.score_params(), settings.thresholds.params())?;
+    }
+    Ok(gossipsub)
+}
+
+// Define the Network Behaviour
+#[derive(NetworkBehaviour)]
+struct MyBehaviour {
+    gossipsub: gossipsub::Behaviour,
+    mdns: mdns::tokio::Behaviour,
+    kad: kad::Behaviour<kad::store::MemoryStore>,
+    identify: identify::Behaviour,
+    relay_client: relay::client::Behaviour,
+    autonat: autonat::Behaviour,
+    dcutr: dcutr::Behaviour,
+    file: files::FileBehaviour,
+    sync: sync::SyncBehaviour,
+}
+
+// Keys and state shared between the running node and its handles.
+struct NodeState {
+    data_dir: PathBuf,
+    identity: identity::Keypair,
+    local_peer_id: String,
+    keys: Mutex<KeyStore>,
+    vault: Vault,
+    sessions: Mutex<HashMap<String, Session>>,
+    ecdh_key: StaticSecret,
+    prekey: StaticSecret,
+    listen_addresses: Mutex<Vec<String>>,
+    settings: Mutex<Settings>,
+    // What the running swarm was built with, `None` until the P2P loop starts
+    running_gossipsub: Mutex<Option<GossipsubSettings>>,
+    network_status: Mutex<NetworkStatus>,
+    groups: Mutex<HashMap<String, Group>>,
+    outbox: Mutex<HashMap<String, PendingHandshake>>,
+    mailbox: Mutex<Mailbox>,
+    transfers: Mutex<HashMap<String, Transfer>>,
+    // Where received files go
+    downloads_dir: PathBuf,
+    store: Store,
+    devices: Mutex<Devices>,
+}
+
+impl NodeState {
+    fn load(keys: KeyStore) -> Result<Self, Box<dyn Error>> {
+        let data_dir = keys.dir().to_path_buf();
+        let vault = Vault::open(&keys)?;
+        let sessions = ratchet::load_sessions(&vault).unwrap_or_else(|e| {
+            eprintln!("Failed to load sessions: {}", e);
+            HashMap::new()
+        });
+        let settings = settings::load_settings(&data_dir).unwrap_or_else(|e| {
+            eprintln!("Failed to load settings: {}", e);
+            Settings::default()
+        });
+        let groups = groups::load_groups(&vault).unwrap_or_else(|e| {
+            eprintln!("Failed to load groups: {}", e);
+            HashMap::new()
+        });
+        // Authors used to be kept next to the history, the store has them
+        let _ = fs::remove_file(data_dir.join("message-authors.bin"));
+        let mailbox = Mailbox::load(&vault).unwrap_or_else(|e| {
+            eprintln!("Failed to load mailbox: {}", e);
+            Mailbox::default()
+        });
+        let transfers = files::load_transfers(&vault).unwrap_or_else(|e| {
+            eprintln!("Failed to load file transfers: {}", e);
+            HashMap::new()
+        });
+        let devices = Devices::load(&vault).unwrap_or_else(|e| {
+            eprintln!("Failed to load linked devices: {}", e);
+            Devices::default()
+        });
+        let downloads_dir = data_dir.join("downloads");
+        fs::create_dir_all(&downloads_dir)?;
+        let store = Store::open(&data_dir.join(store::STORE_FILE), &vault.derive_key(store::STORE_FILE))?;
+
+        // Load identity and ECDH keys
+        let identity = load_or_generate_keypair(&keys, "identity.key")?;
+        let ecdh_key = load_or_generate_ecdh_key(&keys, "ecdh.key")?;
+        let prekey = load_or_generate_ecdh_key(&keys, "prekey.key")?;
+
+        Ok(NodeState {
+            local_peer_id: identity.public().to_peer_id().to_string(),
+            data_dir,
+            identity,
+            keys: Mutex::new(keys),
+            vault,
+            sessions: Mutex::new(sessions),
+            ecdh_key,
+            prekey,
+            listen_addresses: Mutex::new(Vec::new()),
+            settings: Mutex::new(settings),
+            running_gossipsub: Mutex::new(None),
+            network_status: Mutex::new(NetworkStatus::new()),
+            groups: Mutex::new(groups),
+            outbox: Mutex::new(HashMap::new()),
+            mailbox: Mutex::new(mailbox),
+            transfers: Mutex::new(transfers),
+            downloads_dir,
+            store,
+            devices: Mutex::new(devices),
+        })
+    }
+
+    // Who wrote a message is the sender it was first stored with, so a peer
+    // can't take over one of our messages by reusing its id.
+    fn is_author(&self, message_id: &str, peer_id: &str) -> bool {
+        self.store.sender_of(message_id).ok().flatten().as_deref() == Some(peer_id)
+    }
+
+    fn is_own_message(&self, message_id: &str) -> bool {
+        self.is_author(message_id, &self.local_peer_id)
+    }
+
+    // Whether `message_id` belongs to `channel`, so a peer only gets to touch
+    // messages of the conversation it's part of.
+    fn is_in_channel(&self, message_id: &str, channel: &str) -> bool {
+        self.store.channel_of(message_id).ok().flatten().as_deref() == Some(channel)
+    }
+
+    // The X25519 identity key of our session with `peer_id`.
+    fn remote_identity(&self, peer_id: &str) -> Option<[u8; 32]> {
+        let sessions = self.sessions.lock().unwrap();
+        let ik = hex::decode(&sessions.get(peer_id)?.remote_identity).ok()?;
+        ik.try_into().ok()
+    }
+
+    // Remembers the key of the session just set up with `peer_id` and
+    // returns the event to report if it isn't the one we knew.
+    fn note_peer_key(&self, peer_id: &str) -> Option<NodeEvent> {
+        let ik = self.remote_identity(peer_id)?;
+        match self.store.record_peer_key(peer_id, &hex::encode(ik)) {
+            Ok(Some(verified)) => {
+                eprintln!("Identity key of {} changed{}", peer_id, if verified { " (was verified)" } else { "" });
+                Some(NodeEvent::KeyChanged { peer_id: peer_id.to_string(), verified })
+            },
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Failed to record key of {}: {}", peer_id, e);
+                None
+            }
+        }
+    }
+
+    fn local_identity(&self) -> [u8; 32] {
+        x25519_dalek::PublicKey::from(&self.ecdh_key).to_bytes()
+    }
+
+    // Whether `channel` is a 1-on-1 chat, i.e. neither the global channel nor one of our groups.
+    fn is_direct(&self, channel: &str) -> bool {
+        channel != "global-gossip" && channel != "phantom-global" && !self.groups.lock().unwrap().contains_key(channel)
+    }
+
+    // Runs `f` on transfer `id`. Returns the updated transfer for the UI.
+    fn update_transfer<F>(&self, id: &str, f: F) -> Result<TransferInfo, String>
+    where
+        F: FnOnce(&mut Transfer) -> Result<(), String>,
+    {
+        self.with_transfer(id, f).map(|((), info)| info)
+    }
+
+    // Runs `f` on transfer `id` and returns its result along with the updated
+    // transfer. Saves when the status changed and every `SAVE_EVERY_CHUNKS`
+    // chunks in between, a restart asks again for the few chunks after that.
+    fn with_transfer<T, F>(&self, id: &str, f: F) -> Result<(T, TransferInfo), String>
+    where
+        F: FnOnce(&mut Transfer) -> Result<T, String>,
+    {
+        let mut transfers = self.transfers.lock().map_err(|e| e.to_string())?;
+        let transfer = transfers.get_mut(id).ok_or_else(|| format!("Unknown transfer {}", id))?;
+        let (status, chunks_done) = (transfer.status, transfer.chunks_done);
+        let result = f(transfer)?;
+        let info = transfer.info();
+        let changed = transfer.status != status
+            || transfer.chunks_done / SAVE_EVERY_CHUNKS != chunks_done / SAVE_EVERY_CHUNKS;
+        if changed {
+            if let Err(e) = files::save_transfers(&self.vault, &transfers) {
+                eprintln!("Failed to save file transfers: {}", e);
+            }
+        }
+        Ok((result, info))
+    }
+}
+
+pub struct PhantomNode {
+    state: Arc<NodeState>,
+    commands: mpsc::Receiver<NodeCommand>,
+    events: mpsc::UnboundedSender<NodeEvent>,
+}
+
+// Cheap to clone; the node keeps running as long as `PhantomNode::run` does.
+#[derive(Clone)]
+pub struct NodeHandle {
+    commands: mpsc::Sender<NodeCommand>,
+    events: mpsc::UnboundedSender<NodeEvent>,
+    state: Arc<NodeState>,
+}
+
+impl PhantomNode {
+    // Loads (or creates) the keys and state kept in `data_dir`, unlocking the
+    // key files with `passphrase` if they are sealed. The node doesn't touch
+    // the network until `run` is called.
+    pub fn new(data_dir: PathBuf, passphrase: Option<&str>) -> Result<(PhantomNode, NodeHandle, mpsc::UnboundedReceiver<NodeEvent>), Box<dyn Error>> {
+        Self::with_keys(KeyStore::open(&data_dir, passphrase)?)
+    }
+
+    // Same as `new`, over key files that are already unlocked.
+    pub fn with_keys(keys: KeyStore) -> Result<(PhantomNode, NodeHandle, mpsc::UnboundedReceiver<NodeEvent>), Box<dyn Error>> {
+        let state = Arc::new(NodeState::load(keys)?);
+        let (commands_tx, commands) = mpsc::channel(32);
+        let (events, events_rx) = mpsc::unbounded_channel();
+
+        let handle = NodeHandle { commands: commands_tx, events: events.clone(), state: state.clone() };
+        Ok((PhantomNode { state, commands, events }, handle, events_rx))
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        // Answered only now, with the swarm and everything else the loop held dropped
+        if let Some(reply) = run_p2p_node(self.state, self.commands, self.events).await? {
+            let _ = reply.send(());
+        }
+        Ok(())
+    }
+}
+
+impl NodeHandle {
+    pub fn local_peer_id(&self) -> String {
+        self.state.local_peer_id.clone()
+    }
+
+    pub fn listen_addresses(&self) -> Vec<String> {
+        let mut addrs: Vec<libp2p::Multiaddr> = self.state.listen_addresses.lock().unwrap()
+            .iter()
+            .filter_map(|a| a.parse().ok())
+            .collect();
+        // Preferred transport first, so invite codes carry that one
+        self.state.settings.lock().unwrap().sort_addresses(&mut addrs);
+        addrs.iter().map(|a| a.to_string()).collect()
+    }
+
+    // `message_id` is only used for 1-on-1 messages, to match up the receipts.
+    pub async fn send_message(&self, channel: String, message: String, message_id: Option<String>) -> Result<(), String> {
+        let group_opt = {
+            let groups = self.state.groups.lock().map_err(|e| e.to_string())?;
+            groups.get(&channel).cloned()
+        };
+
+        // Into the history first, a failed send takes it out again
+        let stored = StoredMessage::from_payload(&self.state.local_peer_id, store_channel(&channel), &message, message_id.as_deref(), "sent");
+        self.state.store.insert_message(&stored).map_err(|e| e.to_string())?;
+
+        let result = if let Some(group) = group_opt {
+            let envelope = GroupEnvelope { epoch: group.epoch, content: encrypt_message(&message, &group.key)? };
+            let json = serde_json::to_string(&envelope).map_err(|e| e.to_string())?;
+            self.publish(group.topic_name(), json).await
+        } else if channel != "global-gossip" && channel != "phantom-global" {
+            // Assume 1-on-1. Without a session yet, this queues the message until the handshake completes.
+            self.send_to_account(&channel, P2PMessage::Message { id: message_id, content: message }).await
+        } else {
+            self.publish("phantom-global".to_string(), message).await
+        };
+
+        if result.is_err() {
+            let _ = self.state.store.delete_message(&stored.uuid);
+        }
+        result
+    }
+
+    pub async fn send_typing_indicator(&self, channel: String, is_typing: bool) -> Result<(), String> {
+        if channel == "global-gossip" || channel == "phantom-global" || self.state.groups.lock().unwrap().contains_key(&channel) {
+            // Typing indicators only supported for 1-on-1 for now to avoid spam
+            return Ok(());
+        }
+
+        // Sealed like any other inbox message, so relays can't tell it's a typing indicator.
+        // If there is no session yet, we don't send typing indicators (handshake needed first)
+        self.send_sealed(&channel, &P2PMessage::Typing { is_typing }).await?;
+        Ok(())
+    }
+
+    // Edits, deletes and reactions update the history; in 1-on-1 chats they
+    // are also sent to the peer.
+    pub async fn edit_message(&self, channel: String, message_id: String, content: String) -> Result<(), String> {
+        if !self.state.is_own_message(&message_id) {
+            return Err("Only the author can edit a message".to_string());
+        }
+        self.state.store.edit_message(&message_id, &content).map_err(|e| e.to_string())?;
+        if !self.state.is_direct(&channel) {
+            return Ok(());
+        }
+        self.send_to_account(&channel, P2PMessage::Edit { target: message_id, content }).await
+    }
+
+    pub async fn delete_message(&self, channel: String, message_id: String) -> Result<(), String> {
+        if !self.state.is_own_message(&message_id) {
+            return Err("Only the author can delete a message".to_string());
+        }
+        self.state.store.delete_message(&message_id).map_err(|e| e.to_string())?;
+        if !self.state.is_direct(&channel) {
+            return Ok(());
+        }
+        self.send_to_account(&channel, P2PMessage::Delete { target: message_id }).await
+    }
+
+    pub async fn react_to_message(&self, channel: String, message_id: String, emoji: String) -> Result<(), String> {
+        self.state.store.toggle_reaction(&message_id, &emoji, &self.state.local_peer_id).map_err(|e| e.to_string())?;
+        if !self.state.is_direct(&channel) {
+            return Ok(());
+        }
+        self.send_to_account(&channel, P2PMessage::React { target: message_id, emoji }).await
+    }
+
+    // Up to `limit` messages of `channel` sent before `before` (ms since the epoch), oldest first.
+    pub fn history(&self, channel: &str, before: Option<i64>, limit: u32) -> Result<Vec<StoredMessage>, String> {
+        self.state.store.history(store_channel(channel), before, limit).map_err(|e| e.to_string())
+    }
+
+    pub fn search(&self, query: &str, channel: Option<&str>, limit: u32) -> Result<Vec<StoredMessage>, String> {
+        self.state.store.search(query, channel.map(store_channel), limit).map_err(|e| e.to_string())
+    }
+
+    pub fn contacts(&self) -> Result<Vec<Contact>, String> {
+        self.state.store.contacts().map_err(|e| e.to_string())
+    }
+
+    pub fn save_contact(&self, peer_id: String, name: String) -> Result<(), String> {
+        peer_id.parse::<libp2p::PeerId>().map_err(|e| format!("Invalid PeerId: {}", e))?;
+        self.state.store.save_contact(&peer_id, &name).map_err(|e| e.to_string())
+    }
+
+    pub fn delete_contact(&self, peer_id: String) -> Result<(), String> {
+        self.state.store.delete_contact(&peer_id).map_err(|e| e.to_string())
+    }
+
+    // Takes over the history the frontend kept in its own database before
+    // the node had a store.
+    pub fn import_legacy_history(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if self.state.store.import_legacy(path)? {
+            println!("Imported message history from {:?}", path);
+        }
+        Ok(())
+    }
+
+    // Tells `peer_id` we have seen their message. Without a session there is
+    // nothing to send it on, and it is dropped.
+    pub async fn mark_read(&self, peer_id: String, message_id: String) -> Result<(), String> {
+        self.send_sealed(&peer_id, &P2PMessage::Read { id: message_id }).await?;
+        Ok(())
+    }
+
+    pub async fn connect_peer(&self, addr: String) -> Result<(), String> {
+        let multiaddr = addr.parse::<libp2p::Multiaddr>().map_err(|e| format!("Invalid multiaddr: {}", e))?;
+        if Transport::of(&multiaddr).is_none() {
+            return Err("Unsupported transport, expected a /tcp or /quic-v1 address".to_string());
+        }
+        self.request(|reply| NodeCommand::Dial { addr: multiaddr, reply }).await
+    }
+
+    pub async fn disconnect_peer(&self, peer_id: String) -> Result<(), String> {
+        let peer_id = peer_id.parse::<libp2p::PeerId>().map_err(|e| format!("Invalid PeerId: {}", e))?;
+        self.request(|reply| NodeCommand::Disconnect { peer_id, reply }).await
+    }
+
+    // Looks up the addresses `peer_id` published in the DHT and dials them.
+    // The outcome is reported as `NodeEvent::PeerLookup`.
+    pub async fn find_peer(&self, peer_id: String) -> Result<(), String> {
+        let peer_id = peer_id.parse::<libp2p::PeerId>().map_err(|e| format!("Invalid PeerId: {}", e))?;
+        self.send(NodeCommand::FindPeer { peer_id }).await
+    }
+
+    pub fn list_groups(&self) -> Vec<GroupInfo> {
+        let groups = self.state.groups.lock().unwrap();
+        groups.values().map(Group::info).collect()
+    }
+
+    pub async fn create_group(&self, name: String) -> Result<GroupInfo, String> {
+        let group = Group::new(name, self.state.local_peer_id.clone());
+
+        {
+            let mut groups = self.state.groups.lock().map_err(|e| e.to_string())?;
+            groups.insert(group.id.clone(), group.clone());
+            groups::save_groups(&self.state.vault, &groups).map_err(|e| e.to_string())?;
+        }
+
+        self.send(NodeCommand::Subscribe { topic: group.topic_name() }).await?;
+        let _ = self.events.send(NodeEvent::GroupUpdated(group.id.clone()));
+        Ok(group.info())
+    }
+
+    pub async fn invite_to_group(&self, group_id: String, peer_id: String) -> Result<(), String> {
+        // A new member gets a fresh key, so they cannot read anything sent before they joined.
+        let group = self.update_group(&group_id, |group| {
+            if !group.is_member(&peer_id) {
+                group.members.push(peer_id.clone());
+            }
+            group.rotate_key();
+            Ok(())
+        })?;
+        let _ = self.events.send(NodeEvent::GroupUpdated(group.id.clone()));
+
+        self.distribute_group_key(&group).await
+    }
+
+    pub async fn remove_group_member(&self, group_id: String, peer_id: String) -> Result<(), String> {
+        let group = self.update_group(&group_id, |group| {
+            if !group.is_member(&peer_id) {
+                return Err(format!("{} is not a member of this group", peer_id));
+            }
+            if peer_id == self.state.local_peer_id {
+                return Err("Cannot remove yourself from the group".to_string());
+            }
+            group.members.retain(|m| *m != peer_id);
+            group.rotate_key();
+            Ok(())
+        })?;
+        let _ = self.events.send(NodeEvent::GroupUpdated(group.id.clone()));
+
+        // Best effort: the removed peer can no longer read the group either way,
+        // this only lets their client drop the channel.
+        let _ = self.send_or_queue(&peer_id, P2PMessage::Group(GroupUpdate::Removed { group_id: group.id.clone() })).await;
+
+        self.distribute_group_key(&group).await
+    }
+
+    pub async fn rotate_group_key(&self, group_id: String) -> Result<(), String> {
+        let group = self.update_group(&group_id, |group| {
+            group.rotate_key();
+            Ok(())
+        })?;
+        let _ = self.events.send(NodeEvent::GroupUpdated(group.id.clone()));
+
+        self.distribute_group_key(&group).await
+    }
+
+    // Offers the file at `path` to `peer_id`, who pulls it once they accept.
+    // Progress is reported as `NodeEvent::Transfer`.
+    pub async fn send_file(&self, peer_id: String, path: String) -> Result<TransferInfo, String> {
+        let path = PathBuf::from(path);
+        let metadata = fs::metadata(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        if !metadata.is_file() {
+            return Err(format!("{} is not a file", path.display()));
+        }
+        // Large files take a while to hash
+        let hash_path = path.clone();
+        let hash = tokio::task::spawn_blocking(move || files::hash_file(&hash_path))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+
+        let transfer = Transfer::outgoing(&peer_id, path, metadata.len(), hash);
+        let (id, offer) = (transfer.id.clone(), transfer.offer());
+        {
+            let mut transfers = self.state.transfers.lock().map_err(|e| e.to_string())?;
+            transfers.insert(id.clone(), transfer);
+            files::save_transfers(&self.state.vault, &transfers).map_err(|e| e.to_string())?;
+        }
+
+        let result = self.send_or_queue(&peer_id, P2PMessage::FileOffer(offer)).await;
+        let info = self.state.update_transfer(&id, |transfer| {
+            if result.is_err() {
+                transfer.status = TransferStatus::Failed;
+            }
+            Ok(())
+        })?;
+        let _ = self.events.send(NodeEvent::Transfer(info.clone()));
+        result.map(|_| info)
+    }
+
+    pub async fn accept_file(&self, id: String) -> Result<(), String> {
+        self.activate_transfer(&id, &[TransferStatus::Offered]).await
+    }
+
+    // Picks a paused or failed transfer up where it stopped.
+    pub async fn resume_file(&self, id: String) -> Result<(), String> {
+        self.activate_transfer(&id, &[TransferStatus::Paused, TransferStatus::Failed]).await
+    }
+
+    // Incoming transfers stop asking for chunks, outgoing ones tell the peer to wait.
+    pub fn pause_file(&self, id: String) -> Result<(), String> {
+        let info = self.state.update_transfer(&id, |transfer| {
+            if transfer.status != TransferStatus::Active {
+                return Err("Only active transfers can be paused".to_string());
+            }
+            transfer.status = TransferStatus::Paused;
+            Ok(())
+        })?;
+        let _ = self.events.send(NodeEvent::Transfer(info));
+        Ok(())
+    }
+
+    pub async fn cancel_file(&self, id: String) -> Result<(), String> {
+        let info = self.state.update_transfer(&id, Transfer::cancel)?;
+        let _ = self.events.send(NodeEvent::Transfer(info.clone()));
+        // Best effort, the peer's requests are refused either way
+        let _ = self.send_sealed(&info.peer_id, &P2PMessage::FileCancel { id }).await;
+        Ok(())
+    }
+
+    pub fn list_transfers(&self) -> Vec<TransferInfo> {
+        let transfers = self.state.transfers.lock().unwrap();
+        transfers.values().map(Transfer::info).collect()
+    }
+
+    async fn activate_transfer(&self, id: &str, from: &[TransferStatus]) -> Result<(), String> {
+        let info = self.state.update_transfer(id, |transfer| {
+            if !from.contains(&transfer.status) {
+                return Err("The transfer can't be started in its current state".to_string());
+            }
+            // Only the receiver pulls, a failed upload has to be offered again
+            if transfer.direction == Direction::Outgoing && transfer.status == TransferStatus::Failed {
+                return Err("Send the file again instead".to_string());
+            }
+            transfer.status = TransferStatus::Active;
+            Ok(())
+        })?;
+        let _ = self.events.send(NodeEvent::Transfer(info));
+        self.send(NodeCommand::PollTransfers).await
+    }
+
+    pub fn mailbox_settings(&self) -> MailboxSettings {
+        self.state.mailbox.lock().unwrap().settings.clone()
+    }
+
+    pub async fn set_mailbox_settings(&self, settings: MailboxSettings) -> Result<(), String> {
+        let (old_settings, dropped_clients) = {
+            let mut mailbox = self.state.mailbox.lock().map_err(|e| e.to_string())?;
+            let old_settings = std::mem::replace(&mut mailbox.settings, settings.clone());
+            let dropped_clients = if settings.enabled { Vec::new() } else { mailbox.clear() };
+            mailbox.prune();
+            mailbox.save_settings(&self.state.vault).map_err(|e| e.to_string())?;
+            mailbox.save(&self.state.vault).map_err(|e| e.to_string())?;
+            (old_settings, dropped_clients)
+        };
+
+        // Turning the role off forgets everyone we held messages for
+        for client in dropped_clients {
+            self.send(NodeCommand::Unsubscribe { topic: format!("inbox-{}", client) }).await?;
+        }
+
+        if old_settings.mailbox_peer != settings.mailbox_peer {
+            if let Some(old_peer) = &old_settings.mailbox_peer {
+                self.send_or_queue(old_peer, P2PMessage::Mailbox(MailboxMessage::Unregister)).await?;
+            }
+            if let Some(new_peer) = &settings.mailbox_peer {
+                self.send_or_queue(new_peer, P2PMessage::Mailbox(MailboxMessage::Register)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.state.settings.lock().unwrap().clone()
+    }
+
+    // Returns whether the node has to restart for the change to apply, which
+    // is the case for the gossipsub settings.
+    pub async fn set_settings(&self, settings: Settings) -> Result<bool, String> {
+        for node in &settings.bootstrap_nodes {
+            discovery::parse_bootstrap_node(node)?;
+        }
+        if let Some(relay) = &settings.relay {
+            nat::relay_listen_address(relay)?;
+        }
+        build_gossipsub(&self.state.identity, &settings.gossipsub)
+            .map_err(|e| format!("Invalid gossipsub settings: {}", e))?;
+        settings::save_settings(&self.state.data_dir, &settings).map_err(|e| e.to_string())?;
+        let restart_required = self.state.running_gossipsub.lock().map_err(|e| e.to_string())?
+            .as_ref()
+            .is_some_and(|running| *running != settings.gossipsub);
+        *self.state.settings.lock().map_err(|e| e.to_string())? = settings;
+
+        // Pick up changed bootstrap nodes and relay right away
+        self.send(NodeCommand::ReloadSettings).await?;
+        Ok(restart_required)
+    }
+
+    // Gossipsub peers, lowest score first.
+    pub async fn peer_scores(&self) -> Result<Vec<PeerScore>, String> {
+        let (reply, result) = oneshot::channel();
+        self.send(NodeCommand::PeerScores { reply }).await?;
+        result.await.map_err(|_| "P2P node is not running".to_string())
+    }
+
+    pub fn network_status(&self) -> NetworkStatus {
+        self.state.network_status.lock().unwrap().clone()
+    }
+
+    // Seals the key files under `passphrase`, or stores them in plain if it's
+    // empty. `current` has to match the passphrase set now, if any.
+    pub fn set_passphrase(&self, current: Option<String>, passphrase: Option<String>) -> Result<(), String> {
+        let mut keys = self.state.keys.lock().map_err(|e| e.to_string())?;
+        if keys.is_sealed() {
+            KeyStore::open(keys.dir(), current.as_deref()).map_err(|e| e.to_string())?;
+        }
+        let passphrase = passphrase.filter(|p| !p.is_empty());
+        *keys = keys.set_passphrase(passphrase.as_deref()).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    // Needs a session with `peer_id`, the number covers the keys it was set up with.
+    pub fn safety_number(&self, peer_id: &str) -> Result<SafetyNumber, String> {
+        let ik = self.state.remote_identity(peer_id).ok_or("No session with this peer yet")?;
+        let verified = self.state.store.is_verified(peer_id, &hex::encode(ik)).map_err(|e| e.to_string())?;
+        safety::safety_number((&self.state.local_peer_id, &self.state.local_identity()), (peer_id, &ik), verified)
+    }
+
+    // Marks `peer_id`'s current key as verified (or not), after the user compared safety numbers.
+    pub fn set_verified(&self, peer_id: &str, verified: bool) -> Result<(), String> {
+        let ik = self.state.remote_identity(peer_id).ok_or("No session with this peer yet")?;
+        self.state.store.set_verified(peer_id, &hex::encode(ik), verified).map_err(|e| e.to_string())
+    }
+
+    // Checks a QR payload from the peer's screen and marks them verified if
+    // it matches. Returns their PeerId.
+    pub fn verify_safety_qr(&self, payload: &str) -> Result<String, String> {
+        let peer_id = safety::qr_sender(payload)?;
+        let ik = self.state.remote_identity(&peer_id).ok_or("No session with this peer yet")?;
+        if !safety::qr_matches(payload, (&self.state.local_peer_id, &self.state.local_identity()), (&peer_id, &ik)) {
+            return Err("Safety numbers don't match".to_string());
+        }
+        self.state.store.set_verified(&peer_id, &hex::encode(ik), true).map_err(|e| e.to_string())?;
+        Ok(peer_id)
+    }
+
+    pub fn devices(&self) -> DevicesInfo {
+        self.state.devices.lock().unwrap().info(&self.state.local_peer_id)
+    }
+
+    // On a new device: the code to enter on the primary `account`. Its
+    // answer links us to it.
+    pub fn link_code(&self, account: String) -> Result<String, String> {
+        if account == self.state.local_peer_id {
+            return Err("Can't link a device to itself".to_string());
+        }
+        let code = devices::link_code(&self.state.identity, &account)?;
+        let mut devices = self.state.devices.lock().map_err(|e| e.to_string())?;
+        if !devices.own.is_empty() {
+            return Err("This device already has devices linked to it".to_string());
+        }
+        devices.pending_link = Some(account);
+        devices.save(&self.state.vault).map_err(|e| e.to_string())?;
+        Ok(code)
+    }
+
+    // On the primary: links the device that showed `code`.
+    pub async fn link_device(&self, code: String, name: String) -> Result<DeviceCert, String> {
+        let cert = {
+            let mut devices = self.state.devices.lock().map_err(|e| e.to_string())?;
+            if !devices.is_primary() {
+                return Err("Only the primary device can link devices".to_string());
+            }
+            let cert = DeviceCert::issue(&self.state.identity, &code, &name)?;
+            if cert.device == self.state.local_peer_id {
+                return Err("Can't link a device to itself".to_string());
+            }
+            devices.own.retain(|c| c.device != cert.device);
+            devices.own.push(cert.clone());
+            devices.save(&self.state.vault).map_err(|e| e.to_string())?;
+            cert
+        };
+        println!("Linked device {} ({})", cert.device, cert.name);
+        self.announce_devices(None).await?;
+        Ok(cert)
+    }
+
+    pub async fn unlink_device(&self, device: String) -> Result<(), String> {
+        {
+            let mut devices = self.state.devices.lock().map_err(|e| e.to_string())?;
+            if !devices.is_primary() {
+                return Err("Only the primary device can unlink devices".to_string());
+            }
+            devices.own.retain(|c| c.device != device);
+            devices.save(&self.state.vault).map_err(|e| e.to_string())?;
+        }
+        self.announce_devices(Some(&device)).await
+    }
+
+    pub fn identity_backup(&self) -> Result<IdentityBackup, String> {
+        IdentityBackup::new(&self.state.identity, &self.state.ecdh_key).map_err(|e| e.to_string())
+    }
+
+    pub fn keys(&self) -> Result<KeyStore, String> {
+        Ok(self.state.keys.lock().map_err(|e| e.to_string())?.clone())
+    }
+
+    // Writes the keys from `backup` over ours and drops the ratchet sessions
+    // made with the old ones. Only takes effect once a node is started on
+    // the returned key files, so call it after `shutdown`.
+    pub fn restore_identity(&self, backup: &IdentityBackup) -> Result<KeyStore, String> {
+        let keys = self.state.keys.lock().map_err(|e| e.to_string())?;
+        if let Err(e) = backup.restore(&keys) {
+            // Put back whatever was already overwritten
+            if let Err(e) = self.identity_backup()?.restore(&keys) {
+                eprintln!("Failed to put the old identity back: {}", e);
+            }
+            return Err(e.to_string());
+        }
+        let mut sessions = self.state.sessions.lock().map_err(|e| e.to_string())?;
+        sessions.clear();
+        ratchet::save_sessions(&self.state.vault, &sessions).map_err(|e| e.to_string())?;
+        Ok(keys.clone())
+    }
+
+    // Stops the node and waits until the P2P loop has exited, so the key
+    // files and the swarm's listeners are free once this returns.
+    pub async fn shutdown(&self) -> Result<(), String> {
+        let (reply, stopped) = oneshot::channel();
+        if self.send(NodeCommand::Shutdown { reply }).await.is_err() {
+            // The loop has already exited
+            return Ok(());
+        }
+        // Dropped without an answer if the loop failed instead, it's gone either way
+        let _ = stopped.await;
+        Ok(())
+    }
+
+    async fn send(&self, command: NodeCommand) -> Result<(), String> {
+        self.commands.send(command).await.map_err(|_| "P2P node is not running".to_string())
+    }
+
+    async fn request<F>(&self, command: F) -> Result<(), String>
+    where
+        F: FnOnce(oneshot::Sender<Result<(), String>>) -> NodeCommand,
+    {
+        let (reply, result) = oneshot::channel();
+        self.send(command(reply)).await?;
+        result.await.map_err(|_| "P2P node is not running".to_string())?
+    }
+
+    async fn publish(&self, topic: String, data: String) -> Result<(), String> {
+        self.request(|reply| NodeCommand::Publish { topic, data: data.into_bytes(), reply }).await
+    }
+
+    async fn start_handshake(&self, peer_id: &str) -> Result<(), String> {
+        let handshake = InboxFrame::Handshake(signed_bundle(&self.state.identity, &self.state.ecdh_key, &self.state.prekey)?);
+        let json = serde_json::to_string(&handshake).map_err(|e| e.to_string())?;
+
+        self.publish(format!("inbox-{}", peer_id), json).await
+    }
+
+    // Seals `msg` and hands it to the P2P loop for `inbox-<peer_id>`. Returns
+    // false if there is no session with the peer yet.
+    async fn send_sealed(&self, peer_id: &str, msg: &P2PMessage) -> Result<bool, String> {
+        let Some(frame) = seal_for_peer(&self.state.vault, &self.state.sessions, peer_id, msg)? else {
+            return Ok(false);
+        };
+        self.publish(format!("inbox-{}", peer_id), frame).await?;
+        Ok(true)
+    }
+
+    // Like `send_sealed`, but without a session the message is queued in the
+    // outbox and a handshake is started instead. Messages queued earlier for the
+    // same peer always go first, so ordering is preserved across the handshake.
+    async fn send_or_queue(&self, peer_id: &str, msg: P2PMessage) -> Result<(), String> {
+        // Either the sealed frame, or whether this is the first message queued for the peer
+        let sealed = {
+            let mut outbox = self.state.outbox.lock().map_err(|e| e.to_string())?;
+            let frame = if outbox.contains_key(peer_id) {
+                None
+            } else {
+                seal_for_peer(&self.state.vault, &self.state.sessions, peer_id, &msg)?
+            };
+
+            match frame {
+                Some(frame) => Ok(frame),
+                None => {
+                    let pending = outbox.entry(peer_id.to_string()).or_insert_with(|| PendingHandshake {
+                        started: Instant::now(),
+                        messages: Vec::new(),
+                    });
+                    pending.messages.push(msg);
+                    Err(pending.messages.len() == 1)
+                }
+            }
+        };
+
+        match sealed {
+            Ok(frame) => self.publish(format!("inbox-{}", peer_id), frame).await,
+            Err(true) => {
+                let result = self.start_handshake(peer_id).await;
+                if result.is_err() {
+                    // Nothing will complete the handshake, let the next send start over
+                    self.state.outbox.lock().map_err(|e| e.to_string())?.remove(peer_id);
+                }
+                result
+            },
+            Err(false) => Ok(()),
+        }
+    }
+
+    // Sends `msg` to `account` and every device linked to it. Only the
+    // account's own result counts, its devices get it best effort.
+    async fn send_to_account(&self, account: &str, msg: P2PMessage) -> Result<(), String> {
+        let recipients = self.state.devices.lock().map_err(|e| e.to_string())?.recipients(account);
+        for device in recipients.iter().skip(1) {
+            if let Err(e) = self.send_or_queue(device, msg.clone()).await {
+                eprintln!("Failed to send to device {} of {}: {}", device, account, e);
+            }
+        }
+        self.send_or_queue(account, msg).await
+    }
+
+    // Sends our device list to our devices, to `removed` so it learns it was
+    // unlinked, and to everyone we have a session with.
+    async fn announce_devices(&self, removed: Option<&str>) -> Result<(), String> {
+        let (certs, own) = {
+            let devices = self.state.devices.lock().map_err(|e| e.to_string())?;
+            (devices.own.clone(), devices.own_devices(&self.state.local_peer_id))
+        };
+        let msg = P2PMessage::Devices { certs };
+
+        for device in own.iter().map(String::as_str).chain(removed) {
+            self.send_or_queue(device, msg.clone()).await?;
+        }
+        let peers: Vec<String> = self.state.sessions.lock().map_err(|e| e.to_string())?.keys()
+            .filter(|p| !own.contains(p) && Some(p.as_str()) != removed)
+            .cloned()
+            .collect();
+        for peer in peers {
+            if let Err(e) = self.send_sealed(&peer, &msg).await {
+                eprintln!("Failed to send device list to {}: {}", peer, e);
+            }
+        }
+        Ok(())
+    }
+
+    // Hands the current key of `group` to every member except ourselves. Members
+    // we have no session with yet get it as soon as the handshake completes.
+    async fn distribute_group_key(&self, group: &Group) -> Result<(), String> {
+        for member in group.members.iter().filter(|m| **m != self.state.local_peer_id) {
+            self.send_or_queue(member, P2PMessage::Group(GroupUpdate::Key(group.clone()))).await?;
+        }
+        Ok(())
+    }
+
+    fn update_group<F>(&self, group_id: &str, f: F) -> Result<Group, String>
+    where
+        F: FnOnce(&mut Group) -> Result<(), String>,
+    {
+        let mut groups = self.state.groups.lock().map_err(|e| e.to_string())?;
+        let group = groups.get_mut(group_id).ok_or_else(|| format!("Unknown group {}", group_id))?;
+        if !group.is_member(&self.state.local_peer_id) {
+            return Err("Only members can change the group".to_string());
+        }
+        f(group)?;
+        let updated = group.clone();
+        groups::save_groups(&self.state.vault, &groups).map_err(|e| e.to_string())?;
+        Ok(updated)
+    }
+}
+
+// The UI calls the global channel "global-gossip", the history uses the topic name.
+fn store_channel(channel: &str) -> &str {
+    if channel == "global-gossip" { "phantom-global" } else { channel }
+}
+
+// Called by the P2P loop once a session with `peer_id` can send. Returns the
+// sealed frames of everything queued for the peer, oldest first.
+fn flush_outbox(state: &NodeState, peer_id: &str) -> Vec<String> {
+    let mut outbox = state.outbox.lock().unwrap();
+    let Some(pending) = outbox.remove(peer_id) else {
+        return Vec::new();
+    };
+
+    pending.messages.iter()
+        .filter_map(|msg| match seal_for_peer(&state.vault, &state.sessions, peer_id, msg) {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("Failed to seal queued message for {}: {}", peer_id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Returns the reply of the `Shutdown` command that stopped it, if one did.
+async fn run_p2p_node(
+    state: Arc<NodeState>,
+    mut rx: mpsc::Receiver<NodeCommand>,
+    events: mpsc::UnboundedSender<NodeEvent>,
+) -> Result<Option<oneshot::Sender<()>>, Box<dyn Error>> {
+    let state = &*state;
+    let local_key = &state.identity;
+    let NodeState { vault, sessions, ecdh_key, prekey, settings, network_status, groups, mailbox, .. } = state;
+    let gossipsub_settings = settings.lock().unwrap().gossipsub.clone();
+    *state.running_gossipsub.lock().unwrap() = Some(gossipsub_settings.clone());
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key.clone())
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay_client| {
+            let gossipsub = build_gossipsub(key, &gossipsub_settings).or_else(|e| {
+                eprintln!("Invalid gossipsub settings, using the defaults: {}", e);
+                build_gossipsub(key, &GossipsubSettings::default())
+            })?;
+
+            // MDNS configuration
+            let mdns = mdns::tokio::Behaviour::new(
+                mdns::Config::default(),
+                key.public().to_peer_id()
+            )?;
+
+            let peer_id = key.public().to_peer_id();
+            let kad = discovery::build_kad(peer_id);
+            let identify = discovery::build_identify(key);
+
+            // NAT traversal: find out if we're reachable, and if not, get a relayed
+            // address and try to upgrade relayed connections by hole punching
+            let autonat = autonat::Behaviour::new(peer_id, autonat::Config::default());
+            let dcutr = dcutr::Behaviour::new(peer_id);
+
+            let file = files::build_file_behaviour();
+            let sync = sync::build_sync_behaviour();
+
+            Ok(MyBehaviour { gossipsub, mdns, kad, identify, relay_client, autonat, dcutr, file, sync })
+        })?
+        .build();
+
+    // Subscribe to topics
+    let topic_global = gossipsub::IdentTopic::new("phantom-global");
+    let local_peer_id_str = swarm.local_peer_id().to_string();
+    let topic_inbox = gossipsub::IdentTopic::new(format!("inbox-{}", local_peer_id_str));
+    
+    swarm.behaviour_mut().gossipsub.subscribe(&topic_global)?;
+    swarm.behaviour_mut().gossipsub.subscribe(&topic_inbox)?;
+    for group in groups.lock().unwrap().values() {
+        swarm.behaviour_mut().gossipsub.subscribe(&group.topic())?;
+    }
+    // Inboxes of the contacts we serve as a mailbox for
+    for client in mailbox.lock().unwrap().clients() {
+        swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(format!("inbox-{}", client)))?;
+    }
+
+    // Listen on all interfaces
+    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+
+    let local_peer_id = state.local_peer_id.clone();
+    println!("Local Peer ID: {}", local_peer_id);
+    let _ = events.send(NodeEvent::LocalPeerId(local_peer_id.clone()));
+
+    let mut outbox_sweep = tokio::time::interval(Duration::from_secs(5));
+    let mut mdns_peers = HashSet::new();
+    // Joins the DHT via the bootstrap nodes and (re)publishes our address record once done
+    let mut dht_refresh = tokio::time::interval(Duration::from_secs(10 * 60));
+    // Address lookups in flight
+    let mut lookups: HashMap<kad::QueryId, libp2p::PeerId> = HashMap::new();
+    // The relay we hold (or are requesting) a reservation on
+    let mut relay_listener: Option<(String, ListenerId)> = None;
+    // Dials started by `NodeCommand::Dial`, answered once they succeed or fail
+    let mut pending_dials: HashMap<ConnectionId, oneshot::Sender<Result<(), String>>> = HashMap::new();
+    let mut mailbox_sweep = tokio::time::interval(Duration::from_secs(60));
+    // Retries chunk requests that failed or were answered with `Paused`
+    let mut transfer_retry = tokio::time::interval(Duration::from_secs(5));
+    let mut transfers = TransferRequests::default();
+    // Size, format and rate checks of incoming gossip
+    let mut validator = Validator::new(&gossipsub_settings);
+    // Downloads being checked against their hash off the event loop
+    let (finished_tx, mut finished_rx) = mpsc::unbounded_channel::<(String, Result<PathBuf, String>)>();
+
+    reserve_on_relay(&events, &mut swarm, state, &mut relay_listener);
+
+    // Event Loop
+    loop {
+        select! {
+            _ = outbox_sweep.tick() => {
+                // Give up on handshakes that never completed and tell the UI what was lost
+                let expired: Vec<(String, usize)> = {
+                    let mut outbox = state.outbox.lock().unwrap();
+                    let peers: Vec<String> = outbox.iter()
+                        .filter(|(_, pending)| pending.started.elapsed() >= HANDSHAKE_TIMEOUT)
+                        .map(|(peer, _)| peer.clone())
+                        .collect();
+                    peers.into_iter()
+                        .filter_map(|peer| outbox.remove(&peer).map(|pending| (peer, pending.messages.len())))
+                        .collect()
+                };
+
+                for (peer, count) in expired {
+                    eprintln!("Handshake with {} timed out, dropping {} queued message(s)", peer, count);
+                    let _ = events.send(NodeEvent::HandshakeTimeout { peer_id: peer, count });
+                }
+            }
+            _ = dht_refresh.tick() => {
+                // Also retries a relay reservation that was lost
+                reserve_on_relay(&events, &mut swarm, state, &mut relay_listener);
+                bootstrap_dht(&mut swarm, settings);
+            }
+            _ = transfer_retry.tick() => {
+                request_chunks(&mut swarm, state, &mut lookups, &mut transfers, &finished_tx);
+            }
+            Some((id, result)) = finished_rx.recv() => {
+                transfers.finishing.remove(&id);
+                let info = state.update_transfer(&id, |transfer| {
+                    match result {
+                        // Cancelled while we were checking it
+                        Ok(path) if transfer.status != TransferStatus::Active => {
+                            let _ = fs::remove_file(path);
+                        },
+                        Ok(path) => {
+                            println!("Received file {} from {}", path.display(), transfer.peer_id);
+                            transfer.path = path;
+                            transfer.status = TransferStatus::Completed;
+                        },
+                        Err(e) => {
+                            eprintln!("File transfer {} failed: {}", transfer.id, e);
+                            // The partial file is gone, a resume starts over
+                            transfer.chunks_done = 0;
+                            transfer.status = TransferStatus::Failed;
+                        },
+                    }
+                    Ok(())
+                });
+                if let Ok(info) = info {
+                    let _ = events.send(NodeEvent::Transfer(info));
+                }
+            }
+            _ = mailbox_sweep.tick() => {
+                let mut mailbox = mailbox.lock().unwrap();
+                if mailbox.prune() {
+                    if let Err(e) = mailbox.save(vault) {
+                        eprintln!("Failed to save mailbox: {}", e);
+                    }
+                }
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                     println!("Listening on {:?}", address);
+                     let addr_str = address.to_string();
+                     // Store in state
+                     state.listen_addresses.lock().unwrap().push(addr_str.clone());
+                     let _ = events.send(NodeEvent::ListenAddress(addr_str));
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                    let mut discovered: HashMap<libp2p::PeerId, Vec<libp2p::Multiaddr>> = HashMap::new();
+                    for (peer_id, multiaddr) in list {
+                        discovered.entry(peer_id).or_default().push(multiaddr);
+                    }
+
+                    for (peer_id, mut addrs) in discovered {
+                        println!("mDNS discovered a new peer: {peer_id}");
+                        // The peer becomes an explicit gossipsub peer once connected, as
+                        // gossipsub would otherwise dial all of its addresses at once
+                        settings.lock().unwrap().sort_addresses(&mut addrs);
+                        dial_in_order(&mut swarm, peer_id, addrs);
+                        mdns_peers.insert(peer_id);
+                        let _ = events.send(NodeEvent::PeerDiscovered(peer_id.to_string()));
+                    }
+                }
+                // Only peers that speak our DHT protocol go into the routing table
+                SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. }))
+                    if info.protocols.contains(&discovery::KAD_PROTOCOL) =>
+                {
+                    for addr in info.listen_addrs {
+                        swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { id, result, step, .. })) => match result {
+                    kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { num_remaining: 0, .. })) => {
+                        let addrs = discovery::publishable_addresses(swarm.external_addresses(), swarm.listeners());
+                        if addrs.is_empty() {
+                            continue;
+                        }
+                        match discovery::address_record(local_key, addrs) {
+                            Ok(record) => {
+                                if let Err(e) = swarm.behaviour_mut().kad.put_record(record, kad::Quorum::One) {
+                                    eprintln!("Failed to publish address record: {:?}", e);
+                                }
+                            },
+                            Err(e) => eprintln!("Failed to sign address record: {}", e),
+                        }
+                    }
+                    kad::QueryResult::Bootstrap(Err(e)) => eprintln!("DHT bootstrap failed: {:?}", e),
+                    kad::QueryResult::PutRecord(Err(e)) => eprintln!("Failed to publish address record: {:?}", e),
+                    kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(found))) => {
+                        let Some(target) = lookups.get(&id).copied() else { continue };
+                        match discovery::verify_address_record(&found.record) {
+                            Ok((peer_id, mut addrs)) if peer_id == target => {
+                                lookups.remove(&id);
+                                if let Some(mut query) = swarm.behaviour_mut().kad.query_mut(&id) {
+                                    query.finish();
+                                }
+                                println!("Found {} address(es) for {} in the DHT", addrs.len(), peer_id);
+                                settings.lock().unwrap().sort_addresses(&mut addrs);
+                                dial_in_order(&mut swarm, peer_id, addrs);
+                                let _ = events.send(NodeEvent::PeerLookup { peer_id: peer_id.to_string(), found: true });
+                            },
+                            Ok(_) => eprintln!("Ignoring address record under the wrong key for {}", target),
+                            Err(e) => eprintln!("Ignoring invalid address record for {}: {}", target, e),
+                        }
+                    }
+                    // Finished without a valid record
+                    kad::QueryResult::GetRecord(_) if step.last => {
+                        if let Some(target) = lookups.remove(&id) {
+                            println!("No addresses for {} in the DHT", target);
+                            let _ = events.send(NodeEvent::PeerLookup { peer_id: target.to_string(), found: false });
+                        }
+                    }
+                    _ => {}
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                    println!("NAT status changed from {:?} to {:?}", old, new);
+                    let status = {
+                        let mut status = network_status.lock().unwrap();
+                        status.set_nat_status(&new);
+                        status.clone()
+                    };
+                    let _ = events.send(NodeEvent::NetworkStatus(status));
+                    // Our publishable addresses may have changed
+                    bootstrap_dht(&mut swarm, settings);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal: false, .. })) => {
+                    println!("Relay reservation accepted by {}", relay_peer_id);
+                    set_relay_reserved(&events, network_status, true);
+                    // Publish the new /p2p-circuit address
+                    bootstrap_dht(&mut swarm, settings);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => match result {
+                    Ok(_) => println!("Hole punch to {} succeeded, connection is now direct", remote_peer_id),
+                    Err(e) => println!("Hole punch to {} failed: {}", remote_peer_id, e),
+                },
+                SwarmEvent::ListenerClosed { listener_id, reason, .. }
+                    if relay_listener.as_ref().map(|(_, id)| *id) == Some(listener_id) =>
+                {
+                    eprintln!("Relay reservation closed: {:?}", reason);
+                    relay_listener = None;
+                    set_relay_reserved(&events, network_status, false);
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                    println!("Connected to {} via {}", peer_id, endpoint.get_remote_address());
+                    if let Some(reply) = pending_dials.remove(&connection_id) {
+                        let _ = reply.send(Ok(()));
+                    }
+                    if mdns_peers.contains(&peer_id) {
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    }
+                    // A contact is back: fetch what we missed while apart
+                    if num_established.get() == 1 {
+                        request_sync(&mut swarm, state, &peer_id.to_string());
+                    }
+                }
+                SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+                    if let Some(reply) = pending_dials.remove(&connection_id) {
+                        let _ = reply.send(Err(format!("Dial failed: {}", error)));
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                    for (peer_id, _multiaddr) in list {
+                        println!("mDNS discover peer has expired: {peer_id}");
+                        mdns_peers.remove(&peer_id);
+                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        let _ = events.send(NodeEvent::PeerExpired(peer_id.to_string()));
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source: peer_id,
+                    message_id: gossip_id,
+                    message,
+                })) => {
+                    let acceptance = validate_message(state, &mut validator, &message);
+                    let accepted = matches!(acceptance, gossipsub::MessageAcceptance::Accept);
+                    if !accepted {
+                        eprintln!("{:?} message from {:?} on {} via {}", acceptance, message.source, message.topic, peer_id);
+                    }
+                    let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(&gossip_id, &peer_id, acceptance);
+                    if !accepted {
+                        continue;
+                    }
+
+                    let msg_content = String::from_utf8_lossy(&message.data);
+                    let topic_hash = message.topic;
+                    
+                    // The account a direct message's sender belongs to
+                    let conversation: String;
+                    let mut channel = "unknown";
+                    let mut final_content = msg_content.to_string();
+                    // The signed author, not whoever relayed the message to us
+                    let mut sender_id = message.source.unwrap_or(peer_id).to_string();
+                    let mut message_id = None;
+
+                    // The inbox of a contact we are the mailbox for: hold it if they're away.
+                    // If they're online elsewhere in the mesh but not connected to us, they
+                    // get it twice, and the ratchet drops the replay.
+                    let mailbox_client = mailbox.lock().unwrap().clients()
+                        .find(|c| gossipsub::IdentTopic::new(format!("inbox-{}", c)).hash() == topic_hash)
+                        .cloned();
+                    if let Some(client) = mailbox_client {
+                        let online = swarm.behaviour().gossipsub.all_peers()
+                            .any(|(p, topics)| p.to_string() == client && topics.contains(&&topic_hash));
+                        if !online && sender_id != client {
+                            let mut mailbox = mailbox.lock().unwrap();
+                            mailbox.hold(&client, &sender_id, msg_content.to_string());
+                            if let Err(e) = mailbox.save(vault) {
+                                eprintln!("Failed to save mailbox: {}", e);
+                            }
+                        }
+                        continue;
+                    }
+
+                    let group_opt = groups.lock().unwrap().values()
+                        .find(|g| g.topic().hash() == topic_hash)
+                        .cloned();
+
+                    if topic_hash == topic_global.hash() {
+                        channel = "phantom-global";
+                    } else if let Some(group) = &group_opt {
+                        channel = &group.id;
+                        final_content = match serde_json::from_str::<GroupEnvelope>(&msg_content) {
+                            Ok(envelope) if envelope.epoch == group.epoch => {
+                                match decrypt_message(&envelope.content, &group.key) {
+                                    Ok(decrypted) => decrypted,
+                                    Err(e) => format!("(Decryption Failed: {})", e),
+                                }
+                            },
+                            Ok(envelope) => format!("(Encrypted with group key #{}, we have #{})", envelope.epoch, group.epoch),
+                            Err(_) => format!("(Unknown Format: {})", msg_content),
+                        };
+                    } else if topic_hash == topic_inbox.hash() {
+                        // Private message or Handshake!
+                        let mut frame = match serde_json::from_str::<InboxFrame>(&msg_content) {
+                            Ok(frame) => frame,
+                            Err(_) => {
+                                eprintln!("Dropping inbox message in unknown format from {}", sender_id);
+                                continue;
+                            }
+                        };
+
+                        // Unwrap what our mailbox held for us and handle it as if it came from the original sender
+                        if let InboxFrame::Forwarded { from, frame: inner } = frame {
+                            let our_mailbox = mailbox.lock().unwrap().settings.mailbox_peer.clone();
+                            if our_mailbox.as_deref() != Some(sender_id.as_str()) {
+                                eprintln!("Dropping forwarded frame from {}, not our mailbox", sender_id);
+                                continue;
+                            }
+                            frame = match serde_json::from_str::<InboxFrame>(&inner) {
+                                Ok(InboxFrame::Forwarded { .. }) | Err(_) => {
+                                    eprintln!("Dropping malformed forwarded frame from {}", sender_id);
+                                    continue;
+                                },
+                                Ok(frame) => frame,
+                            };
+                            sender_id = from;
+                        }
+
+                        // For UI, channel is the sender's account, the sender ID unless it's a linked device
+                        conversation = state.devices.lock().unwrap().account_of(&sender_id);
+                        channel = &conversation;
+
+                        let payload = match frame {
+                            InboxFrame::Handshake(bundle) => {
+                                println!("Received Handshake from {}", sender_id);
+                                let (their_ik, their_spk) = match verify_bundle(&sender_id, &bundle) {
+                                    Ok(keys) => keys,
+                                    Err(e) => {
+                                        eprintln!("Rejected handshake from {}: {}", sender_id, e);
+                                        let _ = events.send(NodeEvent::HandshakeRejected(sender_id.clone()));
+                                        continue;
+                                    }
+                                };
+
+                                // A replayed handshake must not reset a working session
+                                let established = sessions.lock().unwrap().get(&sender_id)
+                                    .map(|s| s.can_send() && s.is_with(&their_ik, &their_spk))
+                                    .unwrap_or(false);
+                                if established {
+                                    println!("Ignoring handshake from {}, session already established", sender_id);
+                                    continue;
+                                }
+
+                                // Run X3DH as the initiator and answer with the session's first message
+                                let our_bundle = match signed_bundle(local_key, ecdh_key, prekey) {
+                                    Ok(our_bundle) => our_bundle,
+                                    Err(e) => {
+                                        eprintln!("Failed to sign bundle: {}", e);
+                                        continue;
+                                    }
+                                };
+                                let mut session = ratchet::initiate(ecdh_key, &their_ik, &their_spk, our_bundle);
+                                let init_plaintext = serde_json::to_vec(&P2PMessage::SessionInit).unwrap();
+                                let Ok(payload) = session.encrypt(&init_plaintext) else { continue };
+
+                                {
+                                    let mut sessions_guard = sessions.lock().unwrap();
+                                    if let Some(previous) = sessions_guard.get(&sender_id) {
+                                        session.replaces(previous);
+                                    }
+                                    sessions_guard.insert(sender_id.clone(), session);
+                                    if let Err(e) = ratchet::save_sessions(vault, &sessions_guard) {
+                                        eprintln!("Failed to save sessions: {}", e);
+                                    }
+                                }
+
+                                let reply_json = serde_json::to_string(&InboxFrame::Sealed(payload)).unwrap();
+                                let reply_topic = gossipsub::IdentTopic::new(format!("inbox-{}", sender_id));
+                                let _ = swarm.behaviour_mut().gossipsub.publish(reply_topic, reply_json.as_bytes().to_vec());
+
+                                println!("Session initiated with {}", sender_id);
+                                let _ = events.send(NodeEvent::HandshakeComplete(sender_id.clone()));
+                                if let Some(event) = state.note_peer_key(&sender_id) {
+                                    let _ = events.send(event);
+                                }
+                                send_devices(&mut swarm, state, &sender_id);
+
+                                for frame in flush_outbox(state, &sender_id) {
+                                    let topic = gossipsub::IdentTopic::new(format!("inbox-{}", sender_id));
+                                    let _ = swarm.behaviour_mut().gossipsub.publish(topic, frame.as_bytes().to_vec());
+                                }
+                                
+                                // Don't emit message to UI yet
+                                continue; 
+                            },
+                            InboxFrame::Sealed(payload) => payload,
+                            // Already unwrapped above, nested forwarding is dropped there
+                            InboxFrame::Forwarded { .. } => continue,
+                        };
+
+                        let p2p_msg = match open_from_peer(vault, sessions, (ecdh_key, prekey), &local_peer_id, &sender_id, &payload) {
+                            Ok(opened) => {
+                                if opened.new_session {
+                                    println!("Session established with {}", sender_id);
+                                    let _ = events.send(NodeEvent::HandshakeComplete(sender_id.clone()));
+                                    if let Some(event) = state.note_peer_key(&sender_id) {
+                                        let _ = events.send(event);
+                                    }
+                                    send_devices(&mut swarm, state, &sender_id);
+
+                                    if !opened.resend.is_empty() {
+                                        println!("Resending {} messages to {} on its session", opened.resend.len(), sender_id);
+                                    }
+                                    for frame in opened.resend.into_iter().chain(flush_outbox(state, &sender_id)) {
+                                        let topic = gossipsub::IdentTopic::new(format!("inbox-{}", sender_id));
+                                        let _ = swarm.behaviour_mut().gossipsub.publish(topic, frame.as_bytes().to_vec());
+                                    }
+                                }
+                                opened.message
+                            },
+                            Err(e) => {
+                                eprintln!("Dropping sealed message from {}: {}", sender_id, e);
+                                continue;
+                            }
+                        };
+
+                        match p2p_msg {
+                            P2PMessage::SessionInit => continue,
+                            P2PMessage::Message { id, content } => {
+                                message_id = id.clone();
+                                if let Some(id) = id {
+                                    if let Err(e) = publish_sealed(&mut swarm, state, &sender_id, &P2PMessage::Ack { id }) {
+                                        eprintln!("Failed to acknowledge message from {}: {}", sender_id, e);
+                                    }
+                                }
+                                final_content = content;
+                            },
+                            P2PMessage::Edit { target, content } => {
+                                if !state.is_author(&target, &sender_id) {
+                                    eprintln!("Rejected edit of {} from {}, not the author", target, sender_id);
+                                    continue;
+                                }
+                                if let Err(e) = state.store.edit_message(&target, &content) {
+                                    eprintln!("Failed to store edit of {}: {}", target, e);
+                                }
+                                let _ = events.send(NodeEvent::MessageEdited { peer_id: channel.to_string(), message_id: target, content });
+                                continue;
+                            },
+                            P2PMessage::Delete { target } => {
+                                if !state.is_author(&target, &sender_id) {
+                                    eprintln!("Rejected delete of {} from {}, not the author", target, sender_id);
+                                    continue;
+                                }
+                                if let Err(e) = state.store.delete_message(&target) {
+                                    eprintln!("Failed to delete {} from the history: {}", target, e);
+                                }
+                                let _ = events.send(NodeEvent::MessageDeleted { peer_id: channel.to_string(), message_id: target });
+                                continue;
+                            },
+                            P2PMessage::React { target, emoji } => {
+                                if !state.is_in_channel(&target, channel) {
+                                    eprintln!("Rejected reaction to {} from {}, not in their chat", target, sender_id);
+                                    continue;
+                                }
+                                if let Err(e) = state.store.toggle_reaction(&target, &emoji, &sender_id) {
+                                    eprintln!("Failed to store reaction to {}: {}", target, e);
+                                }
+                                let _ = events.send(NodeEvent::MessageReaction { peer_id: channel.to_string(), message_id: target, emoji });
+                                continue;
+                            },
+                            P2PMessage::Ack { id } => {
+                                if !state.is_own_message(&id) || !state.is_in_channel(&id, channel) {
+                                    eprintln!("Ignoring receipt for {} from {}, not our message to them", id, sender_id);
+                                    continue;
+                                }
+                                if let Err(e) = state.store.set_status(&id, "delivered") {
+                                    eprintln!("Failed to store receipt for {}: {}", id, e);
+                                }
+                                let _ = events.send(NodeEvent::MessageStatus { peer_id: sender_id.clone(), message_id: id, status: DeliveryStatus::Delivered });
+                                continue;
+                            },
+                            P2PMessage::Read { id } => {
+                                if !state.is_own_message(&id) || !state.is_in_channel(&id, channel) {
+                                    eprintln!("Ignoring receipt for {} from {}, not our message to them", id, sender_id);
+                                    continue;
+                                }
+                                if let Err(e) = state.store.set_status(&id, "read") {
+                                    eprintln!("Failed to store receipt for {}: {}", id, e);
+                                }
+                                let _ = events.send(NodeEvent::MessageStatus { peer_id: sender_id.clone(), message_id: id, status: DeliveryStatus::Read });
+                                continue;
+                            },
+                            P2PMessage::Group(update) => {
+                                handle_group_update(&events, state, &mut swarm, &sender_id, update);
+                                continue;
+                            },
+                            P2PMessage::Mailbox(request) => {
+                                let reply = handle_mailbox_message(&events, state, &mut swarm, &sender_id, request);
+                                if let Some(reply) = reply {
+                                    if let Err(e) = publish_sealed(&mut swarm, state, &sender_id, &P2PMessage::Mailbox(reply)) {
+                                        eprintln!("Failed to answer mailbox request from {}: {}", sender_id, e);
+                                    }
+                                }
+                                continue;
+                            },
+                            P2PMessage::FileOffer(offer) => {
+                                if !files::is_valid_id(&offer.id) {
+                                    eprintln!("Ignoring file offer with a malformed id from {}", sender_id);
+                                    continue;
+                                }
+                                let info = {
+                                    let mut transfers = state.transfers.lock().unwrap();
+                                    if transfers.contains_key(&offer.id) {
+                                        continue;
+                                    }
+                                    println!("{} offered {} ({} bytes)", sender_id, offer.name, offer.size);
+                                    let transfer = Transfer::incoming(&sender_id, offer, &state.downloads_dir);
+                                    let info = transfer.info();
+                                    transfers.insert(transfer.id.clone(), transfer);
+                                    if let Err(e) = files::save_transfers(vault, &transfers) {
+                                        eprintln!("Failed to save file transfers: {}", e);
+                                    }
+                                    info
+                                };
+                                let _ = events.send(NodeEvent::Transfer(info));
+                                continue;
+                            },
+                            P2PMessage::FileCancel { id } => {
+                                let info = state.update_transfer(&id, |transfer| {
+                                    if transfer.peer_id != sender_id {
+                                        return Err(format!("{} is not part of transfer {}", sender_id, id));
+                                    }
+                                    transfer.cancel()
+                                });
+                                match info {
+                                    Ok(info) => {
+                                        let _ = events.send(NodeEvent::Transfer(info));
+                                    },
+                                    Err(e) => eprintln!("Ignoring file cancel from {}: {}", sender_id, e),
+                                }
+                                continue;
+                            },
+                            P2PMessage::Typing { is_typing } => {
+                                let _ = events.send(NodeEvent::PeerTyping { peer_id: channel.to_string(), is_typing });
+                                continue; // Don't process as a chat message
+                            },
+                            P2PMessage::Devices { certs } => {
+                                let update = {
+                                    let mut devices = state.devices.lock().unwrap();
+                                    let update = devices.receive(&local_peer_id, &sender_id, certs);
+                                    if update.is_ok() {
+                                        if let Err(e) = devices.save(vault) {
+                                            eprintln!("Failed to save linked devices: {}", e);
+                                        }
+                                    }
+                                    update
+                                };
+                                match update {
+                                    Ok(update) => {
+                                        println!("Device list from {}: {:?}", sender_id, update);
+                                        // Newly linked: pull the history from the primary
+                                        if update == DevicesUpdate::Linked {
+                                            let request = P2PMessage::HistoryRequest { before: None };
+                                            if let Err(e) = publish_sealed(&mut swarm, state, &sender_id, &request) {
+                                                eprintln!("Failed to request history from {}: {}", sender_id, e);
+                                            }
+                                        }
+                                        let _ = events.send(NodeEvent::DevicesUpdated);
+                                    },
+                                    Err(e) => eprintln!("Rejected device list from {}: {}", sender_id, e),
+                                }
+                                continue;
+                            },
+                            P2PMessage::HistoryRequest { before } => {
+                                if !state.devices.lock().unwrap().is_own_device(&sender_id) {
+                                    eprintln!("Ignoring history request from {}, not one of our devices", sender_id);
+                                    continue;
+                                }
+                                let (messages, next) = history_page(state, before);
+                                println!("Sending {} messages of history to {}", messages.len(), sender_id);
+                                if let Err(e) = publish_sealed(&mut swarm, state, &sender_id, &P2PMessage::HistoryBatch { messages, next }) {
+                                    eprintln!("Failed to send history to {}: {}", sender_id, e);
+                                }
+                                continue;
+                            },
+                            P2PMessage::HistoryBatch { messages, next } => {
+                                let own_devices = {
+                                    let devices = state.devices.lock().unwrap();
+                                    if !devices.is_own_device(&sender_id) {
+                                        eprintln!("Ignoring history from {}, not one of our devices", sender_id);
+                                        continue;
+                                    }
+                                    devices.own_devices(&local_peer_id)
+                                };
+                                let count = messages.len();
+                                for mut msg in messages {
+                                    // What another device of ours sent is ours here too
+                                    if own_devices.contains(&msg.sender) {
+                                        msg.sender = local_peer_id.clone();
+                                    }
+                                    if let Err(e) = state.store.insert_message(&msg) {
+                                        eprintln!("Failed to store synced message {}: {}", msg.uuid, e);
+                                    }
+                                }
+                                if next.is_some() {
+                                    let request = P2PMessage::HistoryRequest { before: next };
+                                    if let Err(e) = publish_sealed(&mut swarm, state, &sender_id, &request) {
+                                        eprintln!("Failed to request history from {}: {}", sender_id, e);
+                                    }
+                                }
+                                let _ = events.send(NodeEvent::HistorySynced { peer_id: sender_id.clone(), count });
+                                continue;
+                            },
+                            P2PMessage::SyncBatch { messages, more } => {
+                                receive_sync_batch(&events, state, &mut swarm, &sender_id, messages, more);
+                                continue;
+                            },
+                        }
+                    }
+
+                    println!("Got message on channel {} from {}", channel, sender_id);
+
+                    let stored = StoredMessage::from_payload(&sender_id, channel, &final_content, message_id.as_deref(), "delivered");
+                    if let Err(e) = state.store.insert_message(&stored) {
+                        eprintln!("Failed to store message from {}: {}", sender_id, e);
+                    }
+                    let _ = events.send(NodeEvent::Message { sender: sender_id.clone(), content: final_content, channel: channel.to_string() });
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::File(request_response::Event::Message { peer, message })) => match message {
+                    request_response::Message::Request { request, channel, .. } => {
+                        let response = serve_chunk(&events, state, &peer.to_string(), request);
+                        let _ = swarm.behaviour_mut().file.send_response(channel, response);
+                    },
+                    request_response::Message::Response { request_id, response } => {
+                        let Some((id, index)) = transfers.in_flight.remove(&request_id) else { continue };
+                        receive_chunk(&events, state, &id, index, response);
+                        request_chunks(&mut swarm, state, &mut lookups, &mut transfers, &finished_tx);
+                    },
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::File(request_response::Event::OutboundFailure { request_id, error, .. })) => {
+                    if let Some((id, index)) = transfers.in_flight.remove(&request_id) {
+                        eprintln!("Request for chunk {} of {} failed: {}", index, id, error);
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Sync(request_response::Event::Message { peer, message })) => match message {
+                    request_response::Message::Request { request, channel, .. } => {
+                        let response = answer_sync(state, &peer.to_string(), request);
+                        let _ = swarm.behaviour_mut().sync.send_response(channel, response);
+                    },
+                    request_response::Message::Response { response, .. } => {
+                        let peer = peer.to_string();
+                        let frame = match response {
+                            SyncResponse::Sealed(frame) => frame,
+                            SyncResponse::UpToDate => continue,
+                            SyncResponse::Unavailable => {
+                                println!("{} can't sync with us yet", peer);
+                                continue;
+                            }
+                        };
+                        let Ok(InboxFrame::Sealed(payload)) = serde_json::from_str::<InboxFrame>(&frame) else {
+                            eprintln!("Dropping malformed sync batch from {}", peer);
+                            continue;
+                        };
+                        match open_from_peer(vault, sessions, (ecdh_key, prekey), &local_peer_id, &peer, &payload) {
+                            Ok(Opened { message: P2PMessage::SyncBatch { messages, more }, .. }) => {
+                                receive_sync_batch(&events, state, &mut swarm, &peer, messages, more);
+                            },
+                            Ok(_) => eprintln!("Dropping sync response from {} that isn't a sync batch", peer),
+                            Err(e) => eprintln!("Dropping sync batch from {}: {}", peer, e),
+                        }
+                    },
+                },
+                SwarmEvent::Behaviour(MyBehaviourEvent::Sync(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                    eprintln!("Sync with {} failed: {}", peer, error);
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                    // A contact we hold messages for is back: hand them over
+                    let client = peer_id.to_string();
+                    if topic != gossipsub::IdentTopic::new(format!("inbox-{}", client)).hash() {
+                        continue;
+                    }
+                    let held = {
+                        let mut mailbox = mailbox.lock().unwrap();
+                        if !mailbox.is_client(&client) {
+                            continue;
+                        }
+                        let held = mailbox.take(&client);
+                        if let Err(e) = mailbox.save(vault) {
+                            eprintln!("Failed to save mailbox: {}", e);
+                        }
+                        held
+                    };
+
+                    if !held.is_empty() {
+                        println!("Delivering {} held message(s) to {}", held.len(), client);
+                    }
+                    for held_frame in held {
+                        let forwarded = InboxFrame::Forwarded { from: held_frame.from, frame: held_frame.frame };
+                        let json = serde_json::to_string(&forwarded).unwrap();
+                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), json.as_bytes().to_vec()) {
+                            eprintln!("Failed to deliver held message to {}: {:?}", client, e);
+                        }
+                    }
+                }
+                _ => {}
+            },
+            command = rx.recv() => match command {
+                Some(NodeCommand::Publish { topic, data, reply }) => {
+                    // A private message: if we aren't connected, look the peer up so the next attempt has a direct route
+                    if let Some(peer_id) = topic.strip_prefix("inbox-").and_then(|p| p.parse::<libp2p::PeerId>().ok()) {
+                        find_peer_addresses(&mut swarm, &mut lookups, peer_id);
+                    }
+                    let result = swarm.behaviour_mut().gossipsub.publish(gossipsub::IdentTopic::new(topic), data)
+                        .map(|_| ())
+                        .map_err(|e| format!("Publish failed: {:?}", e));
+                    let _ = reply.send(result);
+                }
+                Some(NodeCommand::Subscribe { topic }) => {
+                    if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(topic)) {
+                        eprintln!("Subscribe error: {:?}", e);
+                    }
+                }
+                Some(NodeCommand::Unsubscribe { topic }) => {
+                    let _ = swarm.behaviour_mut().gossipsub.unsubscribe(&gossipsub::IdentTopic::new(topic));
+                }
+                Some(NodeCommand::Dial { addr, reply }) => {
+                    println!("Dialing {}", addr);
+                    let opts = DialOpts::unknown_peer_id().address(addr).build();
+                    let connection_id = opts.connection_id();
+                    match swarm.dial(opts) {
+                        Ok(()) => {
+                            pending_dials.insert(connection_id, reply);
+                        },
+                        Err(e) => {
+                            let _ = reply.send(Err(format!("Dial failed: {}", e)));
+                        }
+                    }
+                }
+                Some(NodeCommand::Disconnect { peer_id, reply }) => {
+                    let result = swarm.disconnect_peer_id(peer_id)
+                        .map_err(|_| format!("Not connected to {}", peer_id));
+                    let _ = reply.send(result);
+                }
+                Some(NodeCommand::FindPeer { peer_id }) => {
+                    find_peer_addresses(&mut swarm, &mut lookups, peer_id);
+                }
+                Some(NodeCommand::ReloadSettings) => {
+                    reserve_on_relay(&events, &mut swarm, state, &mut relay_listener);
+                    bootstrap_dht(&mut swarm, settings);
+                }
+                Some(NodeCommand::PollTransfers) => {
+                    request_chunks(&mut swarm, state, &mut lookups, &mut transfers, &finished_tx);
+                }
+                Some(NodeCommand::PeerScores { reply }) => {
+                    let gossipsub = &swarm.behaviour().gossipsub;
+                    let mut scores: Vec<PeerScore> = gossipsub.all_peers()
+                        .map(|(peer_id, topics)| PeerScore {
+                            peer_id: peer_id.to_string(),
+                            score: gossipsub.peer_score(peer_id),
+                            topics: topics.into_iter().map(|t| t.to_string()).collect(),
+                        })
+                        .collect();
+                    scores.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap_or(std::cmp::Ordering::Equal));
+                    let _ = reply.send(scores);
+                }
+                Some(NodeCommand::Shutdown { reply }) => {
+                    println!("Shutting down P2P node");
+                    return Ok(Some(reply));
+                }
+                // All handles are gone, nobody can talk to us anymore
+                None => {
+                    println!("Shutting down P2P node");
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+// Seals `msg` for `peer_id` and publishes it right away, for answers sent
+// from within the P2P loop. Does nothing if we can't send on the session yet.
+fn publish_sealed(swarm: &mut libp2p::Swarm<MyBehaviour>, state: &NodeState, peer_id: &str, msg: &P2PMessage) -> Result<(), String> {
+    let Some(frame) = seal_for_peer(&state.vault, &state.sessions, peer_id, msg)? else {
+        return Ok(());
+    };
+    let topic = gossipsub::IdentTopic::new(format!("inbox-{}", peer_id));
+    swarm.behaviour_mut().gossipsub.publish(topic, frame.into_bytes())
+        .map(|_| ())
+        .map_err(|e| format!("Publish failed: {:?}", e))
+}
+
+// Whether a gossip message may be forwarded and reach the loop and the UI.
+// On top of `Validator::check`, inbox and group messages have to be frames
+// we can parse. Our mailbox hands over everything it held at once and our
+// devices page through history, so they aren't rate limited.
+fn validate_message(state: &NodeState, validator: &mut Validator, message: &gossipsub::Message) -> gossipsub::MessageAcceptance {
+    let well_formed = validator.check(message) && if message.topic.as_str().starts_with("inbox-") {
+        serde_json::from_slice::<InboxFrame>(&message.data).is_ok()
+    } else if state.groups.lock().unwrap().values().any(|g| g.topic().hash() == message.topic) {
+        serde_json::from_slice::<GroupEnvelope>(&message.data).is_ok()
+    } else {
+        true
+    };
+    if !well_formed {
+        return gossipsub::MessageAcceptance::Reject;
+    }
+
+    let source = message.source.map(|p| p.to_string()).unwrap_or_default();
+    let trusted = state.mailbox.lock().unwrap().settings.mailbox_peer.as_deref() == Some(source.as_str())
+        || state.devices.lock().unwrap().is_own_device(&source);
+    if !trusted && !validator.allow(message) {
+        return gossipsub::MessageAcceptance::Ignore;
+    }
+    gossipsub::MessageAcceptance::Accept
+}
+
+// Tells a peer we just set up a session with which devices our account
+// has. Only the primary does, and only once it has linked any.
+fn send_devices(swarm: &mut libp2p::Swarm<MyBehaviour>, state: &NodeState, peer_id: &str) {
+    let certs = {
+        let devices = state.devices.lock().unwrap();
+        if !devices.is_primary() || devices.own.is_empty() {
+            return;
+        }
+        devices.own.clone()
+    };
+    if let Err(e) = publish_sealed(swarm, state, peer_id, &P2PMessage::Devices { certs }) {
+        eprintln!("Failed to send device list to {}: {}", peer_id, e);
+    }
+}
+
+// A page of our history for another of our devices, newest first, and
+// where the next page starts if there is one. Messages too large for a
+// page on their own are left out.
+fn history_page(state: &NodeState, before: Option<i64>) -> (Vec<StoredMessage>, Option<i64>) {
+    let fetched = match state.store.messages_before(before, HISTORY_PAGE) {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            eprintln!("Failed to read history: {}", e);
+            return (Vec::new(), None);
+        }
+    };
+    let full = fetched.len() == HISTORY_PAGE as usize;
+
+    let mut page = Vec::new();
+    let mut size = 0;
+    let mut next = None;
+    for msg in fetched {
+        let len = serde_json::to_vec(&msg).map(|json| json.len()).unwrap_or(usize::MAX);
+        if len > HISTORY_PAGE_BYTES {
+            next = Some(msg.timestamp);
+            continue;
+        }
+        if size + len > HISTORY_PAGE_BYTES {
+            return (page, next);
+        }
+        size += len;
+        next = Some(msg.timestamp);
+        page.push(msg);
+    }
+    (page, if full { next } else { None })
+}
+
+// Sends `peer_id` the summary of every conversation we share with it. Does
+// nothing without a session, there is nothing to sync then.
+fn request_sync(swarm: &mut libp2p::Swarm<MyBehaviour>, state: &NodeState, peer_id: &str) {
+    let Ok(peer) = peer_id.parse::<libp2p::PeerId>() else { return };
+    if !state.sessions.lock().unwrap().contains_key(peer_id) {
+        return;
+    }
+
+    let since = now_millis() - sync::SYNC_WINDOW_MS;
+    let mut conversations = vec![(Conversation::Direct, state.devices.lock().unwrap().account_of(peer_id))];
+    conversations.extend(state.groups.lock().unwrap().values()
+        .filter(|g| g.members.iter().any(|m| m == peer_id))
+        .map(|g| (Conversation::Group(g.id.clone()), g.id.clone())));
+
+    let summaries = conversations.into_iter()
+        .take(sync::MAX_SUMMARIES)
+        .filter_map(|(conversation, channel)| {
+            let theirs = state.store.messages_by(&channel, peer_id, since)
+                .map_err(|e| eprintln!("Failed to read history of {}: {}", channel, e))
+                .ok()?;
+            let filter = BloomFilter::new(theirs.iter().map(|m| m.uuid.as_str()));
+            Some(Summary { conversation, since, filter })
+        })
+        .collect();
+    swarm.behaviour_mut().sync.send_request(&peer, SyncRequest { summaries });
+}
+
+// Our messages `peer_id`'s summaries are missing, sealed for it.
+fn answer_sync(state: &NodeState, peer_id: &str, request: SyncRequest) -> SyncResponse {
+    let oldest = now_millis() - sync::SYNC_WINDOW_MS;
+    let account = state.devices.lock().unwrap().account_of(peer_id);
+    let mut messages = Vec::new();
+    let mut size = 0;
+    let mut more = false;
+
+    'summaries: for summary in request.summaries.into_iter().take(sync::MAX_SUMMARIES) {
+        if !summary.filter.is_valid() {
+            eprintln!("Ignoring malformed sync summary from {}", peer_id);
+            continue;
+        }
+        let channel = match &summary.conversation {
+            Conversation::Direct => account.clone(),
+            Conversation::Group(id) => {
+                let groups = state.groups.lock().unwrap();
+                match groups.get(id) {
+                    Some(group) if group.members.iter().any(|m| m == peer_id) => id.clone(),
+                    _ => continue,
+                }
+            }
+        };
+        let ours = match state.store.messages_by(&channel, &state.local_peer_id, summary.since.max(oldest)) {
+            Ok(ours) => ours,
+            Err(e) => {
+                eprintln!("Failed to read history of {}: {}", channel, e);
+                continue;
+            }
+        };
+
+        for message in ours.into_iter().filter(|m| !summary.filter.contains(&m.uuid)) {
+            let len = serde_json::to_vec(&message).map(|json| json.len()).unwrap_or(usize::MAX);
+            if len > sync::SYNC_BATCH_BYTES {
+                continue;
+            }
+            if messages.len() == sync::SYNC_BATCH || size + len > sync::SYNC_BATCH_BYTES {
+                more = true;
+                break 'summaries;
+            }
+            size += len;
+            messages.push(SyncedMessage { conversation: summary.conversation.clone(), message });
+        }
+    }
+
+    if messages.is_empty() {
+        return SyncResponse::UpToDate;
+    }
+    println!("Sending {} missed messages to {}", messages.len(), peer_id);
+    match seal_for_peer(&state.vault, &state.sessions, peer_id, &P2PMessage::SyncBatch { messages, more }) {
+        Ok(Some(frame)) => SyncResponse::Sealed(frame),
+        Ok(None) => SyncResponse::Unavailable,
+        Err(e) => {
+            eprintln!("Failed to seal sync batch for {}: {}", peer_id, e);
+            SyncResponse::Unavailable
+        }
+    }
+}
+
+// Stores what `peer_id` sent us to fill the gap, acknowledging its direct
+// messages like ones that came over the inbox.
+fn receive_sync_batch(
+    events: &mpsc::UnboundedSender<NodeEvent>,
+    state: &NodeState,
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    peer_id: &str,
+    messages: Vec<SyncedMessage>,
+    more: bool,
+) {
+    let account = state.devices.lock().unwrap().account_of(peer_id);
+    let mut count = 0;
+    for SyncedMessage { conversation, mut message } in messages {
+        if message.sender != peer_id {
+            eprintln!("Dropping synced message {} from {}, written by {}", message.uuid, peer_id, message.sender);
+            continue;
+        }
+        message.channel = match &conversation {
+            Conversation::Direct => account.clone(),
+            Conversation::Group(id) => {
+                let groups = state.groups.lock().unwrap();
+                match groups.get(id) {
+                    Some(group) if group.members.iter().any(|m| m == peer_id) => id.clone(),
+                    _ => continue,
+                }
+            }
+        };
+        message.status = "delivered".to_string();
+
+        match state.store.insert_message(&message) {
+            Ok(true) => {
+                count += 1;
+                if conversation == Conversation::Direct {
+                    if let Err(e) = publish_sealed(swarm, state, peer_id, &P2PMessage::Ack { id: message.uuid }) {
+                        eprintln!("Failed to acknowledge synced message from {}: {}", peer_id, e);
+                    }
+                }
+            },
+            Ok(false) => {},
+            Err(e) => eprintln!("Failed to store synced message {}: {}", message.uuid, e),
+        }
+    }
+
+    if count > 0 {
+        println!("Synced {} missed messages from {}", count, peer_id);
+        let _ = events.send(NodeEvent::HistorySynced { peer_id: peer_id.to_string(), count });
+    }
+    if more {
+        request_sync(swarm, state, peer_id);
+    }
+}
+
+// What the P2P loop tracks for incoming file transfers.
+#[derive(Default)]
+struct TransferRequests {
+    // Chunk requests waiting for an answer: transfer id and chunk index
+    in_flight: HashMap<request_response::OutboundRequestId, (String, u64)>,
+    // Fully received transfers whose hash is being checked
+    finishing: HashSet<String>,
+}
+
+// Asks for the next chunk of every active incoming transfer that isn't
+// waiting for one already, and hands fully received ones off to be checked.
+// One chunk at a time per transfer keeps `chunks_done` a plain counter.
+fn request_chunks(
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    state: &NodeState,
+    lookups: &mut HashMap<kad::QueryId, libp2p::PeerId>,
+    requests: &mut TransferRequests,
+    finished: &mpsc::UnboundedSender<(String, Result<PathBuf, String>)>,
+) {
+    let transfers = state.transfers.lock().unwrap();
+    let active = transfers.values().filter(|t| t.direction == Direction::Incoming && t.status == TransferStatus::Active);
+    for transfer in active {
+        if requests.finishing.contains(&transfer.id) || requests.in_flight.values().any(|(id, _)| *id == transfer.id) {
+            continue;
+        }
+
+        if transfer.chunks_done >= transfer.chunk_count() {
+            requests.finishing.insert(transfer.id.clone());
+            let (id, part_path, name, hash) = (transfer.id.clone(), transfer.path.clone(), transfer.name.clone(), transfer.hash.clone());
+            let downloads_dir = state.downloads_dir.clone();
+            let finished = finished.clone();
+            tokio::task::spawn_blocking(move || {
+                let result = files::finish_download(&part_path, &downloads_dir, &name, &hash);
+                let _ = finished.send((id, result));
+            });
+            continue;
+        }
+
+        let Ok(peer_id) = transfer.peer_id.parse::<libp2p::PeerId>() else { continue };
+        // The request dials the peer itself if it knows an address
+        find_peer_addresses(swarm, lookups, peer_id);
+        let request = ChunkRequest { id: transfer.id.clone(), index: transfer.chunks_done };
+        let request_id = swarm.behaviour_mut().file.send_request(&peer_id, request);
+        requests.in_flight.insert(request_id, (transfer.id.clone(), transfer.chunks_done));
+    }
+}
+
+// Answers a chunk request. Only the peer a file was offered to gets its chunks.
+fn serve_chunk(events: &mpsc::UnboundedSender<NodeEvent>, state: &NodeState, peer_id: &str, request: ChunkRequest) -> ChunkResponse {
+    let mut transfers = state.transfers.lock().unwrap();
+    let Some(transfer) = transfers.get_mut(&request.id) else {
+        return ChunkResponse::Unavailable;
+    };
+    if transfer.direction != Direction::Outgoing || transfer.peer_id != peer_id || request.index >= transfer.chunk_count() {
+        return ChunkResponse::Unavailable;
+    }
+    match transfer.status {
+        TransferStatus::Active | TransferStatus::Completed => {},
+        TransferStatus::Paused => return ChunkResponse::Paused,
+        _ => return ChunkResponse::Unavailable,
+    }
+
+    let response = files::read_chunk(&transfer.path, request.index)
+        .map_err(|e| e.to_string())
+        .and_then(|data| files::seal_chunk(&transfer.key, &transfer.id, request.index, &data));
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Failed to read chunk {} of {}: {}", request.index, transfer.path.display(), e);
+            return ChunkResponse::Unavailable;
+        }
+    };
+
+    transfer.chunks_done = transfer.chunks_done.max(request.index + 1);
+    let completed = transfer.status == TransferStatus::Active && transfer.chunks_done == transfer.chunk_count();
+    if completed {
+        transfer.status = TransferStatus::Completed;
+    }
+    let _ = events.send(NodeEvent::Transfer(transfer.info()));
+    // Progress of an upload doesn't need to survive a restart, only the outcome
+    if completed {
+        if let Err(e) = files::save_transfers(&state.vault, &transfers) {
+            eprintln!("Failed to save file transfers: {}", e);
+        }
+    }
+    response
+}
+
+// What `receive_chunk` did with a response.
+enum ChunkOutcome {
+    Applied,
+    // Nothing changed, the chunk is asked for again if still needed
+    Ignored,
+}
+
+// Writes a received chunk to the transfer's `.part` file.
+fn receive_chunk(events: &mpsc::UnboundedSender<NodeEvent>, state: &NodeState, id: &str, index: u64, response: ChunkResponse) {
+    let result = state.with_transfer(id, |transfer| {
+        // Paused or cancelled while the request was out
+        if transfer.status != TransferStatus::Active || transfer.chunks_done != index {
+            return Ok(ChunkOutcome::Ignored);
+        }
+        match response {
+            ChunkResponse::Chunk { data, hash } => {
+                let written = files::open_chunk(&transfer.key, id, index, &data, &hash)
+                    .and_then(|data| files::write_chunk(&transfer.path, index, &data).map_err(|e| e.to_string()));
+                match written {
+                    Ok(()) => transfer.chunks_done += 1,
+                    Err(e) => {
+                        eprintln!("File transfer {} failed: {}", id, e);
+                        transfer.status = TransferStatus::Failed;
+                    }
+                }
+                Ok(ChunkOutcome::Applied)
+            },
+            // Asked again on the next retry
+            ChunkResponse::Paused => Ok(ChunkOutcome::Ignored),
+            ChunkResponse::Unavailable => {
+                eprintln!("{} refused chunk {} of {}", transfer.peer_id, index, id);
+                transfer.status = TransferStatus::Failed;
+                Ok(ChunkOutcome::Applied)
+            },
+        }
+    });
+    match result {
+        Ok((ChunkOutcome::Applied, info)) => {
+            let _ = events.send(NodeEvent::Transfer(info));
+        },
+        Ok((ChunkOutcome::Ignored, _)) => {},
+        Err(e) => eprintln!("Dropping chunk {} of {}: {}", index, id, e),
+    }
+}
+
+// Dials `peer_id` trying `addrs` one at a time, in the given order.
+fn dial_in_order(swarm: &mut libp2p::Swarm<MyBehaviour>, peer_id: libp2p::PeerId, addrs: Vec<libp2p::Multiaddr>) {
+    let opts = DialOpts::peer_id(peer_id)
+        .condition(PeerCondition::DisconnectedAndNotDialing)
+        .addresses(addrs)
+        .override_dial_concurrency_factor(NonZeroU8::MIN)
+        .build();
+    if let Err(e) = swarm.dial(opts) {
+        println!("Not dialing {}: {}", peer_id, e);
+    }
+}
+
+// Adds the configured bootstrap nodes to the routing table and (re)joins the DHT.
+fn bootstrap_dht(swarm: &mut libp2p::Swarm<MyBehaviour>, settings: &Mutex<Settings>) {
+    let nodes = settings.lock().unwrap().bootstrap_nodes.clone();
+    for node in nodes {
+        match discovery::parse_bootstrap_node(&node) {
+            Ok((peer_id, addr)) => {
+                swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+            },
+            Err(e) => eprintln!("Skipping bootstrap node: {}", e),
+        }
+    }
+    // Without any known peers there is nothing to join yet
+    let _ = swarm.behaviour_mut().kad.bootstrap();
+}
+
+// Makes sure we hold a reservation on the relay from settings, dropping the
+// one on a previously configured relay.
+fn reserve_on_relay(
+    events: &mpsc::UnboundedSender<NodeEvent>,
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    state: &NodeState,
+    relay_listener: &mut Option<(String, ListenerId)>,
+) {
+    let relay = state.settings.lock().unwrap().relay.clone();
+    if let Some((current, listener_id)) = relay_listener.take() {
+        if Some(&current) == relay.as_ref() {
+            *relay_listener = Some((current, listener_id));
+            return;
+        }
+        swarm.remove_listener(listener_id);
+        set_relay_reserved(events, &state.network_status, false);
+    }
+
+    let Some(relay) = relay else { return };
+    let (relay_peer_id, circuit_addr) = match nat::relay_listen_address(&relay) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Skipping relay: {}", e);
+            return;
+        }
+    };
+
+    // The relay is also a good candidate to check our reachability
+    swarm.behaviour_mut().autonat.add_server(relay_peer_id, relay.parse().ok());
+    match swarm.listen_on(circuit_addr) {
+        Ok(listener_id) => *relay_listener = Some((relay, listener_id)),
+        Err(e) => eprintln!("Failed to listen via relay {}: {:?}", relay, e),
+    }
+}
+
+fn set_relay_reserved(events: &mpsc::UnboundedSender<NodeEvent>, network_status: &Mutex<NetworkStatus>, reserved: bool) {
+    let status = {
+        let mut status = network_status.lock().unwrap();
+        status.relay_reserved = reserved;
+        status.clone()
+    };
+    let _ = events.send(NodeEvent::NetworkStatus(status));
+}
+
+// Starts a DHT lookup of `peer_id`'s address record, unless we're already
+// connected or a lookup is in flight.
+fn find_peer_addresses(swarm: &mut libp2p::Swarm<MyBehaviour>, lookups: &mut HashMap<kad::QueryId, libp2p::PeerId>, peer_id: libp2p::PeerId) {
+    if swarm.is_connected(&peer_id) || lookups.values().any(|p| *p == peer_id) {
+        return;
+    }
+    let id = swarm.behaviour_mut().kad.get_record(discovery::address_record_key(&peer_id));
+    lookups.insert(id, peer_id);
+}
+
+// Handles a mailbox request or answer from `sender_id`, returning the reply to seal back, if any.
+fn handle_mailbox_message(
+    events: &mpsc::UnboundedSender<NodeEvent>,
+    state: &NodeState,
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    sender_id: &str,
+    msg: MailboxMessage,
+) -> Option<MailboxMessage> {
+    let mut mailbox = state.mailbox.lock().unwrap();
+    let topic = gossipsub::IdentTopic::new(format!("inbox-{}", sender_id));
+
+    let reply = match msg {
+        MailboxMessage::Register => {
+            if !mailbox.register(sender_id) {
+                return Some(MailboxMessage::Declined);
+            }
+            println!("Serving as mailbox for {}", sender_id);
+            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                eprintln!("Subscribe error: {:?}", e);
+            }
+            Some(MailboxMessage::Accepted)
+        },
+        MailboxMessage::Unregister => {
+            mailbox.unregister(sender_id);
+            let _ = swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+            None
+        },
+        MailboxMessage::Accepted | MailboxMessage::Declined => {
+            if mailbox.settings.mailbox_peer.as_deref() == Some(sender_id) {
+                let _ = events.send(NodeEvent::MailboxStatus {
+                    peer_id: sender_id.to_string(),
+                    accepted: matches!(msg, MailboxMessage::Accepted),
+                });
+            }
+            return None;
+        },
+    };
+
+    if let Err(e) = mailbox.save(&state.vault) {
+        eprintln!("Failed to save mailbox: {}", e);
+    }
+    reply
+}
+
+// Applies a group key or removal notice received over our inbox. Updates are
+// only taken from peers that are members of the group as we know it; a new
+// group is accepted from any member that lists us in it.
+fn handle_group_update(
+    events: &mpsc::UnboundedSender<NodeEvent>,
+    state: &NodeState,
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    sender_id: &str,
+    update: GroupUpdate,
+) {
+    let mut groups_guard = state.groups.lock().unwrap();
+
+    match update {
+        GroupUpdate::Key(group) => {
+            let authorized = match groups_guard.get(&group.id) {
+                Some(existing) => existing.is_member(sender_id)
+                    && existing.owner() == group.owner()
+                    && group.supersedes(existing),
+                None => group.is_member(sender_id),
+            };
+            if !authorized {
+                eprintln!("Ignoring stale or unauthorized group key for {} from {}", group.id, sender_id);
+                return;
+            }
+
+            if group.is_member(&state.local_peer_id) {
+                println!("Group {} key #{} received from {}", group.id, group.epoch, sender_id);
+                if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&group.topic()) {
+                    eprintln!("Subscribe error: {:?}", e);
+                }
+                groups_guard.insert(group.id.clone(), group.clone());
+            } else {
+                println!("Removed from group {} by {}", group.id, sender_id);
+                let _ = swarm.behaviour_mut().gossipsub.unsubscribe(&group.topic());
+                groups_guard.remove(&group.id);
+            }
+            let _ = events.send(NodeEvent::GroupUpdated(group.id));
+        },
+        GroupUpdate::Removed { group_id } => {
+            let Some(existing) = groups_guard.get(&group_id) else { return };
+            if !existing.is_member(sender_id) {
+                eprintln!("Ignoring removal from {} by {}, not a member", group_id, sender_id);
+                return;
+            }
+            println!("Removed from group {} by {}", group_id, sender_id);
+            let _ = swarm.behaviour_mut().gossipsub.unsubscribe(&existing.topic());
+            groups_guard.remove(&group_id);
+            let _ = events.send(NodeEvent::GroupUpdated(group_id));
+        },
+    }
+
+    if let Err(e) = groups::save_groups(&state.vault, &groups_guard) {
+        eprintln!("Failed to save groups: {}", e);
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce 
+};
+use rand::{rngs::OsRng, RngCore};
+use base64::{Engine as _, engine::general_purpose};
+use libp2p::identity;
+use x25519_dalek::{StaticSecret, PublicKey};
+use crate::devices::DeviceCert;
+use crate::files::FileOffer;
+use crate::groups::GroupUpdate;
+use crate::mailbox::MailboxMessage;
+use crate::ratchet::{self, Bundle, Encrypted, Session};
+use crate::store::StoredMessage;
+use crate::sync::SyncedMessage;
+use crate::vault::Vault;
+
+// What peers send each other and the crypto around it: the signed X3DH
+// bundles, sealing and opening inbox frames with the ratchet sessions, and
+// the AES-GCM used for group messages.
+
+pub fn encrypt_message(plaintext: &str, key: &[u8; 32]) -> Result<String, String> {
+    let cipher = Aes256Gcm::new(key.into());
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let nonce = Nonce::from_slice(&nonce);
+    
+    let ciphertext = cipher.encrypt(nonce, plaintext.as_bytes())
+        .map_err(|e| e.to_string())?;
+    
+    let mut combined = nonce.to_vec();
+    combined.extend(ciphertext);
+    
+    Ok(general_purpose::STANDARD.encode(combined))
+}
+
+pub fn decrypt_message(encrypted_msg: &str, key: &[u8; 32]) -> Result<String, String> {
+    let decoded = general_purpose::STANDARD.decode(encrypted_msg)
+        .map_err(|e| e.to_string())?;
+    
+    if decoded.len() < 12 {
+        return Err("Message too short".to_string());
+    }
+    
+    let (nonce_bytes, ciphertext_bytes) = decoded.split_at(12);
+    let nonce = Nonce::from_slice(nonce_bytes);
+    let cipher = Aes256Gcm::new(key.into());
+    
+    let plaintext = cipher.decrypt(nonce, ciphertext_bytes)
+        .map_err(|e| e.to_string())?;
+        
+    String::from_utf8(plaintext).map_err(|e| e.to_string())
+}
+
+// Domain separator for bundle signatures, so a signature over our X25519 keys
+// can't be confused with anything else the identity key signs.
+const BUNDLE_SIGNING_CONTEXT: &[u8] = b"phantom-bundle-v1";
+
+fn bundle_signing_payload(ik: &[u8; 32], spk: &[u8; 32]) -> Vec<u8> {
+    let mut payload = BUNDLE_SIGNING_CONTEXT.to_vec();
+    payload.extend_from_slice(ik);
+    payload.extend_from_slice(spk);
+    payload
+}
+
+// Our X25519 identity key and signed prekey, signed with the libp2p identity.
+pub fn signed_bundle(identity: &identity::Keypair, ecdh_key: &StaticSecret, prekey: &StaticSecret) -> Result<Bundle, String> {
+    let ik = PublicKey::from(ecdh_key);
+    let spk = PublicKey::from(prekey);
+    let signature = identity.sign(&bundle_signing_payload(ik.as_bytes(), spk.as_bytes()))
+        .map_err(|e| e.to_string())?;
+
+    Ok(Bundle {
+        identity_key: hex::encode(identity.public().encode_protobuf()),
+        ik: hex::encode(ik.as_bytes()),
+        spk: hex::encode(spk.as_bytes()),
+        signature: hex::encode(signature),
+    })
+}
+
+// Returns the bundle's identity key and prekey only if they were signed by
+// the identity key that `sender` (the gossipsub message source) is derived from.
+pub fn verify_bundle(sender: &str, bundle: &Bundle) -> Result<([u8; 32], [u8; 32]), String> {
+    let decode = |key: &str| -> Result<[u8; 32], String> {
+        hex::decode(key)
+            .map_err(|e| e.to_string())?
+            .try_into()
+            .map_err(|_| "X25519 public key must be 32 bytes".to_string())
+    };
+    let ik = decode(&bundle.ik)?;
+    let spk = decode(&bundle.spk)?;
+
+    let identity_bytes = hex::decode(&bundle.identity_key).map_err(|e| e.to_string())?;
+    let identity = identity::PublicKey::try_decode_protobuf(&identity_bytes)
+        .map_err(|e| e.to_string())?;
+    if identity.to_peer_id().to_string() != sender {
+        return Err("Identity key does not match sender PeerId".to_string());
+    }
+
+    let signature = hex::decode(&bundle.signature).map_err(|e| e.to_string())?;
+    if !identity.verify(&bundle_signing_payload(&ik, &spk), &signature) {
+        return Err("Invalid bundle signature".to_string());
+    }
+
+    Ok((ik, spk))
+}
+
+// Seals `msg` for `peer_id` with its ratchet session and persists the
+// advanced state. Returns the serialized `InboxFrame`, or `None` if there is
+// no session we can send on yet.
+pub fn seal_for_peer(vault: &Vault, sessions: &Mutex<HashMap<String, Session>>, peer_id: &str, msg: &P2PMessage) -> Result<Option<String>, String> {
+    let plaintext = serde_json::to_vec(msg).map_err(|e| e.to_string())?;
+
+    let mut sessions = sessions.lock().map_err(|e| e.to_string())?;
+    let Some(session) = sessions.get_mut(peer_id).filter(|s| s.can_send()) else {
+        return Ok(None);
+    };
+
+    let encrypted = session.encrypt(&plaintext)?;
+    ratchet::save_sessions(vault, &sessions).map_err(|e| e.to_string())?;
+    serde_json::to_string(&InboxFrame::Sealed(encrypted)).map(Some).map_err(|e| e.to_string())
+}
+
+// A sealed frame opened by `open_from_peer`.
+pub struct Opened {
+    pub message: P2PMessage,
+    // True when the frame established a new session
+    pub new_session: bool,
+    // Frames to publish to the sender again: what we sent on a session of
+    // ours that lost to the sender's, sealed anew on the sender's session
+    pub resend: Vec<String>,
+}
+
+// Opens a sealed frame from `sender_id`. If it carries an X3DH init we
+// haven't seen, the responder side of the new session is set up first. The
+// bundle signature doesn't cover the init's ephemeral key, so an old init
+// could be replayed: one only replaces a session that never heard from the
+// peer, and never with an ephemeral key used before.
+pub fn open_from_peer(
+    vault: &Vault,
+    sessions: &Mutex<HashMap<String, Session>>,
+    keys: (&StaticSecret, &StaticSecret),
+    local_peer_id: &str,
+    sender_id: &str,
+    payload: &Encrypted,
+) -> Result<Opened, String> {
+    let (ecdh_key, prekey) = keys;
+    let mut sessions = sessions.lock().map_err(|e| e.to_string())?;
+
+    let mut new_session = None;
+    let mut unconfirmed = Vec::new();
+    if let Some(init) = &payload.init {
+        let existing = sessions.get(sender_id);
+        if existing.map(|s| s.ek != init.ek).unwrap_or(true) {
+            let ours_pending = existing.map(|s| s.pending_init.is_some()).unwrap_or(false);
+            if let Some(existing) = existing {
+                if existing.consumed.contains(&init.ek) {
+                    return Err("Ignoring replayed session init".to_string());
+                }
+                if !ours_pending && existing.has_received() {
+                    return Err("Ignoring session init, a session is already established".to_string());
+                }
+            }
+            // We both answered each other's handshake at the same time: the
+            // session started by the peer with the smaller PeerId wins.
+            if ours_pending && local_peer_id < sender_id {
+                return Err("Ignoring concurrent session init".to_string());
+            }
+            verify_bundle(sender_id, &init.bundle)?;
+            let mut session = ratchet::respond(ecdh_key, prekey, init)?;
+            if let Some(existing) = existing {
+                session.replaces(existing);
+                // The peer ignores ours, so what we sent on it never arrived
+                if ours_pending {
+                    unconfirmed = existing.unconfirmed.clone();
+                }
+            }
+            new_session = Some(session);
+        }
+    }
+
+    let (plaintext, new_session, resend) = match new_session {
+        Some(mut session) => {
+            let plaintext = session.decrypt(payload)?;
+            let mut resend = Vec::new();
+            for plaintext in unconfirmed {
+                // The peer already knows it has a session with us
+                if matches!(serde_json::from_slice(&plaintext), Ok(P2PMessage::SessionInit)) {
+                    continue;
+                }
+                let encrypted = session.encrypt(&plaintext)?;
+                resend.push(serde_json::to_string(&InboxFrame::Sealed(encrypted)).map_err(|e| e.to_string())?);
+            }
+            sessions.insert(sender_id.to_string(), session);
+            (plaintext, true, resend)
+        },
+        None => {
+            let session = sessions.get_mut(sender_id).ok_or_else(|| "No session established".to_string())?;
+            (session.decrypt(payload)?, false, Vec::new())
+        },
+    };
+
+    ratchet::save_sessions(vault, &sessions).map_err(|e| e.to_string())?;
+
+    let message = serde_json::from_slice(&plaintext).map_err(|e| e.to_string())?;
+    Ok(Opened { message, new_session, resend })
+}
+
+// What is actually published on `inbox-<peer>`. Apart from the handshake
+// that sets up a session, everything is a sealed `P2PMessage`, so relays only
+// ever see the ratchet header and not what kind of message it is.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "type", content = "payload")]
+pub enum InboxFrame {
+    // Asks the receiver to start a ratchet session with us
+    Handshake(Bundle),
+    Sealed(Encrypted),
+    // A frame our mailbox held for us while we were offline, as originally published by `from`
+    Forwarded { from: String, frame: String },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "payload")]
+pub enum P2PMessage {
+    // First message of a session, answers a Handshake
+    SessionInit,
+    // `id` is the message uuid the UI assigned, echoed back in receipts
+    Message {
+        #[serde(default)]
+        id: Option<String>,
+        content: String,
+    },
+    Typing { is_typing: bool },
+    // Sent back as soon as a message was decrypted
+    Ack { id: String },
+    // Sent once the user has seen the message
+    Read { id: String },
+    // Changes to an earlier message, `target` is its id. Only its author may
+    // edit or delete it, anyone in the chat may react.
+    Edit { target: String, content: String },
+    Delete { target: String },
+    React { target: String, emoji: String },
+    // A group key handed over by another member
+    Group(GroupUpdate),
+    Mailbox(MailboxMessage),
+    // A file the sender wants to hand over, pulled over `/phantom/file/1`
+    FileOffer(FileOffer),
+    FileCancel { id: String },
+    // Certificates of the devices linked to the sender's account
+    Devices { certs: Vec<DeviceCert> },
+    // Between devices of one account: asks for the history older than `before`
+    HistoryRequest { before: Option<i64> },
+    // A page of it, newest first; `next` is where the following page starts
+    HistoryBatch { messages: Vec<StoredMessage>, next: Option<i64> },
+    // Our messages a contact's sync summary was missing; `more` if the batch was cut short
+    SyncBatch { messages: Vec<SyncedMessage>, more: bool },
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Delivered,
+    Read,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Peer {
+        id: String,
+        identity: identity::Keypair,
+        ecdh_key: StaticSecret,
+        prekey: StaticSecret,
+        dir: std::path::PathBuf,
+        vault: Vault,
+        sessions: Mutex<HashMap<String, Session>>,
+    }
+
+    impl Drop for Peer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    impl Peer {
+        fn new() -> Self {
+            let identity = identity::Keypair::generate_ed25519();
+            let id = identity.public().to_peer_id().to_string();
+            let dir = std::env::temp_dir().join(format!("phantom-protocol-test-{}", id));
+            std::fs::create_dir_all(&dir).unwrap();
+            Peer {
+                id,
+                identity,
+                ecdh_key: StaticSecret::random_from_rng(OsRng),
+                prekey: StaticSecret::random_from_rng(OsRng),
+                vault: Vault::with_key(&dir, [7; 32]),
+                dir,
+                sessions: Mutex::new(HashMap::new()),
+            }
+        }
+
+        // What the P2P loop does with a handshake: start a session as the
+        // initiator and answer with its first message.
+        fn answer_handshake(&self, from: &Peer) -> Encrypted {
+            let (session, init) = self.start_session(from);
+            self.sessions.lock().unwrap().insert(from.id.clone(), session);
+            init
+        }
+
+        fn start_session(&self, with: &Peer) -> (Session, Encrypted) {
+            let bundle = signed_bundle(&with.identity, &with.ecdh_key, &with.prekey).unwrap();
+            let (their_ik, their_spk) = verify_bundle(&with.id, &bundle).unwrap();
+            let ours = signed_bundle(&self.identity, &self.ecdh_key, &self.prekey).unwrap();
+            let mut session = ratchet::initiate(&self.ecdh_key, &their_ik, &their_spk, ours);
+            let init = session.encrypt(&serde_json::to_vec(&P2PMessage::SessionInit).unwrap()).unwrap();
+            (session, init)
+        }
+
+        fn seal(&self, to: &Peer, content: &str) -> Encrypted {
+            let msg = P2PMessage::Message { id: None, content: content.to_string() };
+            sealed(&seal_for_peer(&self.vault, &self.sessions, &to.id, &msg).unwrap().unwrap())
+        }
+
+        fn open(&self, from: &Peer, payload: &Encrypted) -> Result<Opened, String> {
+            open_from_peer(&self.vault, &self.sessions, (&self.ecdh_key, &self.prekey), &self.id, &from.id, payload)
+        }
+    }
+
+    fn sealed(frame: &str) -> Encrypted {
+        match serde_json::from_str(frame).unwrap() {
+            InboxFrame::Sealed(payload) => payload,
+            _ => panic!("not a sealed frame"),
+        }
+    }
+
+    fn content(message: P2PMessage) -> String {
+        match message {
+            P2PMessage::Message { content, .. } => content,
+            _ => panic!("not a chat message"),
+        }
+    }
+
+    #[test]
+    fn concurrent_session_init_resends_what_the_loser_sent() {
+        let (a, b) = (Peer::new(), Peer::new());
+        let (winner, loser) = if a.id < b.id { (a, b) } else { (b, a) };
+
+        // Both got the other's handshake and flushed their outbox
+        let winner_init = winner.answer_handshake(&loser);
+        let loser_init = loser.answer_handshake(&winner);
+        let from_winner = winner.seal(&loser, "from winner");
+        let from_loser = loser.seal(&winner, "from loser");
+
+        // The winner keeps its own session and drops the loser's
+        assert!(winner.open(&loser, &loser_init).is_err());
+        assert!(winner.open(&loser, &from_loser).is_err());
+
+        // The loser switches to the winner's session and seals its message again
+        let opened = loser.open(&winner, &winner_init).unwrap();
+        assert!(opened.new_session);
+        assert_eq!(opened.resend.len(), 1);
+        assert_eq!(content(loser.open(&winner, &from_winner).unwrap().message), "from winner");
+
+        let resent = winner.open(&loser, &sealed(&opened.resend[0])).unwrap();
+        assert!(!resent.new_session);
+        assert_eq!(content(resent.message), "from loser");
+    }
+
+    #[test]
+    fn replayed_init_does_not_replace_a_confirmed_session() {
+        let (a, b) = (Peer::new(), Peer::new());
+        let (winner, loser) = if a.id < b.id { (a, b) } else { (b, a) };
+        let winner_init = winner.answer_handshake(&loser);
+        let loser_init = loser.answer_handshake(&winner);
+        let opened = loser.open(&winner, &winner_init).unwrap();
+        assert!(opened.resend.is_empty());
+
+        // Both sides have heard from each other on the winner's session
+        let reply = loser.seal(&winner, "reply");
+        assert_eq!(content(winner.open(&loser, &reply).unwrap().message), "reply");
+
+        // An old init, or any other one, can't take over the session
+        assert!(winner.open(&loser, &loser_init).is_err());
+        let (_, other_init) = winner.start_session(&loser);
+        assert!(loser.open(&winner, &other_init).is_err());
+        assert!(loser.sessions.lock().unwrap()[&winner.id].consumed.contains(&loser_init.init.as_ref().unwrap().ek));
+
+        // The session still works
+        let after = loser.seal(&winner, "still here");
+        assert_eq!(content(winner.open(&loser, &after).unwrap().message), "still here");
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::error::Error;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce
+};
+use base64::{Engine as _, engine::general_purpose};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::vault::Vault;
+
+// X3DH key agreement and Double Ratchet sessions for 1-on-1 chats, after
+// https://signal.org/docs/specifications/x3dh/ and
+// https://signal.org/docs/specifications/doubleratchet/.
+//
+// Our long-term X25519 identity key is `ecdh.key`, the signed prekey is
+// `prekey.key`. Both are published, signed with the libp2p identity, in a
+// `InboxFrame::Handshake`. Whoever receives a handshake runs X3DH as the
+// initiator and the very first ratchet message carries an `X3dhInit` so the
+// other side can derive the same root key. There are no one-time prekeys:
+// we only ever talk to online peers, so there is no prekey server to drain.
+
+const MAX_SKIP: u32 = 1000;
+// Most messages kept for resending while the initiator hasn't heard back
+const MAX_UNCONFIRMED: usize = 256;
+const X3DH_INFO: &[u8] = b"phantom-x3dh-v1";
+const RATCHET_INFO: &[u8] = b"phantom-ratchet-v1";
+const MESSAGE_KEY_INFO: &[u8] = b"phantom-message-keys-v1";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Header {
+    pub dh: String,
+    pub pn: u32,
+    pub n: u32,
+}
+
+// Our X25519 identity key and signed prekey, signed with the libp2p identity
+// (see `signed_bundle` / `verify_bundle` in protocol.rs).
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Bundle {
+    pub identity_key: String,
+    pub ik: String,
+    pub spk: String,
+    pub signature: String,
+}
+
+// Sent along with every message of a session until the initiator has heard
+// back from the responder, so a lost first message doesn't break the session.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct X3dhInit {
+    #[serde(flatten)]
+    pub bundle: Bundle,
+    pub ek: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Encrypted {
+    pub header: Header,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init: Option<X3dhInit>,
+    pub ciphertext: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+struct SkippedKey {
+    dh: String,
+    n: u32,
+    mk: [u8; 32],
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Session {
+    dhs: [u8; 32],
+    dhr: Option<[u8; 32]>,
+    rk: [u8; 32],
+    cks: Option<[u8; 32]>,
+    ckr: Option<[u8; 32]>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: Vec<SkippedKey>,
+    // IK_A || IK_B from X3DH, bound into every message as associated data
+    ad: Vec<u8>,
+    // Ephemeral key of the X3DH run that created this session
+    pub ek: String,
+    // Our own `X3dhInit`, while we are the initiator and haven't heard back
+    pub pending_init: Option<X3dhInit>,
+    // Plaintexts sent while `pending_init` is set. If the peer started a
+    // session of its own at the same time and ours lost, it never read them.
+    #[serde(default)]
+    pub unconfirmed: Vec<Vec<u8>>,
+    // Remote X25519 identity key, hex
+    pub remote_identity: String,
+    // Remote signed prekey the session was started with, hex
+    #[serde(default)]
+    pub remote_prekey: String,
+    // Ephemeral keys of earlier sessions with the peer. An init carrying one
+    // of them is a replay.
+    #[serde(default)]
+    pub consumed: Vec<String>,
+}
+
+fn dh(secret: &StaticSecret, public: &[u8; 32]) -> [u8; 32] {
+    *secret.diffie_hellman(&PublicKey::from(*public)).as_bytes()
+}
+
+fn decode_key(hex_key: &str) -> Result<[u8; 32], String> {
+    hex::decode(hex_key)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| "Key must be 32 bytes".to_string())
+}
+
+fn x3dh_kdf(dh1: &[u8; 32], dh2: &[u8; 32], dh3: &[u8; 32]) -> [u8; 32] {
+    // 32 0xFF bytes first, as required by the X3DH spec for X25519
+    let mut ikm = vec![0xFFu8; 32];
+    ikm.extend_from_slice(dh1);
+    ikm.extend_from_slice(dh2);
+    ikm.extend_from_slice(dh3);
+
+    let mut sk = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm)
+        .expand(X3DH_INFO, &mut sk)
+        .expect("32 bytes is a valid HKDF output length");
+    sk
+}
+
+fn kdf_rk(rk: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(rk), dh_out)
+        .expand(RATCHET_INFO, &mut okm)
+        .expect("64 bytes is a valid HKDF output length");
+
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+fn kdf_ck(ck: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |byte: u8| {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(ck).expect("HMAC accepts any key length");
+        mac.update(&[byte]);
+        let out: [u8; 32] = mac.finalize().into_bytes().into();
+        out
+    };
+    // (next chain key, message key)
+    (step(0x02), step(0x01))
+}
+
+fn associated_data(ad: &[u8], header: &Header) -> Result<Vec<u8>, String> {
+    let mut data = ad.to_vec();
+    data.extend(serde_json::to_vec(header).map_err(|e| e.to_string())?);
+    Ok(data)
+}
+
+fn seal(mk: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<String, String> {
+    let (key, nonce) = message_key_material(mk);
+    let cipher = Aes256Gcm::new(&key.into());
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|e| e.to_string())?;
+    Ok(general_purpose::STANDARD.encode(ciphertext))
+}
+
+fn open(mk: &[u8; 32], ciphertext: &str, aad: &[u8]) -> Result<Vec<u8>, String> {
+    let ciphertext = general_purpose::STANDARD.decode(ciphertext).map_err(|e| e.to_string())?;
+    let (key, nonce) = message_key_material(mk);
+    let cipher = Aes256Gcm::new(&key.into());
+    cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad })
+        .map_err(|_| "Decryption failed".to_string())
+}
+
+// Every message key is used exactly once, so a nonce derived from it is safe.
+fn message_key_material(mk: &[u8; 32]) -> ([u8; 32], [u8; 12]) {
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(None, mk)
+        .expand(MESSAGE_KEY_INFO, &mut okm)
+        .expect("44 bytes is a valid HKDF output length");
+
+    let mut key = [0u8; 32];
+    let mut nonce = [0u8; 12];
+    key.copy_from_slice(&okm[..32]);
+    nonce.copy_from_slice(&okm[32..]);
+    (key, nonce)
+}
+
+// Initiator side of X3DH, run against a verified bundle of the other side.
+// `bundle` is our own, it travels in the `X3dhInit`.
+pub fn initiate(
+    ik: &StaticSecret,
+    their_ik: &[u8; 32],
+    their_spk: &[u8; 32],
+    bundle: Bundle,
+) -> Session {
+    let ek = StaticSecret::random_from_rng(OsRng);
+    let ek_pub = PublicKey::from(&ek);
+
+    let sk = x3dh_kdf(&dh(ik, their_spk), &dh(&ek, their_ik), &dh(&ek, their_spk));
+
+    let mut ad = PublicKey::from(ik).as_bytes().to_vec();
+    ad.extend_from_slice(their_ik);
+
+    let init = X3dhInit { bundle, ek: hex::encode(ek_pub.as_bytes()) };
+
+    let dhs = StaticSecret::random_from_rng(OsRng);
+    let (rk, cks) = kdf_rk(&sk, &dh(&dhs, their_spk));
+
+    Session {
+        dhs: dhs.to_bytes(),
+        dhr: Some(*their_spk),
+        rk,
+        cks: Some(cks),
+        ckr: None,
+        ns: 0,
+        nr: 0,
+        pn: 0,
+        skipped: Vec::new(),
+        ad,
+        ek: init.ek.clone(),
+        pending_init: Some(init),
+        unconfirmed: Vec::new(),
+        remote_identity: hex::encode(their_ik),
+        remote_prekey: hex::encode(their_spk),
+        consumed: Vec::new(),
+    }
+}
+
+// Responder side of X3DH, run when a message carrying an unknown `X3dhInit`
+// arrives. The caller is responsible for checking the init's signature.
+pub fn respond(ik: &StaticSecret, spk: &StaticSecret, init: &X3dhInit) -> Result<Session, String> {
+    let their_ik = decode_key(&init.bundle.ik)?;
+    let their_ek = decode_key(&init.ek)?;
+
+    let sk = x3dh_kdf(&dh(spk, &their_ik), &dh(ik, &their_ek), &dh(spk, &their_ek));
+
+    let mut ad = their_ik.to_vec();
+    ad.extend_from_slice(PublicKey::from(ik).as_bytes());
+
+    Ok(Session {
+        dhs: spk.to_bytes(),
+        dhr: None,
+        rk: sk,
+        cks: None,
+        ckr: None,
+        ns: 0,
+        nr: 0,
+        pn: 0,
+        skipped: Vec::new(),
+        ad,
+        ek: init.ek.clone(),
+        pending_init: None,
+        unconfirmed: Vec::new(),
+        remote_identity: init.bundle.ik.clone(),
+        remote_prekey: init.bundle.spk.clone(),
+        consumed: Vec::new(),
+    })
+}
+
+impl Session {
+    // The responder can only send once the initiator's first message arrived.
+    pub fn can_send(&self) -> bool {
+        self.cks.is_some()
+    }
+
+    // Whether anything from the peer was read on this session.
+    pub fn has_received(&self) -> bool {
+        self.ckr.is_some()
+    }
+
+    // Takes over the ephemeral keys `previous` and the sessions before it used.
+    pub fn replaces(&mut self, previous: &Session) {
+        self.consumed = previous.consumed.clone();
+        for ek in [&previous.ek, &self.ek] {
+            if !self.consumed.contains(ek) {
+                self.consumed.push(ek.clone());
+            }
+        }
+    }
+
+    // Whether the session runs against these keys of the peer.
+    pub fn is_with(&self, their_ik: &[u8; 32], their_spk: &[u8; 32]) -> bool {
+        self.remote_identity == hex::encode(their_ik) && self.remote_prekey == hex::encode(their_spk)
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Encrypted, String> {
+        let cks = self.cks.ok_or_else(|| "Session is not ready to send yet".to_string())?;
+        let (next_ck, mk) = kdf_ck(&cks);
+
+        let header = Header {
+            dh: hex::encode(PublicKey::from(&StaticSecret::from(self.dhs)).as_bytes()),
+            pn: self.pn,
+            n: self.ns,
+        };
+        let ciphertext = seal(&mk, plaintext, &associated_data(&self.ad, &header)?)?;
+
+        self.cks = Some(next_ck);
+        self.ns += 1;
+        if self.pending_init.is_some() && self.unconfirmed.len() < MAX_UNCONFIRMED {
+            self.unconfirmed.push(plaintext.to_vec());
+        }
+
+        Ok(Encrypted { header, init: self.pending_init.clone(), ciphertext })
+    }
+
+    // Leaves the session untouched if the message can't be decrypted, so a
+    // forged or corrupted message can't desynchronize the ratchet.
+    pub fn decrypt(&mut self, message: &Encrypted) -> Result<Vec<u8>, String> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(message)?;
+        next.pending_init = None;
+        next.unconfirmed.clear();
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(&mut self, message: &Encrypted) -> Result<Vec<u8>, String> {
+        let header = &message.header;
+        let aad = associated_data(&self.ad, header)?;
+
+        if let Some(pos) = self.skipped.iter().position(|k| k.dh == header.dh && k.n == header.n) {
+            let skipped = self.skipped.remove(pos);
+            return open(&skipped.mk, &message.ciphertext, &aad);
+        }
+
+        let header_dh = decode_key(&header.dh)?;
+        if self.dhr != Some(header_dh) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(header_dh);
+        }
+
+        self.skip_message_keys(header.n)?;
+        let ckr = self.ckr.ok_or_else(|| "No receiving chain".to_string())?;
+        let (next_ck, mk) = kdf_ck(&ckr);
+        self.ckr = Some(next_ck);
+        self.nr += 1;
+
+        open(&mk, &message.ciphertext, &aad)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), String> {
+        if self.nr + MAX_SKIP < until {
+            return Err("Too many skipped messages".to_string());
+        }
+        if let (Some(mut ckr), Some(dhr)) = (self.ckr, self.dhr) {
+            while self.nr < until {
+                let (next_ck, mk) = kdf_ck(&ckr);
+                self.skipped.push(SkippedKey { dh: hex::encode(dhr), n: self.nr, mk });
+                ckr = next_ck;
+                self.nr += 1;
+            }
+            self.ckr = Some(ckr);
+        }
+        // Keep the store bounded; the oldest keys are the least likely to be needed
+        let excess = self.skipped.len().saturating_sub(MAX_SKIP as usize);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header_dh: [u8; 32]) {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dhr = Some(header_dh);
+
+        let (rk, ckr) = kdf_rk(&self.rk, &dh(&StaticSecret::from(self.dhs), &header_dh));
+        let dhs = StaticSecret::random_from_rng(OsRng);
+        let (rk, cks) = kdf_rk(&rk, &dh(&dhs, &header_dh));
+
+        self.rk = rk;
+        self.ckr = Some(ckr);
+        self.cks = Some(cks);
+        self.dhs = dhs.to_bytes();
+    }
+}
+
+const SESSIONS_FILE: &str = "sessions.bin";
+
+pub fn load_sessions(vault: &Vault) -> Result<HashMap<String, Session>, Box<dyn Error>> {
+    match vault.read(SESSIONS_FILE)? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+pub fn save_sessions(vault: &Vault, sessions: &HashMap<String, Session>) -> Result<(), Box<dyn Error>> {
+    vault.write(SESSIONS_FILE, &serde_json::to_vec(sessions)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(ik: &StaticSecret, spk: &StaticSecret) -> Bundle {
+        Bundle {
+            identity_key: String::new(),
+            ik: hex::encode(PublicKey::from(ik).as_bytes()),
+            spk: hex::encode(PublicKey::from(spk).as_bytes()),
+            signature: String::new(),
+        }
+    }
+
+    // Alice answers Bob's handshake, Bob sets up his side from her first message.
+    fn pair() -> (Session, Session) {
+        let (alice_ik, alice_spk) = (StaticSecret::random_from_rng(OsRng), StaticSecret::random_from_rng(OsRng));
+        let (bob_ik, bob_spk) = (StaticSecret::random_from_rng(OsRng), StaticSecret::random_from_rng(OsRng));
+
+        let mut alice = initiate(
+            &alice_ik,
+            PublicKey::from(&bob_ik).as_bytes(),
+            PublicKey::from(&bob_spk).as_bytes(),
+            bundle(&alice_ik, &alice_spk),
+        );
+        let first = alice.encrypt(b"init").unwrap();
+        let mut bob = respond(&bob_ik, &bob_spk, first.init.as_ref().unwrap()).unwrap();
+        assert_eq!(bob.decrypt(&first).unwrap(), b"init");
+        (alice, bob)
+    }
+
+    #[test]
+    fn messages_arrive_in_both_directions() {
+        let (mut alice, mut bob) = pair();
+        let reply = bob.encrypt(b"hi alice").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"hi alice");
+        assert!(alice.pending_init.is_none());
+        assert!(alice.unconfirmed.is_empty());
+
+        let message = alice.encrypt(b"hi bob").unwrap();
+        assert!(message.init.is_none());
+        assert_eq!(bob.decrypt(&message).unwrap(), b"hi bob");
+    }
+
+    #[test]
+    fn out_of_order_messages_are_read() {
+        let (mut alice, mut bob) = pair();
+        let messages: Vec<Encrypted> = (0..3).map(|i| alice.encrypt(&[i]).unwrap()).collect();
+        assert_eq!(bob.decrypt(&messages[2]).unwrap(), [2]);
+        assert_eq!(bob.decrypt(&messages[0]).unwrap(), [0]);
+        assert_eq!(bob.decrypt(&messages[1]).unwrap(), [1]);
+        // Each key only opens its message once
+        assert!(bob.decrypt(&messages[1]).is_err());
+    }
+
+    #[test]
+    fn forged_message_leaves_the_session_intact() {
+        let (mut alice, mut bob) = pair();
+        let message = alice.encrypt(b"hello").unwrap();
+
+        let mut forged = message.clone();
+        forged.header.n += 5;
+        assert!(bob.decrypt(&forged).is_err());
+        assert!(bob.skipped.is_empty());
+        assert_eq!(bob.decrypt(&message).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn initiator_keeps_what_it_sent_until_it_hears_back() {
+        let (mut alice, _) = pair();
+        alice.encrypt(b"one").unwrap();
+        assert_eq!(alice.unconfirmed, vec![b"init".to_vec(), b"one".to_vec()]);
+    }
+}
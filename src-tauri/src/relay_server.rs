@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use libp2p::{
+    autonat, gossipsub, identify, kad, noise, relay, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux,
+    futures::StreamExt, Multiaddr, PeerId,
+};
+use tokio::select;
+use crate::discovery;
+use crate::keystore::KeyStore;
+use crate::settings::GossipsubSettings;
+use crate::spam::{Validator, PUBLIC_TOPIC};
+
+// Headless node for a VPS: `<app> relay [options]`. It runs no window and
+// keeps no chat state, it only helps the apps find and reach each other:
+//  - Circuit Relay v2 server, so apps behind NAT can reserve a slot
+//  - Kademlia in server mode, to be listed as a bootstrap node
+//  - gossipsub router, joining the app's topics its peers are subscribed to
+//  - AutoNAT server, so apps can learn whether they're reachable
+
+const USAGE: &str = "Usage: relay [--identity <file>] [--listen <multiaddr>]... [--external <multiaddr>]... [--bootstrap <multiaddr>]...
+
+  --identity   Identity key file, created if missing (default: relay-identity.key)
+  --listen     Address to listen on (default: /ip4/0.0.0.0/tcp/4001 and /ip4/0.0.0.0/udp/4001/quic-v1)
+  --external   Publicly reachable address of this node, handed out in relay reservations
+  --bootstrap  Other DHT node to join, ending in /p2p/<peer id>";
+
+// Most topics we route at once
+const MAX_TOPICS: usize = 10_000;
+
+// The public channel and the inbox and group topics of the app, nothing else
+// is worth joining.
+fn is_routable(topic: &gossipsub::TopicHash) -> bool {
+    let topic = topic.as_str();
+    topic == PUBLIC_TOPIC || topic.starts_with("inbox-") || topic.starts_with("group-")
+}
+
+struct RelayArgs {
+    identity: PathBuf,
+    listen: Vec<Multiaddr>,
+    external: Vec<Multiaddr>,
+    bootstrap: Vec<(PeerId, Multiaddr)>,
+}
+
+fn parse_args(args: &[String]) -> Result<RelayArgs, String> {
+    let mut parsed = RelayArgs {
+        identity: PathBuf::from("relay-identity.key"),
+        listen: Vec::new(),
+        external: Vec::new(),
+        bootstrap: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if flag == "--help" || flag == "-h" {
+            return Err(USAGE.to_string());
+        }
+        let value = args.next().ok_or_else(|| format!("{} needs a value\n\n{}", flag, USAGE))?;
+        let multiaddr = || value.parse::<Multiaddr>().map_err(|e| format!("Invalid multiaddr {}: {}", value, e));
+        match flag.as_str() {
+            "--identity" => parsed.identity = PathBuf::from(value),
+            "--listen" => parsed.listen.push(multiaddr()?),
+            "--external" => parsed.external.push(multiaddr()?),
+            "--bootstrap" => parsed.bootstrap.push(discovery::parse_bootstrap_node(value)?),
+            _ => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+
+    if parsed.listen.is_empty() {
+        parsed.listen.push("/ip4/0.0.0.0/tcp/4001".parse().unwrap());
+        parsed.listen.push("/ip4/0.0.0.0/udp/4001/quic-v1".parse().unwrap());
+    }
+    Ok(parsed)
+}
+
+#[derive(NetworkBehaviour)]
+struct RelayBehaviour {
+    relay: relay::Behaviour,
+    kad: kad::Behaviour<kad::store::MemoryStore>,
+    identify: identify::Behaviour,
+    gossipsub: gossipsub::Behaviour,
+    autonat: autonat::Behaviour,
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = parse_args(args)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(run_relay_node(args))
+}
+
+async fn run_relay_node(args: RelayArgs) -> Result<(), Box<dyn Error>> {
+    let dir = args.identity.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file_name = args.identity.file_name().and_then(|name| name.to_str()).ok_or("Invalid identity file name")?;
+    let local_key = crate::node::load_or_generate_keypair(&KeyStore::plain(dir), file_name)?;
+
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_behaviour(|key| {
+            let peer_id = key.public().to_peer_id();
+            let mut kad = discovery::build_kad(peer_id);
+            // Always answer DHT queries, we're here to be a bootstrap node
+            kad.set_mode(Some(kad::Mode::Server));
+
+            Ok(RelayBehaviour {
+                relay: relay::Behaviour::new(peer_id, relay::Config::default()),
+                kad,
+                identify: discovery::build_identify(key),
+                gossipsub: crate::node::build_gossipsub(key, &GossipsubSettings::default())?,
+                autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+            })
+        })?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+
+    let local_peer_id = *swarm.local_peer_id();
+    println!("Relay Peer ID: {}", local_peer_id);
+
+    for addr in args.listen {
+        swarm.listen_on(addr)?;
+    }
+    for addr in args.external {
+        println!("Announcing external address {}/p2p/{}", addr, local_peer_id);
+        swarm.add_external_address(addr);
+    }
+    for (peer_id, addr) in args.bootstrap {
+        swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+    }
+    let _ = swarm.behaviour_mut().kad.bootstrap();
+
+    // We can't read what we route, but can hold it to the size and rate limits
+    let mut validator = Validator::new(&GossipsubSettings::default());
+
+    // Drops topics none of our peers care about anymore
+    let mut topic_sweep = tokio::time::interval(Duration::from_secs(60));
+
+    loop {
+        select! {
+            _ = topic_sweep.tick() => {
+                let wanted: HashSet<gossipsub::TopicHash> = swarm.behaviour().gossipsub.all_peers()
+                    .flat_map(|(_, topics)| topics.into_iter().cloned())
+                    .collect();
+                let stale: Vec<gossipsub::TopicHash> = swarm.behaviour().gossipsub.topics()
+                    .filter(|t| !wanted.contains(*t))
+                    .cloned()
+                    .collect();
+                for topic in stale {
+                    let _ = swarm.behaviour_mut().gossipsub.unsubscribe(&gossipsub::IdentTopic::new(topic.into_string()));
+                }
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    println!("Listening on {}/p2p/{}", address, local_peer_id);
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    println!("Connected to {} via {}", peer_id, endpoint.get_remote_address());
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    println!("Disconnected from {}", peer_id);
+                }
+                // Only peers that speak our DHT protocol go into the routing table
+                SwarmEvent::Behaviour(RelayBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. }))
+                    if info.protocols.contains(&discovery::KAD_PROTOCOL) =>
+                {
+                    for addr in info.listen_addrs {
+                        swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                    }
+                }
+                // Route every topic of ours a peer is interested in
+                SwarmEvent::Behaviour(RelayBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { topic, .. })) => {
+                    if !is_routable(&topic) || swarm.behaviour().gossipsub.topics().any(|t| *t == topic) {
+                        continue;
+                    }
+                    if swarm.behaviour().gossipsub.topics().count() >= MAX_TOPICS {
+                        eprintln!("Not joining {}, already routing {} topics", topic, MAX_TOPICS);
+                        continue;
+                    }
+                    if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(topic.into_string())) {
+                        eprintln!("Subscribe error: {:?}", e);
+                    }
+                }
+                SwarmEvent::Behaviour(RelayBehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message })) => {
+                    let acceptance = if !validator.check(&message) {
+                        gossipsub::MessageAcceptance::Reject
+                    } else if !validator.allow(&message) {
+                        gossipsub::MessageAcceptance::Ignore
+                    } else {
+                        gossipsub::MessageAcceptance::Accept
+                    };
+                    let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance);
+                }
+                SwarmEvent::Behaviour(RelayBehaviourEvent::Relay(event)) => match event {
+                    relay::Event::ReservationReqAccepted { src_peer_id, renewed: false } => {
+                        println!("Reservation accepted for {}", src_peer_id);
+                    }
+                    relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                        println!("Relaying {} -> {}", src_peer_id, dst_peer_id);
+                    }
+                    relay::Event::ReservationTimedOut { src_peer_id } => {
+                        println!("Reservation of {} timed out", src_peer_id);
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+}
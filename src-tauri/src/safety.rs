@@ -0,0 +1,91 @@
+use qrcode::{render::svg, QrCode};
+use sha2::{Digest, Sha512};
+
+// Safety numbers, to check out of band that the keys we hold for a peer are
+// really theirs. Each side's half is a fingerprint of its PeerId (which
+// carries its Ed25519 identity key) and its X25519 identity key. The halves
+// are put in PeerId order, so both peers see the same 60 digits.
+//
+// The QR payload carries both fingerprints as its sender computed them:
+// `phantom-verify:1:<sender PeerId>:<sender's half>:<receiver's half>`.
+
+const FINGERPRINT_VERSION: u8 = 0;
+const ITERATIONS: usize = 5200;
+const QR_PREFIX: &str = "phantom-verify:1";
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SafetyNumber {
+    pub peer_id: String,
+    // 12 groups of 5 digits
+    pub number: String,
+    // For the peer to scan or paste, see `qr_matches`
+    pub qr_payload: String,
+    pub qr_svg: String,
+    pub verified: bool,
+}
+
+// Iterated so that finding keys with a matching fingerprint is expensive.
+fn fingerprint(peer_id: &str, ik: &[u8; 32]) -> [u8; 30] {
+    let mut hash = Sha512::new()
+        .chain_update([FINGERPRINT_VERSION])
+        .chain_update(ik)
+        .chain_update(peer_id.as_bytes())
+        .finalize();
+    for _ in 1..ITERATIONS {
+        hash = Sha512::new().chain_update(hash).chain_update(ik).finalize();
+    }
+    let mut fingerprint = [0u8; 30];
+    fingerprint.copy_from_slice(&hash[..30]);
+    fingerprint
+}
+
+fn digits(fingerprint: &[u8; 30]) -> Vec<String> {
+    fingerprint
+        .chunks(5)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0u64, |n, b| (n << 8) | *b as u64);
+            format!("{:05}", n % 100_000)
+        })
+        .collect()
+}
+
+// `local` and `remote` are a PeerId and its X25519 identity key.
+pub fn safety_number(local: (&str, &[u8; 32]), remote: (&str, &[u8; 32]), verified: bool) -> Result<SafetyNumber, String> {
+    let ours = fingerprint(local.0, local.1);
+    let theirs = fingerprint(remote.0, remote.1);
+
+    let halves = if local.0 < remote.0 { [ours, theirs] } else { [theirs, ours] };
+    let number = halves.iter().flat_map(digits).collect::<Vec<_>>().join(" ");
+
+    let qr_payload = format!("{}:{}:{}:{}", QR_PREFIX, local.0, hex::encode(ours), hex::encode(theirs));
+    let qr_svg = QrCode::new(qr_payload.as_bytes())
+        .map_err(|e| e.to_string())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+
+    Ok(SafetyNumber { peer_id: remote.0.to_string(), number, qr_payload, qr_svg, verified })
+}
+
+// The PeerId of whoever showed the payload.
+pub fn qr_sender(payload: &str) -> Result<String, String> {
+    let rest = payload.trim().strip_prefix(QR_PREFIX).and_then(|rest| rest.strip_prefix(':'));
+    match rest.map(|rest| rest.split(':').collect::<Vec<_>>()).as_deref() {
+        Some([sender, _, _]) => Ok(sender.to_string()),
+        _ => Err("Not a safety number code".to_string()),
+    }
+}
+
+// Whether the payload `remote` showed us has the same keys on both sides as
+// we do.
+pub fn qr_matches(payload: &str, local: (&str, &[u8; 32]), remote: (&str, &[u8; 32])) -> bool {
+    let expected = format!(
+        "{}:{}:{}:{}",
+        QR_PREFIX,
+        remote.0,
+        hex::encode(fingerprint(remote.0, remote.1)),
+        hex::encode(fingerprint(local.0, local.1)),
+    );
+    payload.trim() == expected
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use libp2p::{gossipsub, multiaddr::Protocol, Multiaddr};
+
+// Node settings kept in `settings.json` in the data dir. Unlike the key
+// and state files this is plain JSON, so it can be edited by hand; missing
+// fields fall back to their defaults.
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Quic,
+    Tcp,
+}
+
+impl Transport {
+    pub fn of(addr: &Multiaddr) -> Option<Transport> {
+        addr.iter().find_map(|p| match p {
+            Protocol::QuicV1 => Some(Transport::Quic),
+            Protocol::Tcp(_) => Some(Transport::Tcp),
+            _ => None,
+        })
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    // We always listen on both transports, this only decides which one is
+    // tried first when a peer is reachable over both
+    pub preferred_transport: Transport,
+    // Full multiaddrs (`.../p2p/<peer id>`) of DHT nodes to join through
+    pub bootstrap_nodes: Vec<String>,
+    // Full multiaddr (`.../p2p/<peer id>`) of a circuit relay to reserve a slot on
+    pub relay: Option<String>,
+    // Only read when the node starts, `set_settings` tells when a change needs a restart
+    pub gossipsub: GossipsubSettings,
+}
+
+// Mesh sizes, limits and peer scoring of the gossipsub router. Scoring
+// follows gossipsub v1.1: a peer whose score drops below the thresholds
+// stops getting gossip from us, then our own messages, and at the graylist
+// threshold everything it sends is ignored.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GossipsubSettings {
+    pub heartbeat_secs: u64,
+    // Peers we keep in the mesh of each topic: aim for `mesh_n`, graft below
+    // `mesh_n_low` and prune above `mesh_n_high`
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    pub mesh_outbound_min: usize,
+    // Largest message we send or accept, in bytes
+    pub max_transmit_size: usize,
+    // Largest message we accept on `phantom-global`, which anyone can post to
+    pub max_public_message_size: usize,
+    // Messages each author may get through to us on `phantom-global`, and
+    // on inbox and group topics
+    pub public_rate_limit: RateLimit,
+    pub private_rate_limit: RateLimit,
+    pub scoring: bool,
+    pub thresholds: ScoreThresholds,
+    // Per topic name; topics not listed only get the peer-wide penalties
+    pub topics: HashMap<String, TopicScore>,
+    // Penalty for many peers sharing one IP, above the threshold
+    pub ip_colocation_factor_weight: f64,
+    pub ip_colocation_factor_threshold: f64,
+    // Penalty for protocol misbehaviour such as broken promises
+    pub behaviour_penalty_weight: f64,
+    // Weight of the score the app itself gives a peer
+    pub app_specific_weight: f64,
+}
+
+impl Default for GossipsubSettings {
+    fn default() -> Self {
+        let peer_score = gossipsub::PeerScoreParams::default();
+        GossipsubSettings {
+            heartbeat_secs: 10,
+            mesh_n: 6,
+            mesh_n_low: 4,
+            mesh_n_high: 12,
+            mesh_outbound_min: 2,
+            max_transmit_size: 65536,
+            max_public_message_size: 8192,
+            public_rate_limit: RateLimit { burst: 10, per_minute: 30 },
+            private_rate_limit: RateLimit { burst: 100, per_minute: 600 },
+            scoring: true,
+            thresholds: ScoreThresholds::default(),
+            topics: HashMap::from([("phantom-global".to_string(), TopicScore::default())]),
+            ip_colocation_factor_weight: peer_score.ip_colocation_factor_weight,
+            ip_colocation_factor_threshold: peer_score.ip_colocation_factor_threshold,
+            behaviour_penalty_weight: peer_score.behaviour_penalty_weight,
+            app_specific_weight: peer_score.app_specific_weight,
+        }
+    }
+}
+
+impl GossipsubSettings {
+    pub fn score_params(&self) -> gossipsub::PeerScoreParams {
+        gossipsub::PeerScoreParams {
+            topics: self.topics.iter()
+                .map(|(topic, score)| (gossipsub::IdentTopic::new(topic).hash(), score.params()))
+                .collect(),
+            ip_colocation_factor_weight: self.ip_colocation_factor_weight,
+            ip_colocation_factor_threshold: self.ip_colocation_factor_threshold,
+            behaviour_penalty_weight: self.behaviour_penalty_weight,
+            app_specific_weight: self.app_specific_weight,
+            ..Default::default()
+        }
+    }
+}
+
+// A token bucket: `burst` messages at once, refilled at `per_minute`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScoreThresholds {
+    // Below this we stop gossiping with the peer
+    pub gossip: f64,
+    // Below this we don't publish our own messages to the peer
+    pub publish: f64,
+    // Below this we ignore the peer entirely
+    pub graylist: f64,
+    // Above this we accept peers the peer suggests when it prunes us
+    pub accept_px: f64,
+    // Median mesh score under which we graft better scoring peers
+    pub opportunistic_graft: f64,
+}
+
+impl Default for ScoreThresholds {
+    fn default() -> Self {
+        let thresholds = gossipsub::PeerScoreThresholds::default();
+        ScoreThresholds {
+            gossip: thresholds.gossip_threshold,
+            publish: thresholds.publish_threshold,
+            graylist: thresholds.graylist_threshold,
+            accept_px: thresholds.accept_px_threshold,
+            opportunistic_graft: thresholds.opportunistic_graft_threshold,
+        }
+    }
+}
+
+impl ScoreThresholds {
+    pub fn params(&self) -> gossipsub::PeerScoreThresholds {
+        gossipsub::PeerScoreThresholds {
+            gossip_threshold: self.gossip,
+            publish_threshold: self.publish,
+            graylist_threshold: self.graylist,
+            accept_px_threshold: self.accept_px,
+            opportunistic_graft_threshold: self.opportunistic_graft,
+        }
+    }
+}
+
+// Chat traffic is sparse, so the defaults reward time in the mesh and first
+// deliveries, punish invalid messages hard, and leave out the penalty for
+// too few mesh deliveries that suits busy topics.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TopicScore {
+    pub topic_weight: f64,
+    pub time_in_mesh_weight: f64,
+    pub time_in_mesh_quantum_secs: u64,
+    pub time_in_mesh_cap: f64,
+    pub first_message_deliveries_weight: f64,
+    pub first_message_deliveries_decay: f64,
+    pub first_message_deliveries_cap: f64,
+    // Negative, or zero to disable
+    pub mesh_message_deliveries_weight: f64,
+    pub mesh_message_deliveries_threshold: f64,
+    pub invalid_message_deliveries_weight: f64,
+    pub invalid_message_deliveries_decay: f64,
+}
+
+impl Default for TopicScore {
+    fn default() -> Self {
+        TopicScore {
+            topic_weight: 1.0,
+            time_in_mesh_weight: 0.01,
+            time_in_mesh_quantum_secs: 1,
+            time_in_mesh_cap: 600.0,
+            first_message_deliveries_weight: 1.0,
+            first_message_deliveries_decay: 0.9,
+            first_message_deliveries_cap: 20.0,
+            mesh_message_deliveries_weight: 0.0,
+            mesh_message_deliveries_threshold: 1.0,
+            invalid_message_deliveries_weight: -20.0,
+            invalid_message_deliveries_decay: 0.99,
+        }
+    }
+}
+
+impl TopicScore {
+    fn params(&self) -> gossipsub::TopicScoreParams {
+        gossipsub::TopicScoreParams {
+            topic_weight: self.topic_weight,
+            time_in_mesh_weight: self.time_in_mesh_weight,
+            time_in_mesh_quantum: Duration::from_secs(self.time_in_mesh_quantum_secs),
+            time_in_mesh_cap: self.time_in_mesh_cap,
+            first_message_deliveries_weight: self.first_message_deliveries_weight,
+            first_message_deliveries_decay: self.first_message_deliveries_decay,
+            first_message_deliveries_cap: self.first_message_deliveries_cap,
+            mesh_message_deliveries_weight: self.mesh_message_deliveries_weight,
+            mesh_message_deliveries_threshold: self.mesh_message_deliveries_threshold,
+            // Sticky only when the mesh delivery penalty applies
+            mesh_failure_penalty_weight: self.mesh_message_deliveries_weight,
+            invalid_message_deliveries_weight: self.invalid_message_deliveries_weight,
+            invalid_message_deliveries_decay: self.invalid_message_deliveries_decay,
+            ..Default::default()
+        }
+    }
+}
+
+impl Settings {
+    // Orders `addrs` so that the preferred transport comes first.
+    pub fn sort_addresses(&self, addrs: &mut [Multiaddr]) {
+        addrs.sort_by_key(|a| Transport::of(a) != Some(self.preferred_transport));
+    }
+}
+
+const SETTINGS_FILE: &str = "settings.json";
+
+pub fn load_settings(dir: &Path) -> Result<Settings, Box<dyn Error>> {
+    let path = dir.join(SETTINGS_FILE);
+    if !path.exists() {
+        let settings = Settings::default();
+        save_settings(dir, &settings)?;
+        return Ok(settings);
+    }
+    Ok(serde_json::from_slice(&fs::read(&path)?)?)
+}
+
+pub fn save_settings(dir: &Path, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    fs::write(dir.join(SETTINGS_FILE), serde_json::to_vec_pretty(settings)?)?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use libp2p::{gossipsub, PeerId};
+use crate::settings::{GossipsubSettings, RateLimit};
+
+// Spam protection for gossipsub. The router is built with
+// `validate_messages`, so it holds every message until we report on it with
+// `report_message_validation_result`: accepted ones are forwarded and handed
+// on, rejected ones count as invalid deliveries against the peer that passed
+// them to us, and ignored ones are dropped without a penalty. Oversized and
+// malformed messages are rejected; authors over their rate limit are
+// ignored, since honest peers relaying a flood shouldn't lose score for it.
+
+pub const PUBLIC_TOPIC: &str = "phantom-global";
+
+// Buckets kept before idle ones are dropped
+const MAX_TRACKED: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute as f64 / 60.0).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+pub struct Validator {
+    max_public_size: usize,
+    public_limit: RateLimit,
+    private_limit: RateLimit,
+    // Per author, and whether it's for the public topic
+    buckets: HashMap<(PeerId, bool), Bucket>,
+}
+
+impl Validator {
+    pub fn new(settings: &GossipsubSettings) -> Self {
+        Validator {
+            max_public_size: settings.max_public_message_size,
+            public_limit: settings.public_rate_limit,
+            private_limit: settings.private_rate_limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // What any router can tell without knowing the topic's format: public
+    // messages have to be non-empty UTF-8 within the public size limit, and
+    // every message needs a signed author.
+    pub fn check(&self, message: &gossipsub::Message) -> bool {
+        if message.source.is_none() {
+            return false;
+        }
+        !is_public(&message.topic)
+            || (!message.data.is_empty()
+                && message.data.len() <= self.max_public_size
+                && std::str::from_utf8(&message.data).is_ok())
+    }
+
+    // Takes a token from the author's bucket for the message's topic. False
+    // if there is none left.
+    pub fn allow(&mut self, message: &gossipsub::Message) -> bool {
+        let Some(source) = message.source else { return false };
+        let public = is_public(&message.topic);
+        let limit = self.limit(public);
+        let now = Instant::now();
+
+        // Full buckets are as good as new ones
+        if self.buckets.len() >= MAX_TRACKED && !self.buckets.contains_key(&(source, public)) {
+            let limits = (self.limit(true), self.limit(false));
+            self.buckets.retain(|(_, public), bucket| {
+                let limit = if *public { limits.0 } else { limits.1 };
+                bucket.refill(limit, now);
+                bucket.tokens < limit.burst as f64
+            });
+        }
+
+        let bucket = self.buckets.entry((source, public)).or_insert(Bucket { tokens: limit.burst as f64, updated: now });
+        bucket.refill(limit, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn limit(&self, public: bool) -> RateLimit {
+        if public { self.public_limit } else { self.private_limit }
+    }
+}
+
+pub fn is_public(topic: &gossipsub::TopicHash) -> bool {
+    topic.as_str() == PUBLIC_TOPIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str, data: &[u8]) -> gossipsub::Message {
+        gossipsub::Message {
+            source: Some(PeerId::random()),
+            data: data.to_vec(),
+            sequence_number: Some(1),
+            topic: gossipsub::IdentTopic::new(topic).hash(),
+        }
+    }
+
+    #[test]
+    fn public_messages_must_be_short_text() {
+        let settings = GossipsubSettings::default();
+        let validator = Validator::new(&settings);
+        assert!(validator.check(&message(PUBLIC_TOPIC, "привет".as_bytes())));
+        assert!(!validator.check(&message(PUBLIC_TOPIC, b"")));
+        assert!(!validator.check(&message(PUBLIC_TOPIC, &[0xff, 0xfe])));
+        assert!(!validator.check(&message(PUBLIC_TOPIC, &vec![b'a'; settings.max_public_message_size + 1])));
+    }
+
+    #[test]
+    fn private_topics_carry_any_bytes_but_need_an_author() {
+        let validator = Validator::new(&GossipsubSettings::default());
+        assert!(validator.check(&message("inbox-peer", &[0xff, 0xfe])));
+
+        let mut anonymous = message("inbox-peer", b"sealed");
+        anonymous.source = None;
+        assert!(!validator.check(&anonymous));
+    }
+
+    #[test]
+    fn authors_are_limited_to_their_burst() {
+        let settings = GossipsubSettings::default();
+        let mut validator = Validator::new(&settings);
+        let first = message(PUBLIC_TOPIC, b"hi");
+        let source = first.source;
+
+        for _ in 0..settings.public_rate_limit.burst {
+            assert!(validator.allow(&first));
+        }
+        assert!(!validator.allow(&first));
+
+        // The private bucket is separate
+        let mut private = message("inbox-peer", b"sealed");
+        private.source = source;
+        assert!(validator.allow(&private));
+        // And so is everyone else's
+        assert!(validator.allow(&message(PUBLIC_TOPIC, b"hi")));
+    }
+}
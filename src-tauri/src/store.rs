@@ -0,0 +1,496 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+// Message history and contacts, kept in `messages.db` in the data dir. The
+// node writes everything it sends and receives, so the history is there even
+// when no UI is attached. The database is encrypted with SQLCipher under a
+// key derived from the vault's. The schema version lives in
+// `PRAGMA user_version`: `MIGRATIONS[n]` takes the database from version n
+// to n + 1.
+
+pub const STORE_FILE: &str = "messages.db";
+
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+const MIGRATIONS: &[&str] = &[
+    // 1: messages and contacts as the frontend used to keep them
+    "CREATE TABLE messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        uuid TEXT NOT NULL UNIQUE,
+        sender TEXT NOT NULL,
+        content TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        reply_to TEXT,
+        kind TEXT NOT NULL DEFAULT 'text',
+        status TEXT NOT NULL DEFAULT 'sent',
+        reactions TEXT NOT NULL DEFAULT '{}',
+        last_edited INTEGER,
+        file_name TEXT,
+        file_size TEXT
+    );
+    CREATE TABLE contacts (
+        peer_id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        added_at INTEGER NOT NULL
+    );",
+    // 2: history is always read per channel, newest first
+    "CREATE INDEX messages_channel_timestamp ON messages (channel, timestamp);",
+    // 3: the X25519 identity key last seen for each peer, and whether the user verified it
+    "CREATE TABLE peer_keys (
+        peer_id TEXT PRIMARY KEY,
+        identity_key TEXT NOT NULL,
+        verified INTEGER NOT NULL DEFAULT 0,
+        updated_at INTEGER NOT NULL
+    );",
+];
+
+// Shaped like the frontend's `DBMessage`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredMessage {
+    pub uuid: String,
+    // PeerId of the author, ours for messages we sent
+    pub sender: String,
+    pub content: String,
+    // "phantom-global", a group id or the other peer's PeerId
+    pub channel: String,
+    pub timestamp: i64,
+    // JSON of the quoted message
+    pub reply_to: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub status: String,
+    // JSON of emoji -> PeerIds that reacted with it
+    pub reactions: String,
+    pub last_edited: Option<i64>,
+    pub file_name: Option<String>,
+    pub file_size: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+    pub peer_id: String,
+    pub name: String,
+    pub added_at: i64,
+    // The user compared safety numbers for the peer's current key
+    pub verified: bool,
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+impl StoredMessage {
+    // Reads a chat payload as the UI sends it: either plain text or JSON with
+    // `type`, `content` (or `text`), `uuid`, `replyTo`, `fileName` and `fileSize`.
+    pub fn from_payload(sender: &str, channel: &str, payload: &str, message_id: Option<&str>, status: &str) -> Self {
+        let json = serde_json::from_str::<serde_json::Value>(payload).ok().filter(|v| v.is_object());
+        let field = |name: &str| json.as_ref().and_then(|v| v.get(name)).filter(|v| !v.is_null());
+        let text = |name: &str| field(name).and_then(|v| v.as_str()).map(str::to_string);
+
+        let content = match (&json, text("content").or_else(|| text("text"))) {
+            (Some(_), Some(content)) => content,
+            _ => payload.to_string(),
+        };
+        let uuid = text("uuid")
+            .or_else(|| message_id.map(str::to_string))
+            .unwrap_or_else(|| {
+                let mut id = [0u8; 16];
+                OsRng.fill_bytes(&mut id);
+                hex::encode(id)
+            });
+
+        StoredMessage {
+            uuid,
+            sender: sender.to_string(),
+            content,
+            channel: channel.to_string(),
+            timestamp: now_millis(),
+            reply_to: field("replyTo").map(|v| v.to_string()),
+            kind: text("type").unwrap_or_else(|| "text".to_string()),
+            status: status.to_string(),
+            reactions: "{}".to_string(),
+            last_edited: None,
+            file_name: text("fileName"),
+            file_size: text("fileSize"),
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(StoredMessage {
+            uuid: row.get("uuid")?,
+            sender: row.get("sender")?,
+            content: row.get("content")?,
+            channel: row.get("channel")?,
+            timestamp: row.get("timestamp")?,
+            reply_to: row.get("reply_to")?,
+            kind: row.get("kind")?,
+            status: row.get("status")?,
+            reactions: row.get("reactions")?,
+            last_edited: row.get("last_edited")?,
+            file_name: row.get("file_name")?,
+            file_size: row.get("file_size")?,
+        })
+    }
+}
+
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: &Path, key: &[u8; 32]) -> Result<Self, Box<dyn Error>> {
+        if is_plaintext(path)? {
+            // Written before the store was encrypted
+            encrypt_plain(path, key)?;
+        }
+        let mut conn = open_encrypted(path, key)?;
+        // Fails here if the key is wrong or the file isn't a database
+        conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))?;
+        migrate(&mut conn)?;
+        Ok(Store { conn: Mutex::new(conn) })
+    }
+
+    // Does nothing if a message with the same uuid is already stored.
+    // False if a message with this uuid was already there.
+    pub fn insert_message(&self, msg: &StoredMessage) -> rusqlite::Result<bool> {
+        let inserted = self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO messages (uuid, sender, content, channel, timestamp, reply_to, kind, status, reactions, last_edited, file_name, file_size)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                msg.uuid, msg.sender, msg.content, msg.channel, msg.timestamp, msg.reply_to,
+                msg.kind, msg.status, msg.reactions, msg.last_edited, msg.file_name, msg.file_size,
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    pub fn sender_of(&self, uuid: &str) -> rusqlite::Result<Option<String>> {
+        self.conn.lock().unwrap()
+            .query_row("SELECT sender FROM messages WHERE uuid = ?1", [uuid], |row| row.get(0))
+            .optional()
+    }
+
+    pub fn channel_of(&self, uuid: &str) -> rusqlite::Result<Option<String>> {
+        self.conn.lock().unwrap()
+            .query_row("SELECT channel FROM messages WHERE uuid = ?1", [uuid], |row| row.get(0))
+            .optional()
+    }
+
+    // The newest `limit` messages of `channel` older than `before`, oldest first.
+    pub fn history(&self, channel: &str, before: Option<i64>, limit: u32) -> rusqlite::Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM messages WHERE channel = ?1 AND timestamp < ?2 ORDER BY timestamp DESC, id DESC LIMIT ?3",
+        )?;
+        let mut messages = stmt
+            .query_map(params![channel, before.unwrap_or(i64::MAX), limit], StoredMessage::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    // The newest `limit` messages of all channels older than `before`, newest first.
+    pub fn messages_before(&self, before: Option<i64>, limit: u32) -> rusqlite::Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT * FROM messages WHERE timestamp < ?1 ORDER BY timestamp DESC, id DESC LIMIT ?2")?;
+        let messages = stmt
+            .query_map(params![before.unwrap_or(i64::MAX), limit], StoredMessage::from_row)?
+            .collect();
+        messages
+    }
+
+    // What `sender` wrote in `channel` since `since`, oldest first.
+    pub fn messages_by(&self, channel: &str, sender: &str, since: i64) -> rusqlite::Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM messages WHERE channel = ?1 AND sender = ?2 AND timestamp >= ?3 ORDER BY timestamp, id",
+        )?;
+        let messages = stmt
+            .query_map(params![channel, sender, since], StoredMessage::from_row)?
+            .collect();
+        messages
+    }
+
+    // Messages whose text contains `query`, in one channel or all of them, oldest first.
+    pub fn search(&self, query: &str, channel: Option<&str>, limit: u32) -> rusqlite::Result<Vec<StoredMessage>> {
+        let pattern = format!("%{}%", query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM messages WHERE content LIKE ?1 ESCAPE '\\' AND (?2 IS NULL OR channel = ?2)
+             ORDER BY timestamp DESC, id DESC LIMIT ?3",
+        )?;
+        let mut messages = stmt
+            .query_map(params![pattern, channel, limit], StoredMessage::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    // Receipts can arrive out of order, so a message never goes back from "read".
+    pub fn set_status(&self, uuid: &str, status: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE messages SET status = ?1 WHERE uuid = ?2 AND status != 'read'",
+            params![status, uuid],
+        )?;
+        Ok(())
+    }
+
+    pub fn edit_message(&self, uuid: &str, content: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE messages SET content = ?1, last_edited = ?2 WHERE uuid = ?3",
+            params![content, now_millis(), uuid],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_message(&self, uuid: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute("DELETE FROM messages WHERE uuid = ?1", [uuid])?;
+        Ok(())
+    }
+
+    // Adds `peer_id` to the `emoji` reactions of the message, or takes it back
+    // if it was already there.
+    pub fn toggle_reaction(&self, uuid: &str, emoji: &str, peer_id: &str) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some(reactions) = tx
+            .query_row("SELECT reactions FROM messages WHERE uuid = ?1", [uuid], |row| row.get::<_, String>(0))
+            .optional()?
+        else {
+            return Ok(());
+        };
+
+        let mut reactions: BTreeMap<String, Vec<String>> = serde_json::from_str(&reactions).unwrap_or_default();
+        let reactors = reactions.entry(emoji.to_string()).or_default();
+        if let Some(pos) = reactors.iter().position(|p| p == peer_id) {
+            reactors.remove(pos);
+            if reactors.is_empty() {
+                reactions.remove(emoji);
+            }
+        } else {
+            reactors.push(peer_id.to_string());
+        }
+
+        tx.execute(
+            "UPDATE messages SET reactions = ?1 WHERE uuid = ?2",
+            params![serde_json::to_string(&reactions).unwrap(), uuid],
+        )?;
+        tx.commit()
+    }
+
+    pub fn contacts(&self) -> rusqlite::Result<Vec<Contact>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT c.peer_id, c.name, c.added_at, COALESCE(k.verified, 0) FROM contacts c
+             LEFT JOIN peer_keys k ON k.peer_id = c.peer_id ORDER BY c.name ASC",
+        )?;
+        let contacts = stmt
+            .query_map([], |row| Ok(Contact { peer_id: row.get(0)?, name: row.get(1)?, added_at: row.get(2)?, verified: row.get(3)? }))?
+            .collect();
+        contacts
+    }
+
+    // Adds the contact, or renames it if it already exists.
+    pub fn save_contact(&self, peer_id: &str, name: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO contacts (peer_id, name, added_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer_id) DO UPDATE SET name = excluded.name",
+            params![peer_id, name, now_millis()],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_contact(&self, peer_id: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute("DELETE FROM contacts WHERE peer_id = ?1", [peer_id])?;
+        Ok(())
+    }
+
+    // Remembers `identity_key` for `peer_id`. If it replaces a different key,
+    // the verification is dropped and `Some(was_verified)` is returned.
+    pub fn record_peer_key(&self, peer_id: &str, identity_key: &str) -> rusqlite::Result<Option<bool>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let known: Option<(String, bool)> = tx.query_row(
+            "SELECT identity_key, verified FROM peer_keys WHERE peer_id = ?1",
+            [peer_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+
+        let change = match known {
+            Some((key, _)) if key == identity_key => return Ok(None),
+            Some((_, verified)) => Some(verified),
+            None => None,
+        };
+        tx.execute(
+            "INSERT INTO peer_keys (peer_id, identity_key, verified, updated_at) VALUES (?1, ?2, 0, ?3)
+             ON CONFLICT (peer_id) DO UPDATE SET identity_key = excluded.identity_key, verified = 0, updated_at = excluded.updated_at",
+            params![peer_id, identity_key, now_millis()],
+        )?;
+        tx.commit()?;
+        Ok(change)
+    }
+
+    // Whether the user verified `identity_key` as `peer_id`'s.
+    pub fn is_verified(&self, peer_id: &str, identity_key: &str) -> rusqlite::Result<bool> {
+        let verified = self.conn.lock().unwrap().query_row(
+            "SELECT verified FROM peer_keys WHERE peer_id = ?1 AND identity_key = ?2",
+            [peer_id, identity_key],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(verified.unwrap_or(false))
+    }
+
+    pub fn set_verified(&self, peer_id: &str, identity_key: &str, verified: bool) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO peer_keys (peer_id, identity_key, verified, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (peer_id) DO UPDATE SET identity_key = excluded.identity_key, verified = excluded.verified, updated_at = excluded.updated_at",
+            params![peer_id, identity_key, verified, now_millis()],
+        )?;
+        Ok(())
+    }
+
+    // Moves the messages and contacts the frontend used to keep in its own
+    // database (`phantom_chat.db`) over, then drops them there so this only
+    // happens once. Returns false if there was nothing to import.
+    pub fn import_legacy(&self, path: &Path) -> rusqlite::Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        // An empty key, the frontend's database isn't encrypted
+        conn.execute("ATTACH DATABASE ?1 AS legacy KEY ''", [path.to_string_lossy()])?;
+
+        let result = (|| {
+            let has_table = |conn: &Connection, name: &str| -> rusqlite::Result<bool> {
+                conn.query_row(
+                    "SELECT COUNT(*) FROM legacy.sqlite_master WHERE type = 'table' AND name = ?1",
+                    [name],
+                    |row| row.get::<_, i64>(0),
+                ).map(|count| count > 0)
+            };
+            let (messages, contacts) = (has_table(&conn, "messages")?, has_table(&conn, "contacts")?);
+            if !messages && !contacts {
+                return Ok(false);
+            }
+
+            let tx = conn.transaction()?;
+            if messages {
+                // The UI called the global channel "global-gossip"
+                tx.execute_batch(
+                    "INSERT OR IGNORE INTO messages (uuid, sender, content, channel, timestamp, reply_to, kind, status, reactions, last_edited, file_name, file_size)
+                     SELECT COALESCE(uuid, 'legacy-' || id), sender, content,
+                            CASE channel WHEN 'global-gossip' THEN 'phantom-global' ELSE channel END,
+                            timestamp, replyTo, COALESCE(type, 'text'), COALESCE(status, 'sent'),
+                            COALESCE(reactions, '{}'), lastEdited, fileName, fileSize
+                     FROM legacy.messages;
+                     DROP TABLE legacy.messages;",
+                )?;
+            }
+            if contacts {
+                tx.execute_batch(
+                    "INSERT OR IGNORE INTO contacts (peer_id, name, added_at)
+                     SELECT peerId, name, addedAt FROM legacy.contacts;
+                     DROP TABLE legacy.contacts;",
+                )?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })();
+
+        conn.execute("DETACH DATABASE legacy", [])?;
+        result
+    }
+}
+
+fn open_encrypted(path: &Path, key: &[u8; 32]) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", hex::encode(key)))?;
+    Ok(conn)
+}
+
+// A plaintext SQLite database starts with this header, an encrypted one
+// with random salt.
+fn is_plaintext(path: &Path) -> std::io::Result<bool> {
+    let mut header = [0u8; 16];
+    match fs::File::open(path).and_then(|mut file| file.read_exact(&mut header)) {
+        Ok(()) => Ok(&header == SQLITE_HEADER),
+        // Missing or too short to be a database yet
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::UnexpectedEof) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Rewrites a plaintext database at `path` encrypted under `key`.
+fn encrypt_plain(path: &Path, key: &[u8; 32]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = path.with_extension("db.tmp");
+    let _ = fs::remove_file(&tmp_path);
+    {
+        let conn = Connection::open(path)?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        conn.execute(
+            &format!("ATTACH DATABASE ?1 AS encrypted KEY \"x'{}'\"", hex::encode(key)),
+            [tmp_path.to_string_lossy()],
+        )?;
+        conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+        conn.execute_batch(&format!("PRAGMA encrypted.user_version = {}; DETACH DATABASE encrypted;", version))?;
+    }
+    fs::rename(&tmp_path, path)?;
+    println!("Encrypted message store at {:?}", path);
+    Ok(())
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        println!("Migrated message store to version {}", i + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("phantom-store-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(STORE_FILE)
+    }
+
+    #[test]
+    fn plaintext_store_is_encrypted_on_open() {
+        let path = temp_db("plain");
+        Connection::open(&path).unwrap()
+            .execute_batch("CREATE TABLE legacy (x INTEGER); INSERT INTO legacy VALUES (1);").unwrap();
+        assert!(is_plaintext(&path).unwrap());
+
+        let store = Store::open(&path, &[1; 32]).unwrap();
+        let rows: i64 = store.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM legacy", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 1);
+        drop(store);
+        assert!(!is_plaintext(&path).unwrap());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn wrong_key_is_an_error() {
+        let path = temp_db("key");
+        drop(Store::open(&path, &[1; 32]).unwrap());
+        assert!(Store::open(&path, &[2; 32]).is_err());
+        // Still opens with the right key, nothing was rewritten
+        assert!(Store::open(&path, &[1; 32]).is_ok());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,148 @@
+use libp2p::{request_response, StreamProtocol};
+use sha2::{Digest, Sha256};
+use crate::store::StoredMessage;
+
+// Backfill of what gossipsub lost while one of two contacts was offline.
+// When we connect to a peer we have a session with, we send it a summary of
+// every conversation we share over `/phantom/sync/1`: a bloom filter of the
+// ids of its messages we hold from the last `SYNC_WINDOW_MS`. The peer
+// answers with its own messages missing from the filter, sealed with the
+// ratchet session like anything sent to our inbox, as a
+// `P2PMessage::SyncBatch`. Everyone only hands out messages they wrote, so
+// nobody can slip words into someone else's mouth. A false positive of the
+// filter leaves a message out until the next sync.
+
+pub const SYNC_PROTOCOL: StreamProtocol = StreamProtocol::new("/phantom/sync/1");
+// How far back a sync looks
+pub const SYNC_WINDOW_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+// Most messages in one batch; the requester asks again if there are more
+pub const SYNC_BATCH: usize = 100;
+pub const SYNC_BATCH_BYTES: usize = 256 * 1024;
+// Most summaries handled in one request
+pub const MAX_SUMMARIES: usize = 64;
+
+// Bits per id and probes per lookup, about 0.05% false positives
+const BITS_PER_ITEM: usize = 16;
+const HASHES: u32 = 11;
+// Largest filter we accept, enough for 32k ids
+const MAX_FILTER_BYTES: usize = 64 * 1024;
+
+pub type SyncBehaviour = request_response::cbor::Behaviour<SyncRequest, SyncResponse>;
+
+pub fn build_sync_behaviour() -> SyncBehaviour {
+    request_response::cbor::Behaviour::new(
+        [(SYNC_PROTOCOL, request_response::ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+// A conversation as both sides name it: in our store a 1-on-1 chat is
+// keyed by the other peer, so each side has a different channel for it.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+pub enum Conversation {
+    Direct,
+    Group(String),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Summary {
+    pub conversation: Conversation,
+    // Start of the window the filter covers
+    pub since: i64,
+    pub filter: BloomFilter,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SyncRequest {
+    pub summaries: Vec<Summary>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub enum SyncResponse {
+    // An inbox frame sealing a `P2PMessage::SyncBatch`
+    Sealed(String),
+    // Nothing the requester is missing
+    UpToDate,
+    // No session to seal with yet
+    Unavailable,
+}
+
+// One message of a `P2PMessage::SyncBatch`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SyncedMessage {
+    pub conversation: Conversation,
+    pub message: StoredMessage,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct BloomFilter {
+    #[serde(with = "serde_bytes")]
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new<'a>(ids: impl ExactSizeIterator<Item = &'a str>) -> Self {
+        let bytes = (ids.len() * BITS_PER_ITEM).div_ceil(8).clamp(8, MAX_FILTER_BYTES);
+        let mut filter = BloomFilter { bits: vec![0; bytes], hashes: HASHES };
+        for id in ids {
+            for bit in filter.probes(id) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.probes(id).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Whether a peer's filter is one we are willing to probe.
+    pub fn is_valid(&self) -> bool {
+        !self.bits.is_empty() && self.bits.len() <= MAX_FILTER_BYTES && (1..=32).contains(&self.hashes)
+    }
+
+    // Double hashing: two halves of the SHA-256 of the id give all probes.
+    fn probes(&self, id: &str) -> impl Iterator<Item = usize> {
+        let hash = Sha256::digest(id.as_bytes());
+        let h1 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap());
+        let bits = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_holds_every_id() {
+        let ids: Vec<String> = (0..1000).map(|i| format!("message-{}", i)).collect();
+        let filter = BloomFilter::new(ids.iter().map(String::as_str));
+        assert!(filter.is_valid());
+        assert!(ids.iter().all(|id| filter.contains(id)));
+    }
+
+    #[test]
+    fn false_positives_are_rare() {
+        let ids: Vec<String> = (0..1000).map(|i| format!("message-{}", i)).collect();
+        let filter = BloomFilter::new(ids.iter().map(String::as_str));
+        let false_positives = (0..10_000).filter(|i| filter.contains(&format!("other-{}", i))).count();
+        assert!(false_positives < 10, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn empty_conversation_still_gets_a_filter() {
+        let filter = BloomFilter::new(std::iter::empty::<&str>());
+        assert!(filter.is_valid());
+        assert!(!filter.contains("message"));
+    }
+
+    #[test]
+    fn oversized_or_degenerate_filters_are_refused() {
+        assert!(!BloomFilter { bits: Vec::new(), hashes: HASHES }.is_valid());
+        assert!(!BloomFilter { bits: vec![0; MAX_FILTER_BYTES + 1], hashes: HASHES }.is_valid());
+        assert!(!BloomFilter { bits: vec![0; 8], hashes: 0 }.is_valid());
+    }
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce
+};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use crate::keystore::KeyStore;
+
+// At-rest encryption for the state files we keep in the app data dir
+// (ratchet sessions, group keys). Everything is sealed with AES-256-GCM under
+// `storage.key`, which is one of the key files in the `KeyStore`.
+#[derive(Clone)]
+pub struct Vault {
+    dir: PathBuf,
+    key: [u8; 32],
+}
+
+impl Vault {
+    // Opens the vault over the files in the key store's dir.
+    pub fn open(keys: &KeyStore) -> Result<Self, Box<dyn Error>> {
+        let dir = keys.dir().to_path_buf();
+
+        // A key that is there but can't be read stays an error: a new one
+        // would lock us out of everything sealed under the old one.
+        if let Some(bytes) = keys.read("storage.key")? {
+            let key = <[u8; 32]>::try_from(bytes.as_slice())
+                .map_err(|_| format!("Invalid storage key in {:?}", dir))?;
+            return Ok(Vault { dir, key });
+        }
+
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        keys.write("storage.key", &key)?;
+        println!("Generated and saved new storage key to {:?}", dir);
+        Ok(Vault { dir, key })
+    }
+
+    // A vault under a key that isn't `storage.key`, see `KeyStore`.
+    pub fn with_key(dir: &Path, key: [u8; 32]) -> Self {
+        Vault { dir: dir.to_path_buf(), key }
+    }
+
+    // A key for data kept outside the vault files, e.g. the message store.
+    pub fn derive_key(&self, purpose: &str) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &self.key)
+            .expand(purpose.as_bytes(), &mut key)
+            .expect("32 bytes is a valid HKDF output length");
+        key
+    }
+
+    // Returns `None` if the file doesn't exist yet.
+    pub fn read(&self, file_name: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let path = self.dir.join(file_name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(self.open_sealed(file_name, &fs::read(&path)?)?))
+    }
+
+    pub fn write(&self, file_name: &str, plaintext: &[u8]) -> Result<(), Box<dyn Error>> {
+        let data = self.seal(file_name, plaintext)?;
+
+        // Write-and-rename, so a crash mid-write can't leave a ratchet state
+        // behind that no longer matches what the peer has.
+        let tmp_path = self.dir.join(format!("{}.tmp", file_name));
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, self.dir.join(file_name))?;
+        Ok(())
+    }
+
+    // The file name is authenticated too, so files can't be swapped around
+    pub fn seal(&self, file_name: &str, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = Aes256Gcm::new(&self.key.into());
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: file_name.as_bytes() })
+            .map_err(|e| e.to_string())?;
+
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+        Ok(data)
+    }
+
+    pub fn open_sealed(&self, file_name: &str, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if data.len() < 12 {
+            return Err(format!("{} is truncated", file_name).into());
+        }
+        let (nonce, ciphertext) = data.split_at(12);
+        let cipher = Aes256Gcm::new(&self.key.into());
+        let plaintext = cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: file_name.as_bytes() })
+            .map_err(|_| format!("Failed to decrypt {}", file_name))?;
+        Ok(plaintext)
+    }
+}
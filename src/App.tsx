@@ -3,27 +3,56 @@ import { listen } from "@tauri-apps/api/event";
 import { invoke } from "@tauri-apps/api/core";
 import { check } from "@tauri-apps/plugin-updater";
 import { relaunch } from "@tauri-apps/plugin-process";
+import { open } from "@tauri-apps/plugin-dialog";
+import { openPath } from "@tauri-apps/plugin-opener";
 import { Sidebar } from "./components/Layout/Sidebar";
 import { ChatHeader } from "./components/Chat/ChatHeader";
 import { MessageList, Message } from "./components/Chat/MessageList";
 import { MessageInput } from "./components/Chat/MessageInput";
-import { dbService, DBContact } from "./services/db";
+import { FileTransfers, TransferInfo } from "./components/Chat/FileTransfers";
+import { SafetyNumberModal } from "./components/Chat/SafetyNumberModal";
+import { dbService, DBContact, DBMessage } from "./services/db";
+
+export interface GroupInfo {
+  id: string;
+  name: string;
+  epoch: number;
+  members: string[];
+  // The group's creator; any member invites, removes and rotates the key
+  owner: string;
+}
+
+export interface NetworkStatus {
+  reachability: "public" | "private" | "unknown";
+  publicAddress: string | null;
+  relayReserved: boolean;
+}
 
-function App() {
+interface AppProps {
+  onLock: () => void;
+}
+
+function App({ onLock }: AppProps) {
   const [activeChannel, setActiveChannel] = useState("global-gossip");
   const [activePeer, setActivePeer] = useState<string | null>(null);
   const [localPeerId, setLocalPeerId] = useState<string>("Инициализация...");
   const [peers, setPeers] = useState<string[]>([]);
   const [contacts, setContacts] = useState<DBContact[]>([]);
+  // Peer whose safety number is being shown
+  const [verifyingPeer, setVerifyingPeer] = useState<string | null>(null);
   const [userProfile, setUserProfile] = useState<{name: string} | null>(null);
   const [messages, setMessages] = useState<Message[]>([]);
   const [inputValue, setInputValue] = useState("");
   const [replyingTo, setReplyingTo] = useState<{sender: string, content: string} | null>(null);
   const [searchQuery, setSearchQuery] = useState("");
+  const [searchResults, setSearchResults] = useState<Message[]>([]);
   const [typingPeers, setTypingPeers] = useState<Record<string, number>>({});
   const typingTimeoutRef = useRef<ReturnType<typeof setTimeout> | null>(null);
   const [isDragging, setIsDragging] = useState(false);
   const [listenAddresses, setListenAddresses] = useState<string[]>([]);
+  const [groups, setGroups] = useState<GroupInfo[]>([]);
+  const [networkStatus, setNetworkStatus] = useState<NetworkStatus | null>(null);
+  const [transfers, setTransfers] = useState<TransferInfo[]>([]);
 
   const playNotificationSound = () => {
     try {
@@ -48,10 +77,9 @@ function App() {
     }
   };
 
-  // Initialize DB and load messages & contacts
+  // Load messages & contacts
   useEffect(() => {
     const initDB = async () => {
-      await dbService.init();
       loadContacts();
       loadProfile();
 
@@ -73,6 +101,10 @@ function App() {
         if (pid && pid !== "Initializing...") {
             setLocalPeerId(pid);
         }
+
+        await loadGroups();
+        setNetworkStatus(await invoke<NetworkStatus>("get_network_status"));
+        setTransfers(await invoke<TransferInfo[]>("list_transfers"));
       } catch (e) {
         console.error("Failed to get p2p info", e);
       }
@@ -94,13 +126,59 @@ function App() {
   const handleConnectPeer = async (addr: string) => {
     try {
         await invoke("connect_peer", { addr });
-        alert("Запрос на подключение отправлен");
+        alert("Подключено");
     } catch (e) {
         console.error("Connection failed", e);
         alert("Ошибка подключения: " + e);
     }
   };
 
+  const loadGroups = async () => {
+    try {
+        setGroups(await invoke<GroupInfo[]>("list_groups"));
+    } catch (e) {
+        console.error("Failed to load groups", e);
+    }
+  };
+
+  const handleCreateGroup = async (name: string) => {
+    try {
+        const group = await invoke<GroupInfo>("create_group", { name });
+        await loadGroups();
+        setActiveChannel(group.id);
+        setActivePeer(null);
+    } catch (e) {
+        alert("Не удалось создать группу: " + e);
+    }
+  };
+
+  const handleInviteToGroup = async (groupId: string, peerId: string) => {
+    try {
+        await invoke("invite_to_group", { groupId, peerId });
+        alert("Ключ группы отправлен");
+    } catch (e) {
+        alert("Ошибка приглашения: " + e);
+    }
+  };
+
+  const handleRemoveGroupMember = async (groupId: string, peerId: string) => {
+    try {
+        await invoke("remove_group_member", { groupId, peerId });
+        alert("Участник удалён, ключ группы обновлён");
+    } catch (e) {
+        alert("Ошибка удаления участника: " + e);
+    }
+  };
+
+  const handleRotateGroupKey = async (groupId: string) => {
+    try {
+        await invoke("rotate_group_key", { groupId });
+        alert("Ключ группы обновлён");
+    } catch (e) {
+        alert("Ошибка смены ключа: " + e);
+    }
+  };
+
   const loadContacts = async () => {
 
     const loadedContacts = await dbService.getContacts();
@@ -117,42 +195,71 @@ function App() {
     await loadContacts();
   };
 
-  // Load messages when active channel changes
+  // The history keeps PeerIds, messages from before it did the name shown back then
+  const senderDisplayName = (sender: string) => {
+    if (sender === localPeerId) return userProfile?.name || "Я";
+    const contact = contacts.find(c => c.peerId === sender);
+    if (contact) return contact.name;
+    return sender.length > 40 ? sender.substring(0, 8) + "..." : sender;
+  };
+
+  const toMessage = (msg: DBMessage): Message => {
+    let replyTo = undefined;
+    if (msg.replyTo) {
+       try {
+         replyTo = JSON.parse(msg.replyTo);
+       } catch(e) {}
+    }
+    
+    let reactions = {};
+    if (msg.reactions) {
+      try {
+        reactions = JSON.parse(msg.reactions);
+      } catch(e) {}
+    }
+
+    return {
+      uuid: msg.uuid,
+      sender: senderDisplayName(msg.sender),
+      content: msg.content,
+      channel: msg.channel,
+      time: new Date(msg.timestamp).toLocaleTimeString(),
+      replyTo,
+      type: msg.type as any,
+      status: msg.status as any,
+      reactions,
+      isEdited: !!msg.lastEdited,
+      fileName: msg.fileName || undefined,
+      fileSize: msg.fileSize || undefined,
+      timestamp: msg.timestamp
+    };
+  };
+
+  // Load messages when the open chat changes
   useEffect(() => {
     const loadMessages = async () => {
-      const dbMessages = await dbService.getMessages(activeChannel);
-      const formattedMessages: Message[] = dbMessages.map(msg => {
-        let replyTo = undefined;
-        if (msg.replyTo) {
-           try {
-             replyTo = JSON.parse(msg.replyTo);
-           } catch(e) {}
-        }
-        
-        let reactions = {};
-        if (msg.reactions) {
-          try {
-            reactions = JSON.parse(msg.reactions);
-          } catch(e) {}
-        }
-
-        return {
-          uuid: msg.uuid,
-          sender: msg.sender,
-          content: msg.content,
-          channel: msg.channel,
-          time: new Date(msg.timestamp).toLocaleTimeString(),
-          replyTo,
-          type: msg.type as any,
-          status: msg.status as any,
-          reactions,
-          isEdited: !!msg.lastEdited
-        };
-      });
-      setMessages(formattedMessages);
+      const dbMessages = await dbService.getMessages(activePeer || activeChannel);
+      setMessages(dbMessages.map(toMessage));
     };
     loadMessages();
-  }, [activeChannel]);
+  }, [activeChannel, activePeer, localPeerId, contacts, userProfile]);
+
+  // Searching goes through the whole history of the open chat, not just what's loaded
+  useEffect(() => {
+    if (!searchQuery) {
+      setSearchResults([]);
+      return;
+    }
+    dbService.searchMessages(searchQuery, activePeer || activeChannel)
+      .then(results => setSearchResults(results.map(toMessage)));
+  }, [searchQuery, activeChannel, activePeer]);
+
+  // Look up the contact's addresses in the DHT, so the chat can reach them directly
+  useEffect(() => {
+    // @ts-ignore
+    if (!activePeer || !window.__TAURI_INTERNALS__) return;
+    invoke("find_peer", { peerId: activePeer }).catch(console.error);
+  }, [activePeer]);
 
   useEffect(() => {
     // Check if running in Tauri environment
@@ -209,7 +316,7 @@ function App() {
         try {
             const payload = JSON.parse(event.payload);
             const channel = payload.channel === "phantom-global" ? "global-gossip" : payload.channel;
-            const senderName = payload.sender === localPeerId ? "Я" : payload.sender.substring(0, 8) + "...";
+            const senderName = senderDisplayName(payload.sender);
             
             if (payload.sender !== localPeerId) {
                 playNotificationSound();
@@ -219,7 +326,6 @@ function App() {
             let replyTo = undefined;
             let type = 'text';
             let uuid = undefined;
-            let targetUuid = undefined;
             
             // Try to parse structured message
             try {
@@ -231,7 +337,6 @@ function App() {
                         content = json.content || json.text;
                         replyTo = json.replyTo;
                         uuid = json.uuid;
-                        targetUuid = json.targetUuid;
                     } 
                     // Fallback for older messages
                     else if ('text' in json) {
@@ -243,61 +348,7 @@ function App() {
                 // Not JSON or plain text, treat as content
             }
 
-            // Handle Special Types (Edit, Reaction, Delete)
-            if (type === 'edit' && targetUuid) {
-                 // Update DB
-                 await dbService.updateMessageContent(targetUuid, content);
-                 // Update UI
-                 setMessages(prev => prev.map(m => 
-                    m.uuid === targetUuid ? { ...m, content: content, isEdited: true } : m
-                 ));
-                 return;
-            }
-
-            if (type === 'delete' && targetUuid) {
-                 // Delete from DB
-                 await dbService.deleteMessage(targetUuid);
-                 // Update UI
-                 setMessages(prev => prev.filter(m => m.uuid !== targetUuid));
-                 return;
-            }
-
-            if (type === 'reaction' && targetUuid) {
-                 // Update DB logic needs to be robust. 
-                 // For now, let's just update UI and assume next load will sync or we do simple append?
-                 // Reactions are complex to sync without full history.
-                 // We will just update UI for now.
-                 setMessages(prev => prev.map(m => {
-                    if (m.uuid === targetUuid) {
-                        const reactions = { ...(m.reactions || {}) };
-                        const currentReactors = reactions[content] || [];
-                        if (currentReactors.includes(senderName)) {
-                            reactions[content] = currentReactors.filter(r => r !== senderName);
-                            if (reactions[content].length === 0) delete reactions[content];
-                        } else {
-                            reactions[content] = [...currentReactors, senderName];
-                        }
-                        // Async update DB
-                        dbService.updateMessageReactions(targetUuid, JSON.stringify(reactions));
-                        return { ...m, reactions };
-                    }
-                    return m;
-                 }));
-                 return;
-            }
-
-            // Regular Message
-            // Save to DB
-            await dbService.saveMessage({
-              uuid: uuid,
-              sender: senderName,
-              content: content,
-              channel: channel,
-              timestamp: Date.now(),
-              replyTo: replyTo ? JSON.stringify(replyTo) : undefined,
-              type: type as any,
-              status: 'delivered' // Received messages are delivered
-            });
+            // Regular Message, the node has already stored it
 
             // Only update UI if message belongs to active channel or active peer
             // Note: If channel is a peer ID, it should match activePeer
@@ -313,6 +364,11 @@ function App() {
                     type: type as any,
                     status: 'delivered'
                 }]);
+
+                // The chat is open, so the message has been seen
+                if (uuid && channel === activePeer) {
+                    invoke("mark_read", { peerId: channel, messageId: uuid }).catch(console.error);
+                }
             }
         } catch (e) {
             console.error("Failed to parse message:", e);
@@ -337,6 +393,170 @@ function App() {
         }
     });
 
+    // Handshake whose key was not signed by the sender's identity
+    const unlistenRejected = listen<string>("handshake-rejected", (event) => {
+        const peerId = event.payload;
+        if (activePeer === peerId) {
+             setMessages(prev => [...prev, {
+                sender: "Система",
+                content: "⚠️ Отклонено рукопожатие с неверной подписью. Возможна атака посредника.",
+                channel: peerId,
+                time: new Date().toLocaleTimeString()
+             }]);
+        }
+    });
+
+    // A session came up with another key than the one we knew for the peer
+    const unlistenKeyChanged = listen<string>("key-changed", (event) => {
+        try {
+            const { peerId, verified } = JSON.parse(event.payload);
+            loadContacts();
+            if (verified) {
+                alert(`⚠️ ВНИМАНИЕ: ключ безопасности проверенного контакта ${getPeerDisplayName(peerId)} изменился!\n\nЕсли собеседник не переустанавливал приложение, возможна атака посредника. Сверьте код безопасности заново.`);
+            }
+            if (activePeer === peerId) {
+                 setMessages(prev => [...prev, {
+                    sender: "Система",
+                    content: verified
+                        ? "🚨 Ключ безопасности проверенного контакта изменился. Сверьте код безопасности заново."
+                        : "⚠️ Ключ безопасности собеседника изменился.",
+                    channel: peerId,
+                    time: new Date().toLocaleTimeString()
+                 }]);
+            }
+        } catch (e) {
+            console.error("Failed to parse key change:", e);
+        }
+    });
+
+    // Another of our devices handed us a page of history
+    const unlistenHistory = listen<string>("history-synced", async () => {
+        loadContacts();
+        const dbMessages = await dbService.getMessages(activePeer || activeChannel);
+        setMessages(dbMessages.map(toMessage));
+    });
+
+    // Our account's devices or a contact's changed
+    const unlistenDevices = listen("devices-updated", () => {
+        loadContacts();
+    });
+
+    // Messages queued for a peer whose handshake never completed
+    const unlistenTimeout = listen<string>("handshake-timeout", (event) => {
+        try {
+            const { peerId, count } = JSON.parse(event.payload);
+            if (activePeer === peerId) {
+                 setMessages(prev => [...prev, {
+                    sender: "Система",
+                    content: `⚠️ Не удалось установить защищенное соединение. Не доставлено сообщений: ${count}`,
+                    channel: peerId,
+                    time: new Date().toLocaleTimeString()
+                 }]);
+            }
+        } catch (e) {
+            console.error("Failed to parse handshake timeout:", e);
+        }
+    });
+
+    // Edits and deletes were checked against the original author by the node
+    const unlistenEdited = listen<string>("message-edited", async (event) => {
+        try {
+            const { messageId, content } = JSON.parse(event.payload);
+            setMessages(prev => prev.map(m => 
+               m.uuid === messageId ? { ...m, content: content, isEdited: true } : m
+            ));
+        } catch (e) {
+            console.error("Failed to parse message edit:", e);
+        }
+    });
+
+    const unlistenDeleted = listen<string>("message-deleted", async (event) => {
+        try {
+            const { messageId } = JSON.parse(event.payload);
+            setMessages(prev => prev.filter(m => m.uuid !== messageId));
+        } catch (e) {
+            console.error("Failed to parse message delete:", e);
+        }
+    });
+
+    const unlistenReaction = listen<string>("message-reaction", (event) => {
+        try {
+            const { peerId, messageId, emoji } = JSON.parse(event.payload);
+            setMessages(prev => prev.map(m => {
+               if (m.uuid === messageId) {
+                   const reactions = { ...(m.reactions || {}) };
+                   const currentReactors = reactions[emoji] || [];
+                   if (currentReactors.includes(peerId)) {
+                       reactions[emoji] = currentReactors.filter(r => r !== peerId);
+                       if (reactions[emoji].length === 0) delete reactions[emoji];
+                   } else {
+                       reactions[emoji] = [...currentReactors, peerId];
+                   }
+                   return { ...m, reactions };
+               }
+               return m;
+            }));
+        } catch (e) {
+            console.error("Failed to parse reaction:", e);
+        }
+    });
+
+    // Delivery and read receipts for messages we sent
+    const unlistenStatus = listen<string>("message-status", async (event) => {
+        try {
+            const { messageId, status } = JSON.parse(event.payload);
+            // A late 'delivered' must not undo 'read'
+            const rank = (s?: string) => ['sending', 'sent', 'delivered', 'read'].indexOf(s || 'sending');
+            setMessages(prev => prev.map(m =>
+                m.uuid === messageId && rank(status) > rank(m.status) ? { ...m, status } : m
+            ));
+        } catch (e) {
+            console.error("Failed to parse message status:", e);
+        }
+    });
+
+    // Answer of the peer we asked to hold our messages while we're offline
+    const unlistenMailbox = listen<string>("mailbox-status", (event) => {
+        try {
+            const { peerId, accepted } = JSON.parse(event.payload);
+            if (!accepted) {
+                alert(`Узел ${peerId.substring(0, 8)}... отказался хранить ваши сообщения`);
+            }
+        } catch (e) {
+            console.error("Failed to parse mailbox status:", e);
+        }
+    });
+
+    // Reachability from outside (AutoNAT) and relay reservation
+    const unlistenNetwork = listen<string>("network-status", (event) => {
+        try {
+            setNetworkStatus(JSON.parse(event.payload));
+        } catch (e) {
+            console.error("Failed to parse network status:", e);
+        }
+    });
+
+    // Direct file transfers: offers, progress and status changes
+    const unlistenTransfer = listen<string>("file-transfer", (event) => {
+        try {
+            const info: TransferInfo = JSON.parse(event.payload);
+            setTransfers(prev => {
+                const rest = prev.filter(t => t.id !== info.id);
+                return [...rest, info];
+            });
+            if (info.direction === 'incoming' && info.status === 'offered') {
+                playNotificationSound();
+            }
+        } catch (e) {
+            console.error("Failed to parse file transfer:", e);
+        }
+    });
+
+    // Group membership or key changes
+    const unlistenGroups = listen<string>("group-updated", () => {
+        loadGroups();
+    });
+
     // Listen for typing indicators
     const unlistenTyping = listen<string>("peer-typing", (event) => {
         try {
@@ -378,6 +598,19 @@ function App() {
         unlistenHandshake.then(f => f());
         unlistenTyping.then(f => f());
         unlistenAddress.then(f => f());
+        unlistenGroups.then(f => f());
+        unlistenRejected.then(f => f());
+        unlistenTimeout.then(f => f());
+        unlistenKeyChanged.then(f => f());
+        unlistenHistory.then(f => f());
+        unlistenDevices.then(f => f());
+        unlistenMailbox.then(f => f());
+        unlistenNetwork.then(f => f());
+        unlistenStatus.then(f => f());
+        unlistenEdited.then(f => f());
+        unlistenDeleted.then(f => f());
+        unlistenReaction.then(f => f());
+        unlistenTransfer.then(f => f());
     }
   }, [localPeerId, activeChannel, activePeer]);
 
@@ -414,80 +647,40 @@ function App() {
       m.uuid === msg.uuid ? { ...m, content: newContent, isEdited: true } : m
     ));
 
-    // Update DB
-    await dbService.updateMessageContent(msg.uuid, newContent);
-
-    // Send update to peers
-    const targetChannel = activePeer || activeChannel;
-    if (activePeer) {
-      // P2P update
-      const payload = {
-        uuid: crypto.randomUUID(),
-        type: 'edit',
-        content: newContent,
-        targetUuid: msg.uuid,
-        timestamp: Date.now()
-      };
-      await invoke("send_message", { 
-        channel: targetChannel, 
-        message: JSON.stringify(payload) 
-      }).catch(console.error);
-    }
+    // Updates the history, and the peer in a 1-on-1 chat
+    await invoke("edit_message", { 
+      channel: activePeer || activeChannel, 
+      messageId: msg.uuid,
+      content: newContent
+    }).catch(console.error);
   };
 
   const handleReactMessage = async (msg: Message, emoji: string) => {
     if (!msg.uuid) return;
 
     // Optimistic update
-    const myName = userProfile?.name || localPeerId;
     setMessages(prev => prev.map(m => {
       if (m.uuid === msg.uuid) {
         const reactions = { ...(m.reactions || {}) };
         const currentReactors = reactions[emoji] || [];
         
         // Toggle reaction
-        if (currentReactors.includes(myName)) {
-          reactions[emoji] = currentReactors.filter(r => r !== myName);
+        if (currentReactors.includes(localPeerId)) {
+          reactions[emoji] = currentReactors.filter(r => r !== localPeerId);
           if (reactions[emoji].length === 0) delete reactions[emoji];
         } else {
-          reactions[emoji] = [...currentReactors, myName];
+          reactions[emoji] = [...currentReactors, localPeerId];
         }
         return { ...m, reactions };
       }
       return m;
     }));
 
-    // Calculate new reactions string for DB
-    const message = messages.find(m => m.uuid === msg.uuid);
-    if (message) {
-        // Need to get the updated state, but state update is async.
-        // Re-calculate logic for DB save
-        const reactions = { ...(message.reactions || {}) };
-        const currentReactors = reactions[emoji] || [];
-        if (currentReactors.includes(myName)) {
-            reactions[emoji] = currentReactors.filter(r => r !== myName);
-             if (reactions[emoji].length === 0) delete reactions[emoji];
-        } else {
-            reactions[emoji] = [...currentReactors, myName];
-        }
-        await dbService.updateMessageReactions(msg.uuid, JSON.stringify(reactions));
-    }
-
-    // Send reaction to peers
-    const targetChannel = activePeer || activeChannel;
-    if (activePeer) {
-      const payload = {
-        uuid: crypto.randomUUID(),
-        type: 'reaction',
-        content: emoji,
-        targetUuid: msg.uuid,
-        timestamp: Date.now()
-      };
-      await invoke("send_message", { 
-        channel: targetChannel, 
-        message: JSON.stringify(payload) 
-      }).catch(console.error);
-    }
+    await invoke("react_to_message", { 
+      channel: activePeer || activeChannel, 
+      messageId: msg.uuid,
+      emoji
+    }).catch(console.error);
   };
 
   const handleDeleteMessage = async (msg: Message) => {
@@ -498,23 +691,10 @@ function App() {
     // Optimistic update
     setMessages(prev => prev.filter(m => m.uuid !== msg.uuid));
 
-    // Update DB
-    await dbService.deleteMessage(msg.uuid);
-
-    // Send P2P delete
-    const targetChannel = activePeer || activeChannel;
-    if (activePeer) {
-      const payload = {
-        uuid: crypto.randomUUID(),
-        type: 'delete',
-        targetUuid: msg.uuid,
-        timestamp: Date.now()
-      };
-      await invoke("send_message", { 
-        channel: targetChannel, 
-        message: JSON.stringify(payload) 
-      }).catch(console.error);
-    }
+    await invoke("delete_message", { 
+      channel: activePeer || activeChannel, 
+      messageId: msg.uuid
+    }).catch(console.error);
   };
 
   const handleSendMessage = async () => {
@@ -567,19 +747,8 @@ function App() {
     try {
         await invoke("send_message", { 
             channel: targetChannel, 
-            message: messageToSend 
-        });
-
-        // Save to DB
-        await dbService.saveMessage({
-            uuid: messageUuid,
-            sender: senderName,
-            content: inputValue,
-            channel: targetChannel,
-            timestamp: Date.now(),
-            replyTo: replyingTo ? JSON.stringify(replyingTo) : undefined,
-            type: 'text',
-            status: 'sent'
+            message: messageToSend,
+            messageId: messageUuid
         });
 
         // Optimistically add to UI
@@ -598,20 +767,39 @@ function App() {
         setReplyingTo(null);
     } catch (e) {
         console.error("Failed to send message:", e);
-        if (typeof e === 'string' && e.includes("Handshake sent")) {
-             setMessages(prev => [...prev, {
-                sender: "Система",
-                content: "⏳ Установка защищенного соединения... Повторите отправку после подтверждения.",
-                channel: targetChannel,
-                time: new Date().toLocaleTimeString()
-             }]);
-        }
+        alert("Не удалось отправить сообщение: " + e);
+    }
+  };
+
+  // In a 1-on-1 chat files go over a direct transfer, so there is no size limit
+  const handlePickFile = async () => {
+    if (!activePeer) return;
+    const path = await open({ multiple: false, directory: false });
+    if (typeof path !== "string") return;
+    try {
+      await invoke("send_file", { peerId: activePeer, path });
+    } catch (e) {
+      console.error("Failed to send file:", e);
+      alert("Не удалось отправить файл: " + e);
+    }
+  };
+
+  const handleTransferAction = (command: string) => async (id: string) => {
+    try {
+      await invoke(command, { id });
+    } catch (e) {
+      console.error(`${command} failed:`, e);
+      alert("Ошибка передачи файла: " + e);
     }
   };
 
+  const handleOpenTransfer = (transfer: TransferInfo) => {
+    if (transfer.path) openPath(transfer.path).catch(console.error);
+  };
+
   const handleFileSelect = async (file: File) => {
     if (file.size > 2 * 1024 * 1024) {
-      alert("Файл слишком большой (макс. 2МБ)");
+      alert(activePeer ? "Файл слишком большой (макс. 2МБ), используйте кнопку «+»" : "Файл слишком большой (макс. 2МБ)");
       return;
     }
 
@@ -637,20 +825,6 @@ function App() {
 
       const messageToSend = JSON.stringify(messagePayload);
 
-      // Save locally
-      await dbService.saveMessage({
-        uuid: messageUuid,
-        sender: senderName,
-        content: content,
-        channel: targetChannel,
-        timestamp: Date.now(),
-        replyTo: replyingTo ? JSON.stringify(replyingTo) : undefined,
-        type: type,
-        status: 'sent',
-        fileName: file.name,
-        fileSize: (file.size / 1024).toFixed(1) + ' KB'
-      });
-
       // Update UI
       setMessages(prev => [...prev, {
         uuid: messageUuid,
@@ -672,7 +846,8 @@ function App() {
       try {
         await invoke("send_message", { 
             channel: targetChannel, 
-            message: messageToSend 
+            message: messageToSend,
+            messageId: messageUuid
         });
       } catch (e) {
          console.error("Failed to send file:", e);
@@ -701,17 +876,6 @@ function App() {
         const messageToSend = JSON.stringify(messagePayload);
 
         try {
-             // Save to DB
-             await dbService.saveMessage({
-                uuid: messageUuid,
-                sender: senderName,
-                content: base64Audio,
-                channel: targetChannel,
-                timestamp: Date.now(),
-                type: 'audio',
-                status: 'sent'
-             });
-
              // Update UI
              setMessages(prev => [...prev, {
                 uuid: messageUuid,
@@ -727,7 +891,8 @@ function App() {
              // Send P2P
              await invoke("send_message", { 
                 channel: targetChannel, 
-                message: messageToSend 
+                message: messageToSend,
+                messageId: messageUuid
              });
         } catch (e) {
             console.error("Failed to send audio:", e);
@@ -736,6 +901,8 @@ function App() {
     reader.readAsDataURL(blob);
   };
 
+  const activeGroup = groups.find(g => g.id === activeChannel);
+
   const getPeerDisplayName = (peerId: string) => {
     const contact = contacts.find(c => c.peerId === peerId);
     return contact ? contact.name : `Участник ${peerId.substring(0, 8)}...`;
@@ -764,11 +931,7 @@ function App() {
     }
   };
 
-  const filteredMessages = messages.filter(msg => {
-     if (!searchQuery) return true;
-     return msg.content.toLowerCase().includes(searchQuery.toLowerCase()) || 
-            msg.sender.toLowerCase().includes(searchQuery.toLowerCase());
-  });
+  const filteredMessages = searchQuery ? searchResults : messages;
 
   return (
     <div 
@@ -805,37 +968,62 @@ function App() {
           onAddContact={handleAddContact}
           onDeleteContact={handleDeleteContact}
           onUpdateProfile={handleUpdateProfile}
+          groups={groups}
+          networkStatus={networkStatus}
+          onCreateGroup={handleCreateGroup}
+          onInviteToGroup={handleInviteToGroup}
+          onRemoveGroupMember={handleRemoveGroupMember}
+          onRotateGroupKey={handleRotateGroupKey}
+          onLock={onLock}
         />
       </div>
 
       {/* Main Chat Area */}
       <div className="flex-1 h-full flex flex-col rounded-3xl overflow-hidden shadow-glass glass-panel ring-1 ring-white/10 relative">
         <ChatHeader 
-           channelName={activeChannel || (activePeer ? getPeerDisplayName(activePeer) : "Выберите чат")}
-           channelDescription={activeChannel ? "Глобальный публичный канал" : "Прямое P2P соединение"}
+           channelName={activeGroup?.name || activeChannel || (activePeer ? getPeerDisplayName(activePeer) : "Выберите чат")}
+           channelDescription={activeGroup ? `Зашифрованная группа · ${activeGroup.members.length} участн.` : activeChannel ? "Глобальный публичный канал" : "Прямое P2P соединение"}
            peerCount={peers.length}
            isTyping={activePeer ? !!typingPeers[activePeer] : false}
            onSearch={setSearchQuery}
+           onVerify={activePeer ? () => setVerifyingPeer(activePeer) : undefined}
+           verified={activePeer ? contacts.some(c => c.peerId === activePeer && c.verified) : undefined}
         />
         <MessageList 
           messages={filteredMessages} 
           activeChannel={activePeer || activeChannel}
-          localPeerId={userProfile?.name || localPeerId}
+          localPeerId={localPeerId}
           onReply={(msg) => setReplyingTo({sender: msg.sender, content: msg.content})}
           onReact={handleReactMessage}
           onEdit={handleEditMessage}
           onDelete={handleDeleteMessage}
         />
+        <FileTransfers
+          transfers={transfers.filter(t => t.peerId === activePeer && t.status !== 'cancelled')}
+          onAccept={handleTransferAction("accept_file")}
+          onPause={handleTransferAction("pause_file")}
+          onResume={handleTransferAction("resume_file")}
+          onCancel={handleTransferAction("cancel_file")}
+          onOpen={handleOpenTransfer}
+        />
         <MessageInput 
           value={inputValue}
           onChange={handleInputChange}
           onSend={handleSendMessage}
           onFileSelect={handleFileSelect}
+          onPickFile={activePeer ? handlePickFile : undefined}
           onSendAudio={handleSendAudio}
           replyingTo={replyingTo}
           onCancelReply={() => setReplyingTo(null)}
         />
       </div>
+
+      <SafetyNumberModal
+        peerId={verifyingPeer}
+        peerName={verifyingPeer ? getPeerDisplayName(verifyingPeer) : ""}
+        onClose={() => setVerifyingPeer(null)}
+        onVerifiedChange={loadContacts}
+      />
     </div>
   );
 }
@@ -1,4 +1,4 @@
-import { Hash, Search, Bell, Users, MoreVertical, X } from "lucide-react";
+import { Hash, Search, Bell, Users, MoreVertical, X, ShieldCheck, ShieldAlert } from "lucide-react";
 import { useState } from "react";
 
 interface ChatHeaderProps {
@@ -7,9 +7,12 @@ interface ChatHeaderProps {
   peerCount: number;
   isTyping: boolean;
   onSearch: (query: string) => void;
+  // Only in 1-on-1 chats
+  onVerify?: () => void;
+  verified?: boolean;
 }
 
-export function ChatHeader({ channelName, channelDescription, peerCount, isTyping, onSearch }: ChatHeaderProps) {
+export function ChatHeader({ channelName, channelDescription, peerCount, isTyping, onSearch, onVerify, verified }: ChatHeaderProps) {
   const [isSearchOpen, setIsSearchOpen] = useState(false);
   const [searchQuery, setSearchQuery] = useState("");
 
@@ -68,6 +71,15 @@ export function ChatHeader({ channelName, channelDescription, peerCount, isTypin
              </button>
          )}
          
+         {onVerify && (
+            <button
+               onClick={onVerify}
+               title={verified ? "Собеседник проверен" : "Проверить код безопасности"}
+               className="p-2 rounded-xl hover:bg-white/5 text-muted hover:text-white transition-colors"
+            >
+               {verified ? <ShieldCheck className="w-5 h-5 text-success" /> : <ShieldAlert className="w-5 h-5" />}
+            </button>
+         )}
          <button className="p-2 rounded-xl hover:bg-white/5 text-muted hover:text-white transition-colors">
             <Bell className="w-5 h-5" />
          </button>
@@ -0,0 +1,99 @@
+import { Download, Upload, Check, X, Pause, Play, FolderOpen } from "lucide-react";
+
+export interface TransferInfo {
+  id: string;
+  peerId: string;
+  direction: 'incoming' | 'outgoing';
+  name: string;
+  size: number;
+  transferred: number;
+  status: 'offered' | 'active' | 'paused' | 'completed' | 'failed' | 'cancelled';
+  path: string | null;
+}
+
+interface FileTransfersProps {
+  transfers: TransferInfo[];
+  onAccept: (id: string) => void;
+  onPause: (id: string) => void;
+  onResume: (id: string) => void;
+  onCancel: (id: string) => void;
+  onOpen: (transfer: TransferInfo) => void;
+}
+
+const STATUS_LABELS: Record<TransferInfo['status'], string> = {
+  offered: "Входящий файл",
+  active: "Передача...",
+  paused: "Пауза",
+  completed: "Готово",
+  failed: "Ошибка",
+  cancelled: "Отменено",
+};
+
+const formatSize = (bytes: number) => {
+  if (bytes < 1024) return `${bytes} B`;
+  if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(1)} KB`;
+  if (bytes < 1024 * 1024 * 1024) return `${(bytes / 1024 / 1024).toFixed(1)} MB`;
+  return `${(bytes / 1024 / 1024 / 1024).toFixed(2)} GB`;
+};
+
+// Direct file transfers with the current peer, shown above the input.
+export function FileTransfers({ transfers, onAccept, onPause, onResume, onCancel, onOpen }: FileTransfersProps) {
+  if (transfers.length === 0) return null;
+
+  return (
+    <div className="px-6 pt-2 flex flex-col gap-2 max-h-48 overflow-y-auto">
+      {transfers.map(t => {
+        const percent = t.size > 0 ? Math.round((t.transferred / t.size) * 100) : 100;
+        const canRetry = t.status === 'paused' || (t.status === 'failed' && t.direction === 'incoming');
+        return (
+          <div key={t.id} className="bg-surface/40 backdrop-blur-xl rounded-2xl p-3 ring-1 ring-white/10 flex items-center gap-3">
+            <div className="p-2 bg-white/10 rounded-lg text-muted">
+              {t.direction === 'incoming' ? <Download className="w-4 h-4" /> : <Upload className="w-4 h-4" />}
+            </div>
+            <div className="flex-1 min-w-0">
+              <div className="flex justify-between text-sm">
+                <span className="font-medium truncate">{t.name}</span>
+                <span className="text-xs text-muted ml-2 whitespace-nowrap">
+                  {STATUS_LABELS[t.status]} · {formatSize(t.transferred)} / {formatSize(t.size)}
+                </span>
+              </div>
+              <div className="h-1.5 mt-1.5 bg-white/10 rounded-full overflow-hidden">
+                <div
+                  className={`h-full rounded-full transition-all duration-300 ${t.status === 'failed' ? 'bg-red-500' : 'bg-primary'}`}
+                  style={{ width: `${percent}%` }}
+                />
+              </div>
+            </div>
+            <div className="flex items-center gap-1 text-muted">
+              {t.status === 'offered' && (
+                <button onClick={() => onAccept(t.id)} className="p-1.5 hover:bg-white/10 hover:text-white rounded-full transition" title="Принять">
+                  <Check className="w-4 h-4" />
+                </button>
+              )}
+              {t.status === 'active' && (
+                <button onClick={() => onPause(t.id)} className="p-1.5 hover:bg-white/10 hover:text-white rounded-full transition" title="Пауза">
+                  <Pause className="w-4 h-4" />
+                </button>
+              )}
+              {canRetry && (
+                <button onClick={() => onResume(t.id)} className="p-1.5 hover:bg-white/10 hover:text-white rounded-full transition" title="Продолжить">
+                  <Play className="w-4 h-4" />
+                </button>
+              )}
+              {t.status === 'completed' && t.direction === 'incoming' && (
+                <button onClick={() => onOpen(t)} className="p-1.5 hover:bg-white/10 hover:text-white rounded-full transition" title="Открыть">
+                  <FolderOpen className="w-4 h-4" />
+                </button>
+              )}
+              {(t.status === 'offered' || t.status === 'active' || t.status === 'paused' || t.status === 'failed') && (
+                <button onClick={() => onCancel(t.id)} className="p-1.5 hover:bg-red-500/10 hover:text-red-400 rounded-full transition" title="Отменить">
+                  <X className="w-4 h-4" />
+                </button>
+              )}
+            </div>
+          </div>
+        );
+      })}
+    </div>
+  );
+}
@@ -6,13 +6,15 @@ interface MessageInputProps {
   onChange: (val: string) => void;
   onSend: () => void;
   onFileSelect?: (file: File) => void;
+  // Replaces the browser file input, e.g. to pick a path for a direct transfer
+  onPickFile?: () => void;
   onSendAudio?: (blob: Blob) => void;
   placeholder?: string;
   replyingTo?: { sender: string; content: string } | null;
   onCancelReply?: () => void;
 }
 
-export function MessageInput({ value, onChange, onSend, onFileSelect, onSendAudio, placeholder = "Напишите сообщение...", replyingTo, onCancelReply }: MessageInputProps) {
+export function MessageInput({ value, onChange, onSend, onFileSelect, onPickFile, onSendAudio, placeholder = "Напишите сообщение...", replyingTo, onCancelReply }: MessageInputProps) {
   const fileInputRef = useRef<HTMLInputElement>(null);
   const [isRecording, setIsRecording] = useState(false);
   const [recordingTime, setRecordingTime] = useState(0);
@@ -113,7 +115,7 @@ export function MessageInput({ value, onChange, onSend, onFileSelect, onSendAudi
       <div className={`bg-surface/40 backdrop-blur-xl rounded-2xl flex items-center p-2 gap-2 ring-1 ring-white/10 shadow-glass hover:ring-white/20 transition-all duration-300 ${isRecording ? 'ring-red-500/50 bg-red-500/10' : 'focus-within:ring-primary/50 focus-within:bg-surface/60 focus-within:shadow-[0_0_20px_rgba(139,92,246,0.1)]'}`}>
         {!isRecording && (
           <button 
-            onClick={() => fileInputRef.current?.click()}
+            onClick={() => onPickFile ? onPickFile() : fileInputRef.current?.click()}
             className="p-2.5 rounded-xl text-muted hover:text-white hover:bg-white/10 transition-all active:scale-95"
           >
             <Plus className="w-5 h-5" />
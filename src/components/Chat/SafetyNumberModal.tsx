@@ -0,0 +1,146 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { X, ShieldCheck, ShieldAlert, Copy, Check } from "lucide-react";
+
+interface SafetyNumber {
+  peerId: string;
+  number: string;
+  qrPayload: string;
+  qrSvg: string;
+  verified: boolean;
+}
+
+interface SafetyNumberModalProps {
+  peerId: string | null;
+  peerName: string;
+  onClose: () => void;
+  onVerifiedChange: () => void;
+}
+
+// Safety number of the session with a peer, to compare in person or by
+// scanning each other's code.
+export function SafetyNumberModal({ peerId, peerName, onClose, onVerifiedChange }: SafetyNumberModalProps) {
+  const [safety, setSafety] = useState<SafetyNumber | null>(null);
+  const [error, setError] = useState<string | null>(null);
+  const [code, setCode] = useState("");
+  const [copied, setCopied] = useState(false);
+
+  const load = async (id: string) => {
+    try {
+      setSafety(await invoke<SafetyNumber>("get_safety_number", { peerId: id }));
+      setError(null);
+    } catch (e) {
+      setSafety(null);
+      setError(String(e));
+    }
+  };
+
+  useEffect(() => {
+    if (peerId) load(peerId);
+    setCode("");
+  }, [peerId]);
+
+  const setVerified = async (verified: boolean) => {
+    if (!peerId) return;
+    try {
+      await invoke("set_contact_verified", { peerId, verified });
+      await load(peerId);
+      onVerifiedChange();
+    } catch (e) {
+      alert(`Ошибка: ${e}`);
+    }
+  };
+
+  const handleCheckCode = async () => {
+    if (!peerId || !code.trim()) return;
+    try {
+      const verifiedPeer = await invoke<string>("verify_safety_code", { payload: code.trim() });
+      if (verifiedPeer !== peerId) {
+        alert("Код принадлежит другому контакту, он отмечен как проверенный");
+      }
+      setCode("");
+      await load(peerId);
+      onVerifiedChange();
+    } catch (e) {
+      alert(String(e) === "Safety numbers don't match"
+        ? "⚠️ Коды безопасности НЕ совпадают. Возможна атака посредника!"
+        : `Ошибка: ${e}`);
+    }
+  };
+
+  const handleCopy = () => {
+    if (!safety) return;
+    navigator.clipboard.writeText(safety.qrPayload);
+    setCopied(true);
+    setTimeout(() => setCopied(false), 2000);
+  };
+
+  if (!peerId) return null;
+
+  return (
+    <div className="fixed inset-0 z-50 flex items-center justify-center bg-black/60 backdrop-blur-sm animate-in fade-in duration-200">
+      <div className="w-[440px] bg-surface border border-white/10 rounded-2xl shadow-2xl overflow-hidden animate-in zoom-in-95 duration-200 flex flex-col max-h-[85vh]">
+        <div className="flex items-center justify-between px-6 py-4 border-b border-white/5 bg-white/[0.02]">
+          <h2 className="text-lg font-bold text-white flex items-center gap-2">
+            {safety?.verified ? <ShieldCheck className="w-5 h-5 text-success" /> : <ShieldAlert className="w-5 h-5 text-muted" />}
+            Проверка {peerName}
+          </h2>
+          <button onClick={onClose} className="p-2 rounded-xl hover:bg-white/5 text-muted hover:text-white transition-colors">
+            <X className="w-5 h-5" />
+          </button>
+        </div>
+
+        <div className="p-6 overflow-y-auto custom-scrollbar space-y-4">
+          {error && <p className="text-sm text-muted">{error === "No session with this peer yet" ? "Защищенное соединение ещё не установлено." : error}</p>}
+          {safety && (
+            <>
+              <div className="grid grid-cols-4 gap-2 font-mono text-lg text-white text-center">
+                {safety.number.split(" ").map((group, i) => <span key={i}>{group}</span>)}
+              </div>
+              <p className="text-xs text-muted/60">Сравните эти цифры с экраном собеседника. Если они совпадают, никто не подменил ключи.</p>
+
+              <div className="flex justify-center">
+                <div className="bg-white p-2 rounded-xl w-[216px]" dangerouslySetInnerHTML={{ __html: safety.qrSvg }} />
+              </div>
+              <button
+                onClick={handleCopy}
+                className="w-full flex items-center justify-center gap-2 px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all"
+              >
+                {copied ? <Check className="w-4 h-4 text-success" /> : <Copy className="w-4 h-4" />}
+                Скопировать код
+              </button>
+
+              <div className="flex gap-2">
+                <input
+                  type="text"
+                  value={code}
+                  onChange={(e) => setCode(e.target.value)}
+                  placeholder="Код собеседника..."
+                  className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all font-mono text-xs"
+                />
+                <button
+                  onClick={handleCheckCode}
+                  disabled={!code.trim()}
+                  className="px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all disabled:opacity-50 disabled:cursor-not-allowed"
+                >
+                  Проверить
+                </button>
+              </div>
+
+              <button
+                onClick={() => setVerified(!safety.verified)}
+                className={`w-full px-4 py-2.5 rounded-xl text-sm font-medium transition-all ${
+                  safety.verified
+                    ? "bg-white/5 hover:bg-red-500/10 border border-white/10 text-white hover:text-red-400"
+                    : "bg-gradient-to-r from-primary to-purple-600 text-white shadow-lg shadow-primary/25"
+                }`}
+              >
+                {safety.verified ? "Снять отметку о проверке" : "Отметить как проверенный"}
+              </button>
+            </>
+          )}
+        </div>
+      </div>
+    </div>
+  );
+}
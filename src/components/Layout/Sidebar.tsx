@@ -10,9 +10,13 @@ import {
   UserPlus,
   X,
   Check,
-  Trash2
+  Trash2,
+  ShieldCheck,
+  UserMinus,
+  RefreshCw
 } from "lucide-react";
 import { DBContact } from "../../services/db";
+import { GroupInfo, NetworkStatus } from "../../App";
 import { SettingsModal } from "../Settings/SettingsModal";
 
 interface SidebarProps {
@@ -28,6 +32,13 @@ interface SidebarProps {
   onUpdateProfile: (name: string) => void;
   listenAddresses: string[];
   onConnectPeer: (addr: string) => void;
+  groups: GroupInfo[];
+  onCreateGroup: (name: string) => void;
+  onInviteToGroup: (groupId: string, peerId: string) => void;
+  onRemoveGroupMember: (groupId: string, peerId: string) => void;
+  onRotateGroupKey: (groupId: string) => void;
+  networkStatus: NetworkStatus | null;
+  onLock: () => void;
 }
 
 type Tab = "channels" | "peers";
@@ -44,7 +55,14 @@ export function Sidebar({
   onDeleteContact,
   onUpdateProfile,
   listenAddresses,
-  onConnectPeer
+  onConnectPeer,
+  groups,
+  onCreateGroup,
+  onInviteToGroup,
+  onRemoveGroupMember,
+  onRotateGroupKey,
+  networkStatus,
+  onLock
 }: SidebarProps) {
   const [activeTab, setActiveTab] = useState<Tab>("channels");
   const [isAddingContact, setIsAddingContact] = useState(false);
@@ -67,16 +85,28 @@ export function Sidebar({
     return contact ? contact.name : peerId;
   };
 
+  const getReachabilityLabel = () => {
+    if (networkStatus?.reachability === "public") return { text: "В сети · доступен напрямую", color: "bg-success" };
+    if (networkStatus?.relayReserved) return { text: "В сети · через ретранслятор", color: "bg-success" };
+    if (networkStatus?.reachability === "private") return { text: "В сети · за NAT", color: "bg-yellow-500" };
+    return { text: "В сети", color: "bg-success" };
+  };
+
   const isContact = (peerId: string) => {
     return contacts.some(c => c.peerId === peerId);
   };
 
 
   const channels = [
-    { id: "global-gossip", name: "Общий чат", type: "text" },
-    { id: "encrypted-chat", name: "Зашифрованный чат", type: "encrypted" },
+    { id: "global-gossip", name: "Общий чат", type: "text", members: [] as string[] },
+    ...groups.map(g => ({ id: g.id, name: g.name, type: "encrypted", members: g.members })),
   ];
 
+  const handleCreateGroup = () => {
+    const name = prompt("Название группы");
+    if (name && name.trim()) onCreateGroup(name.trim());
+  };
+
   return (
     <div className="w-80 h-full flex flex-col glass-panel-dark border-r border-white/5 bg-[#0b0a15]/90 backdrop-blur-xl">
       {/* App Header */}
@@ -146,8 +176,11 @@ export function Sidebar({
         {activeTab === "channels" && (
           <>
             <div className="px-3 py-2 text-xs font-semibold text-muted/50 uppercase tracking-wider flex items-center justify-between group">
-              Публичные
-              <Plus className="w-3 h-3 cursor-pointer opacity-0 group-hover:opacity-100 hover:text-white transition-opacity" />
+              Каналы и группы
+              <Plus
+                className="w-3 h-3 cursor-pointer opacity-0 group-hover:opacity-100 hover:text-white transition-opacity"
+                onClick={handleCreateGroup}
+              />
             </div>
             {channels.map((channel) => (
               <button
@@ -175,6 +208,47 @@ export function Sidebar({
                     {channel.type === "encrypted" ? "Сквозное шифрование" : "Глобальный чат"}
                   </span>
                 </div>
+                {channel.type === "encrypted" && (
+                  <div className="ml-auto flex opacity-0 group-hover:opacity-100 transition-all duration-300">
+                    <div
+                      className="p-2 hover:bg-primary/20 rounded-lg text-muted hover:text-primary active:scale-90"
+                      onClick={(e) => {
+                        e.stopPropagation();
+                        const peerId = prompt("ID пира для приглашения");
+                        if (peerId && peerId.trim()) onInviteToGroup(channel.id, peerId.trim());
+                      }}
+                      title="Пригласить в группу"
+                    >
+                      <UserPlus className="w-4 h-4" />
+                    </div>
+                    <div
+                      className="p-2 hover:bg-red-500/20 rounded-lg text-muted hover:text-red-500 active:scale-90"
+                      onClick={(e) => {
+                        e.stopPropagation();
+                        const others = channel.members.filter(m => m !== localPeerId);
+                        if (others.length === 0) {
+                          alert("В группе нет других участников");
+                          return;
+                        }
+                        const peerId = prompt("ID участника для удаления:\n" + others.join("\n"));
+                        if (peerId && peerId.trim()) onRemoveGroupMember(channel.id, peerId.trim());
+                      }}
+                      title="Удалить участника"
+                    >
+                      <UserMinus className="w-4 h-4" />
+                    </div>
+                    <div
+                      className="p-2 hover:bg-primary/20 rounded-lg text-muted hover:text-primary active:scale-90"
+                      onClick={(e) => {
+                        e.stopPropagation();
+                        if (confirm("Сменить ключ группы? Участники получат новый ключ.")) onRotateGroupKey(channel.id);
+                      }}
+                      title="Сменить ключ"
+                    >
+                      <RefreshCw className="w-4 h-4" />
+                    </div>
+                  </div>
+                )}
               </button>
             ))}
           </>
@@ -316,8 +390,9 @@ export function Sidebar({
                       <div className="absolute bottom-0 right-0 w-3 h-3 bg-muted/50 rounded-full border-2 border-[#0b0a15]" />
                     </div>
                     <div className="flex flex-col items-start flex-1 min-w-0">
-                      <span className="font-medium text-sm truncate w-full text-left">
+                      <span className="font-medium text-sm truncate w-full text-left flex items-center gap-1">
                         {contact.name}
+                        {contact.verified && <ShieldCheck className="w-3 h-3 text-success shrink-0" />}
                       </span>
                       <span className="text-[10px] text-muted/50 flex items-center gap-1 group-hover:text-muted/70 transition-colors">
                         Не в сети
@@ -353,8 +428,8 @@ export function Sidebar({
           <div className="flex flex-col items-start flex-1 min-w-0">
              <span className="text-sm font-bold text-white truncate w-full text-left">Настройки</span>
              <span className="text-[10px] text-muted/60 truncate w-full text-left flex items-center gap-1">
-                <div className="w-1.5 h-1.5 rounded-full bg-success animate-pulse" />
-                В сети
+                <div className={`w-1.5 h-1.5 rounded-full ${getReachabilityLabel().color} animate-pulse`} />
+                {getReachabilityLabel().text}
              </span>
           </div>
         </button>
@@ -367,6 +442,7 @@ export function Sidebar({
         onUpdateProfile={onUpdateProfile}
         listenAddresses={listenAddresses}
         onConnectPeer={onConnectPeer}
+        onLock={onLock}
       />
     </div>
   );
@@ -1,7 +1,42 @@
 import { useState, useEffect } from "react";
-import { X, Save, User, Key, Shield, Globe, Link as LinkIcon, Copy, Check } from "lucide-react";
+import { invoke } from "@tauri-apps/api/core";
+import { open, save } from "@tauri-apps/plugin-dialog";
+import { X, Save, User, Key, Shield, Globe, Link as LinkIcon, Copy, Check, Inbox, Lock, Download, Upload, Smartphone, Trash2, Activity, RefreshCw } from "lucide-react";
 import { dbService } from "../../services/db";
 
+interface NodeSettings {
+  preferredTransport: "quic" | "tcp";
+  bootstrapNodes: string[];
+  relay: string | null;
+}
+
+interface MailboxSettings {
+  enabled: boolean;
+  mailboxPeer: string | null;
+  maxMessagesPerPeer: number;
+  retentionHours: number;
+}
+
+interface DeviceCert {
+  account: string;
+  device: string;
+  name: string;
+  issuedAt: number;
+}
+
+interface DevicesInfo {
+  account: string;
+  primary: boolean;
+  devices: DeviceCert[];
+  pendingLink: string | null;
+}
+
+interface PeerScore {
+  peerId: string;
+  score: number | null;
+  topics: string[];
+}
+
 interface SettingsModalProps {
   isOpen: boolean;
   onClose: () => void;
@@ -9,15 +44,31 @@ interface SettingsModalProps {
   onUpdateProfile: (name: string) => void;
   listenAddresses: string[];
   onConnectPeer: (addr: string) => void;
+  onLock: () => void;
 }
 
-export function SettingsModal({ isOpen, onClose, localPeerId, onUpdateProfile, listenAddresses, onConnectPeer }: SettingsModalProps) {
+export function SettingsModal({ isOpen, onClose, localPeerId, onUpdateProfile, listenAddresses, onConnectPeer, onLock }: SettingsModalProps) {
   const [activeTab, setActiveTab] = useState<"profile" | "invites">("profile");
   const [displayName, setDisplayName] = useState("");
   const [inviteCode, setInviteCode] = useState("");
   const [inputInviteCode, setInputInviteCode] = useState("");
   const [isSaving, setIsSaving] = useState(false);
   const [copied, setCopied] = useState(false);
+  const [mailbox, setMailbox] = useState<MailboxSettings | null>(null);
+  const [nodeSettings, setNodeSettings] = useState<NodeSettings | null>(null);
+  const [isProtected, setIsProtected] = useState(false);
+  const [currentPassphrase, setCurrentPassphrase] = useState("");
+  const [newPassphrase, setNewPassphrase] = useState("");
+  const [confirmPassphrase, setConfirmPassphrase] = useState("");
+  const [mnemonic, setMnemonic] = useState<string | null>(null);
+  const [backupPassword, setBackupPassword] = useState("");
+  const [restoreMnemonic, setRestoreMnemonic] = useState("");
+  const [devices, setDevices] = useState<DevicesInfo | null>(null);
+  const [linkCode, setLinkCode] = useState<string | null>(null);
+  const [inputLinkCode, setInputLinkCode] = useState("");
+  const [deviceName, setDeviceName] = useState("");
+  const [primaryPeer, setPrimaryPeer] = useState("");
+  const [peerScores, setPeerScores] = useState<PeerScore[] | null>(null);
 
   useEffect(() => {
     if (isOpen) {
@@ -47,16 +98,165 @@ export function SettingsModal({ isOpen, onClose, localPeerId, onUpdateProfile, l
   const loadSettings = async () => {
     const name = await dbService.getSetting("displayName");
     if (name) setDisplayName(name);
+    try {
+      setMailbox(await invoke<MailboxSettings>("get_mailbox_settings"));
+      setNodeSettings(await invoke<NodeSettings>("get_settings"));
+      setIsProtected((await invoke<{ protected: boolean }>("get_lock_state")).protected);
+      setDevices(await invoke<DevicesInfo>("get_devices"));
+    } catch (e) {
+      console.error("Failed to load mailbox settings:", e);
+    }
   };
 
   const handleSave = async () => {
     setIsSaving(true);
     await dbService.saveSetting("displayName", displayName);
     onUpdateProfile(displayName);
+    if (nodeSettings) {
+      try {
+        const restartRequired = await invoke<boolean>("set_settings", {
+          settings: {
+            ...nodeSettings,
+            bootstrapNodes: nodeSettings.bootstrapNodes.map(n => n.trim()).filter(n => n),
+            relay: nodeSettings.relay?.trim() || null
+          }
+        });
+        if (restartRequired) {
+          alert("Изменения настроек gossipsub вступят в силу после перезапуска");
+        }
+      } catch (e) {
+        console.error("Failed to save settings:", e);
+        alert(`Ошибка сохранения настроек: ${e}`);
+      }
+    }
+    if (mailbox) {
+      try {
+        await invoke("set_mailbox_settings", {
+          settings: { ...mailbox, mailboxPeer: mailbox.mailboxPeer?.trim() || null }
+        });
+      } catch (e) {
+        console.error("Failed to save mailbox settings:", e);
+      }
+    }
     setIsSaving(false);
     onClose();
   };
 
+  // An empty passphrase removes it
+  const handleSetPassphrase = async (passphrase: string) => {
+    if (passphrase && passphrase !== confirmPassphrase) {
+      alert("Парольные фразы не совпадают");
+      return;
+    }
+    try {
+      await invoke("set_passphrase", { current: isProtected ? currentPassphrase : null, passphrase });
+      setIsProtected(!!passphrase);
+      setCurrentPassphrase("");
+      setNewPassphrase("");
+      setConfirmPassphrase("");
+      alert(passphrase ? "Парольная фраза установлена" : "Парольная фраза снята");
+    } catch (e) {
+      alert(String(e) === "Wrong passphrase" ? "Неверная текущая парольная фраза" : `Ошибка: ${e}`);
+    }
+  };
+
+  const handleLock = async () => {
+    try {
+      await invoke("lock");
+      onClose();
+      onLock();
+    } catch (e) {
+      console.error("Failed to lock:", e);
+    }
+  };
+
+  const handleShowMnemonic = async () => {
+    try {
+      setMnemonic(await invoke<string>("export_identity_mnemonic"));
+    } catch (e) {
+      alert(`Ошибка: ${e}`);
+    }
+  };
+
+  const handleExportFile = async () => {
+    const path = await save({ defaultPath: "phantom-identity.json", filters: [{ name: "JSON", extensions: ["json"] }] });
+    if (!path) return;
+    try {
+      await invoke("export_identity_file", { path, password: backupPassword });
+      setBackupPassword("");
+      alert("Резервная копия сохранена");
+    } catch (e) {
+      alert(`Ошибка экспорта: ${e}`);
+    }
+  };
+
+  // From the typed mnemonic if there is one, otherwise from a backup file
+  // protected with `backupPassword`
+  const handleRestore = async () => {
+    if (!confirm("Текущие ключи будут заменены ключами из резервной копии. Продолжить?")) return;
+    try {
+      let peerId: string;
+      if (restoreMnemonic.trim()) {
+        peerId = await invoke<string>("restore_identity", { mnemonic: restoreMnemonic.trim() });
+      } else {
+        const path = await open({ multiple: false, directory: false, filters: [{ name: "JSON", extensions: ["json"] }] });
+        if (typeof path !== "string") return;
+        peerId = await invoke<string>("restore_identity", { path, password: backupPassword });
+      }
+      alert(`Восстановлен PeerID ${peerId.substring(0, 12)}...`);
+      window.location.reload();
+    } catch (e) {
+      alert(`Ошибка восстановления: ${e}`);
+    }
+  };
+
+  const loadDevices = async () => {
+    try {
+      setDevices(await invoke<DevicesInfo>("get_devices"));
+    } catch (e) {
+      console.error("Failed to load devices:", e);
+    }
+  };
+
+  // On the new device: the code to enter on the primary one
+  const handleRequestLink = async () => {
+    try {
+      setLinkCode(await invoke<string>("get_link_code", { account: primaryPeer.trim() }));
+      await loadDevices();
+    } catch (e) {
+      alert(`Ошибка: ${e}`);
+    }
+  };
+
+  const handleLinkDevice = async () => {
+    try {
+      await invoke("link_device", { code: inputLinkCode.trim(), name: deviceName.trim() || "Устройство" });
+      setInputLinkCode("");
+      setDeviceName("");
+      await loadDevices();
+    } catch (e) {
+      alert(`Ошибка привязки: ${e}`);
+    }
+  };
+
+  const handleUnlinkDevice = async (device: DeviceCert) => {
+    if (!confirm(`Отвязать устройство «${device.name}»?`)) return;
+    try {
+      await invoke("unlink_device", { device: device.device });
+      await loadDevices();
+    } catch (e) {
+      alert(`Ошибка: ${e}`);
+    }
+  };
+
+  const loadPeerScores = async () => {
+    try {
+      setPeerScores(await invoke<PeerScore[]>("get_peer_scores"));
+    } catch (e) {
+      alert(`Ошибка: ${e}`);
+    }
+  };
+
   const handleCopyInvite = () => {
     navigator.clipboard.writeText(inviteCode);
     setCopied(true);
@@ -170,6 +370,307 @@ export function SettingsModal({ isOpen, onClose, localPeerId, onUpdateProfile, l
                   Используется для сквозного шифрования (E2EE)
                 </p>
               </div>
+
+              <div className="space-y-2">
+                <label className="text-sm font-medium text-muted flex items-center gap-2">
+                  <Lock className="w-4 h-4" />
+                  Парольная фраза
+                </label>
+                {isProtected && (
+                  <input
+                    type="password"
+                    value={currentPassphrase}
+                    onChange={(e) => setCurrentPassphrase(e.target.value)}
+                    placeholder="Текущая парольная фраза..."
+                    className="w-full bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all text-sm"
+                  />
+                )}
+                <div className="flex gap-2">
+                  <input
+                    type="password"
+                    value={newPassphrase}
+                    onChange={(e) => setNewPassphrase(e.target.value)}
+                    placeholder="Новая..."
+                    className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all text-sm"
+                  />
+                  <input
+                    type="password"
+                    value={confirmPassphrase}
+                    onChange={(e) => setConfirmPassphrase(e.target.value)}
+                    placeholder="Повторите..."
+                    className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all text-sm"
+                  />
+                </div>
+                <div className="flex gap-2">
+                  <button
+                    onClick={() => handleSetPassphrase(newPassphrase)}
+                    disabled={!newPassphrase}
+                    className="px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all disabled:opacity-50 disabled:cursor-not-allowed"
+                  >
+                    {isProtected ? "Сменить" : "Установить"}
+                  </button>
+                  {isProtected && (
+                    <>
+                      <button
+                        onClick={() => handleSetPassphrase("")}
+                        className="px-4 py-2 bg-white/5 hover:bg-red-500/10 border border-white/10 rounded-xl text-white hover:text-red-400 text-sm transition-all"
+                      >
+                        Снять
+                      </button>
+                      <button
+                        onClick={handleLock}
+                        className="ml-auto px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all flex items-center gap-2"
+                      >
+                        <Lock className="w-4 h-4" />
+                        Заблокировать
+                      </button>
+                    </>
+                  )}
+                </div>
+                <p className="text-xs text-muted/60">Ключи и история сообщений хранятся зашифрованными. С парольной фразой их нельзя открыть без неё, восстановить её невозможно.</p>
+              </div>
+
+              <div className="space-y-2">
+                <label className="text-sm font-medium text-muted flex items-center gap-2">
+                  <Key className="w-4 h-4" />
+                  Резервная копия ключей
+                </label>
+                {mnemonic ? (
+                  <div className="w-full bg-black/40 border border-white/5 rounded-xl px-4 py-3 text-white font-mono text-xs break-words select-all">
+                    {mnemonic}
+                  </div>
+                ) : (
+                  <button
+                    onClick={handleShowMnemonic}
+                    className="px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all"
+                  >
+                    Показать секретную фразу (48 слов)
+                  </button>
+                )}
+                <div className="flex gap-2">
+                  <input
+                    type="password"
+                    value={backupPassword}
+                    onChange={(e) => setBackupPassword(e.target.value)}
+                    placeholder="Пароль файла копии..."
+                    className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all text-sm"
+                  />
+                  <button
+                    onClick={handleExportFile}
+                    disabled={!backupPassword}
+                    className="px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all flex items-center gap-2 disabled:opacity-50 disabled:cursor-not-allowed"
+                  >
+                    <Download className="w-4 h-4" />
+                    В файл
+                  </button>
+                </div>
+                <textarea
+                  value={restoreMnemonic}
+                  onChange={(e) => setRestoreMnemonic(e.target.value)}
+                  placeholder="Секретная фраза для восстановления (или оставьте пустым и выберите файл)..."
+                  rows={2}
+                  className="w-full bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all font-mono text-xs"
+                />
+                <button
+                  onClick={handleRestore}
+                  className="px-4 py-2 bg-white/5 hover:bg-red-500/10 border border-white/10 rounded-xl text-white hover:text-red-400 text-sm transition-all flex items-center gap-2"
+                >
+                  <Upload className="w-4 h-4" />
+                  Восстановить
+                </button>
+                <p className="text-xs text-muted/60">Секретная фраза и файл копии возвращают ваш PeerID на новом устройстве. Никому их не показывайте.</p>
+              </div>
+
+              {devices && (
+                <div className="space-y-2">
+                  <label className="text-sm font-medium text-muted flex items-center gap-2">
+                    <Smartphone className="w-4 h-4" />
+                    Устройства
+                  </label>
+                  {devices.devices.map((device) => (
+                    <div key={device.device} className="flex items-center justify-between bg-black/20 border border-white/5 rounded-xl px-4 py-2">
+                      <div className="min-w-0">
+                        <p className="text-sm text-white truncate">{device.name}</p>
+                        <p className="text-xs text-muted/60 font-mono truncate">{device.device}</p>
+                      </div>
+                      {devices.primary && (
+                        <button
+                          onClick={() => handleUnlinkDevice(device)}
+                          className="p-2 rounded-xl hover:bg-red-500/10 text-muted hover:text-red-400 transition-colors"
+                        >
+                          <Trash2 className="w-4 h-4" />
+                        </button>
+                      )}
+                    </div>
+                  ))}
+                  {devices.primary ? (
+                    <>
+                      <div className="flex gap-2">
+                        <input
+                          type="text"
+                          value={deviceName}
+                          onChange={(e) => setDeviceName(e.target.value)}
+                          placeholder="Название..."
+                          className="w-1/3 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all text-sm"
+                        />
+                        <input
+                          type="text"
+                          value={inputLinkCode}
+                          onChange={(e) => setInputLinkCode(e.target.value)}
+                          placeholder="Код привязки нового устройства..."
+                          className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all font-mono text-xs"
+                        />
+                        <button
+                          onClick={handleLinkDevice}
+                          disabled={!inputLinkCode.trim()}
+                          className="px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all disabled:opacity-50 disabled:cursor-not-allowed"
+                        >
+                          Привязать
+                        </button>
+                      </div>
+                      {devices.devices.length === 0 && (
+                        <div className="flex gap-2">
+                          <input
+                            type="text"
+                            value={primaryPeer}
+                            onChange={(e) => setPrimaryPeer(e.target.value)}
+                            placeholder="PeerID основного устройства..."
+                            className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 transition-all font-mono text-xs"
+                          />
+                          <button
+                            onClick={handleRequestLink}
+                            disabled={!primaryPeer.trim()}
+                            className="px-4 py-2 bg-white/5 hover:bg-white/10 border border-white/10 rounded-xl text-white text-sm transition-all disabled:opacity-50 disabled:cursor-not-allowed"
+                          >
+                            Получить код
+                          </button>
+                        </div>
+                      )}
+                      {linkCode && (
+                        <div className="w-full bg-black/40 border border-white/5 rounded-xl px-4 py-3 text-white font-mono text-xs break-all select-all">
+                          {linkCode}
+                        </div>
+                      )}
+                      {devices.pendingLink && (
+                        <p className="text-xs text-muted/60">Ожидание подтверждения от {devices.pendingLink.substring(0, 12)}...</p>
+                      )}
+                    </>
+                  ) : (
+                    <p className="text-xs text-muted/60">Это устройство привязано к {devices.account.substring(0, 12)}...</p>
+                  )}
+                  <p className="text-xs text-muted/60">Чтобы добавить устройство, получите на нём код привязки и введите его здесь на основном. История переносится на новое устройство автоматически.</p>
+                </div>
+              )}
+
+              {nodeSettings && (
+                <div className="space-y-2">
+                  <label className="text-sm font-medium text-muted">Предпочтительный транспорт</label>
+                  <select
+                    value={nodeSettings.preferredTransport}
+                    onChange={(e) => setNodeSettings({ ...nodeSettings, preferredTransport: e.target.value as NodeSettings["preferredTransport"] })}
+                    className="w-full bg-black/20 border border-white/10 rounded-xl px-4 py-3 text-white outline-none focus:border-primary/50 focus:ring-1 focus:ring-primary/50 transition-all text-sm"
+                  >
+                    <option value="quic">QUIC (быстрее, лучше проходит NAT)</option>
+                    <option value="tcp">TCP</option>
+                  </select>
+                  <p className="text-xs text-muted/60">Узел слушает оба транспорта, этот пробуется первым.</p>
+
+                  <label className="text-sm font-medium text-muted">Узлы начальной загрузки (DHT)</label>
+                  <textarea
+                    value={nodeSettings.bootstrapNodes.join("\n")}
+                    onChange={(e) => setNodeSettings({ ...nodeSettings, bootstrapNodes: e.target.value.split("\n") })}
+                    placeholder="/ip4/1.2.3.4/udp/4001/quic-v1/p2p/12D3KooW..."
+                    rows={3}
+                    className="w-full bg-black/20 border border-white/10 rounded-xl px-4 py-3 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 focus:ring-1 focus:ring-primary/50 transition-all font-mono text-xs"
+                  />
+                  <p className="text-xs text-muted/60">По одному адресу на строку. Через них контакты находятся за пределами локальной сети.</p>
+
+                  <label className="text-sm font-medium text-muted">Ретранслятор (Circuit Relay)</label>
+                  <input
+                    type="text"
+                    value={nodeSettings.relay ?? ""}
+                    onChange={(e) => setNodeSettings({ ...nodeSettings, relay: e.target.value })}
+                    placeholder="/ip4/1.2.3.4/tcp/4001/p2p/12D3KooW..."
+                    className="w-full bg-black/20 border border-white/10 rounded-xl px-4 py-3 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 focus:ring-1 focus:ring-primary/50 transition-all font-mono text-xs"
+                  />
+                  <p className="text-xs text-muted/60">Позволяет принимать соединения, если вы за NAT.</p>
+                </div>
+              )}
+
+              <div className="space-y-2">
+                <label className="text-sm font-medium text-muted flex items-center justify-between">
+                  <span className="flex items-center gap-2">
+                    <Activity className="w-4 h-4" />
+                    Оценки узлов (gossipsub)
+                  </span>
+                  <button
+                    onClick={loadPeerScores}
+                    className="p-1.5 rounded-lg hover:bg-white/5 text-muted hover:text-white transition-colors"
+                  >
+                    <RefreshCw className="w-4 h-4" />
+                  </button>
+                </label>
+                {peerScores && (peerScores.length === 0 ? (
+                  <p className="text-xs text-muted/60">Нет подключенных узлов.</p>
+                ) : (
+                  <div className="max-h-40 overflow-y-auto custom-scrollbar space-y-1">
+                    {peerScores.map((peer) => (
+                      <div key={peer.peerId} className="flex items-center justify-between bg-black/20 border border-white/5 rounded-xl px-4 py-1.5 text-xs">
+                        <span className="font-mono text-white truncate" title={peer.topics.join(", ")}>{peer.peerId.substring(0, 20)}...</span>
+                        <span className={`font-mono ${peer.score !== null && peer.score < 0 ? "text-red-400" : "text-muted"}`}>
+                          {peer.score === null ? "—" : peer.score.toFixed(2)}
+                        </span>
+                      </div>
+                    ))}
+                  </div>
+                ))}
+                <p className="text-xs text-muted/60">Узлы, рассылающие спам или неверные сообщения, теряют очки и отключаются от рассылки. Размеры mesh, лимиты и параметры оценки задаются в разделе gossipsub файла settings.json и применяются после перезапуска.</p>
+              </div>
+
+              {mailbox && (
+                <div className="space-y-3">
+                  <label className="text-sm font-medium text-muted flex items-center gap-2">
+                    <Inbox className="w-4 h-4" />
+                    Почтовый ящик
+                  </label>
+                  <input
+                    type="text"
+                    value={mailbox.mailboxPeer ?? ""}
+                    onChange={(e) => setMailbox({ ...mailbox, mailboxPeer: e.target.value })}
+                    placeholder="PeerID узла, хранящего ваши сообщения..."
+                    className="w-full bg-black/20 border border-white/10 rounded-xl px-4 py-3 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 focus:ring-1 focus:ring-primary/50 transition-all font-mono text-xs"
+                  />
+                  <label className="flex items-center gap-2 text-sm text-white">
+                    <input
+                      type="checkbox"
+                      checked={mailbox.enabled}
+                      onChange={(e) => setMailbox({ ...mailbox, enabled: e.target.checked })}
+                    />
+                    Хранить сообщения для контактов, пока они не в сети
+                  </label>
+                  {mailbox.enabled && (
+                    <div className="flex gap-2">
+                      <input
+                        type="number"
+                        min={1}
+                        value={mailbox.retentionHours}
+                        onChange={(e) => setMailbox({ ...mailbox, retentionHours: Math.max(1, Number(e.target.value)) })}
+                        title="Срок хранения (часы)"
+                        className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white outline-none focus:border-primary/50 transition-all text-sm"
+                      />
+                      <input
+                        type="number"
+                        min={1}
+                        value={mailbox.maxMessagesPerPeer}
+                        onChange={(e) => setMailbox({ ...mailbox, maxMessagesPerPeer: Math.max(1, Number(e.target.value)) })}
+                        title="Максимум сообщений на контакт"
+                        className="flex-1 bg-black/20 border border-white/10 rounded-xl px-4 py-2 text-white outline-none focus:border-primary/50 transition-all text-sm"
+                      />
+                    </div>
+                  )}
+                  <p className="text-xs text-muted/60">Сообщения хранятся в зашифрованном виде и доставляются, когда адресат снова в сети.</p>
+                </div>
+              )}
             </div>
           ) : (
             <div className="space-y-6">
@@ -0,0 +1,59 @@
+import { useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import { Lock, Unlock } from "lucide-react";
+
+interface UnlockScreenProps {
+  onUnlocked: () => void;
+}
+
+// Shown instead of the app while the keys are sealed under a passphrase.
+export function UnlockScreen({ onUnlocked }: UnlockScreenProps) {
+  const [passphrase, setPassphrase] = useState("");
+  const [error, setError] = useState<string | null>(null);
+  const [isUnlocking, setIsUnlocking] = useState(false);
+
+  const handleUnlock = async () => {
+    if (!passphrase) return;
+    setIsUnlocking(true);
+    setError(null);
+    try {
+      await invoke("unlock", { passphrase });
+      setPassphrase("");
+      onUnlocked();
+    } catch (e) {
+      setError(String(e) === "Wrong passphrase" ? "Неверная парольная фраза" : `Ошибка: ${e}`);
+    } finally {
+      setIsUnlocking(false);
+    }
+  };
+
+  return (
+    <div className="h-screen w-screen flex items-center justify-center bg-transparent text-text font-sans">
+      <div className="w-[380px] bg-surface border border-white/10 rounded-2xl shadow-2xl p-6 space-y-4">
+        <h2 className="text-lg font-bold flex items-center gap-2">
+          <Lock className="w-5 h-5 text-primary" />
+          Phantom заблокирован
+        </h2>
+        <p className="text-xs text-muted/60">Ключи и история сообщений зашифрованы. Введите парольную фразу, чтобы открыть их.</p>
+        <input
+          type="password"
+          autoFocus
+          value={passphrase}
+          onChange={(e) => setPassphrase(e.target.value)}
+          onKeyDown={(e) => e.key === "Enter" && handleUnlock()}
+          placeholder="Парольная фраза..."
+          className="w-full bg-black/20 border border-white/10 rounded-xl px-4 py-3 text-white placeholder:text-muted/40 outline-none focus:border-primary/50 focus:ring-1 focus:ring-primary/50 transition-all"
+        />
+        {error && <p className="text-xs text-red-400">{error}</p>}
+        <button
+          onClick={handleUnlock}
+          disabled={!passphrase || isUnlocking}
+          className="w-full flex items-center justify-center gap-2 px-6 py-2.5 bg-gradient-to-r from-primary to-purple-600 text-white font-medium rounded-xl shadow-lg shadow-primary/25 hover:shadow-primary/40 transition-all active:scale-95 disabled:opacity-50 disabled:cursor-not-allowed"
+        >
+          {isUnlocking ? "Проверка..." : "Разблокировать"}
+          {!isUnlocking && <Unlock className="w-4 h-4" />}
+        </button>
+      </div>
+    </div>
+  );
+}
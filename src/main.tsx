@@ -1,10 +1,32 @@
-import React from "react";
+import React, { useEffect, useState } from "react";
 import ReactDOM from "react-dom/client";
+import { invoke } from "@tauri-apps/api/core";
 import App from "./App";
+import { UnlockScreen } from "./components/Unlock/UnlockScreen";
 import "./index.css";
 
+interface LockState {
+  protected: boolean;
+  unlocked: boolean;
+}
+
+// The node only starts once the keys are unlocked, so the app waits for that.
+function Root() {
+  const [unlocked, setUnlocked] = useState<boolean | null>(null);
+
+  useEffect(() => {
+    invoke<LockState>("get_lock_state")
+      .then(state => setUnlocked(state.unlocked))
+      .catch(e => console.error("Failed to get lock state:", e));
+  }, []);
+
+  if (unlocked === null) return null;
+  if (!unlocked) return <UnlockScreen onUnlocked={() => setUnlocked(true)} />;
+  return <App onLock={() => setUnlocked(false)} />;
+}
+
 ReactDOM.createRoot(document.getElementById("root") as HTMLElement).render(
   <React.StrictMode>
-    <App />
+    <Root />
   </React.StrictMode>,
 );